@@ -1,24 +1,19 @@
 // Load environment variables from .env file
 use dotenv::dotenv;
 
+// Import StreamExt to consume the stream of incoming messages
+use futures::StreamExt;
+
 // Import Graphcast SDK types and functions for agent configuration, message handling, and more
 use graphcast_sdk::{
-    graphcast_agent::{
-        message_typing::GraphcastMessage, waku_handling::WakuHandlingError, GraphcastAgent,
-        GraphcastAgentConfig,
-    },
+    graphcast_agent::{message_typing::GraphcastMessage, GraphcastAgent, GraphcastAgentConfig},
     graphql::client_graph_node::update_network_chainheads,
     networks::NetworkName,
     BlockPointer,
 };
 
-// Import the OnceCell container for lazy initialization of global/static data
-use once_cell::sync::OnceCell;
-
-// Import HashMap for key-value storage
-
-// Import Arc and Mutex for thread-safe sharing of data across threads
-use std::sync::{Arc, Mutex};
+// Import Arc for thread-safe sharing of data across tasks
+use std::sync::Arc;
 
 // Import sleep and Duration for handling time intervals and thread delays
 use std::{thread::sleep, time::Duration};
@@ -50,65 +45,64 @@ async fn main() {
     let config = Config::args();
     let _parent_span = tracing::info_span!("main").entered();
 
-    /// A global static (singleton) instance of A GraphcastMessage vector.
-    /// It is used to save incoming messages after they've been validated, in order
-    /// defer their processing for later, because async code is required for the processing but
-    /// it is not allowed in the handler itself.
-    pub static MESSAGES: OnceCell<Arc<Mutex<Vec<GraphcastMessage<RadioPayloadMessage>>>>> =
-        OnceCell::new();
-
-    /// The Graphcast Agent instance must be a global static variable (for the time being).
-    /// This is because the Radio handler requires a static immutable context and
-    /// the handler itself is being passed into the Graphcast Agent, so it needs to be static as well.
-    pub static GRAPHCAST_AGENT: OnceCell<GraphcastAgent> = OnceCell::new();
-
     // subtopics are optionally provided and used as the content topic identifier of the message subject,
     // if not provided then they are usually generated based on indexer allocations
     let subtopics: Vec<String> = vec!["ping-pong-content-topic".to_string()];
 
     // GraphcastAgentConfig defines the configuration that the SDK expects from all Radios, regardless of their specific functionality
-    let graphcast_agent_config = GraphcastAgentConfig::new(
-        config.private_key.expect("No private key provided"),
-        config.indexer_address,
-        radio_name,
-        config.registry_subgraph,
-        config.network_subgraph,
-        config.graph_node_endpoint,
-        None,
-        Some("testnet".to_string()),
-        Some(subtopics),
-        None,
-        None,
-        None,
-        None,
-        Some(true),
+    let mut config_builder = GraphcastAgentConfig::builder()
+        .wallet_key(config.private_key.expect("No private key provided"))
+        .graph_account(config.indexer_address)
+        .radio_name(radio_name)
+        .registry_subgraph(config.registry_subgraph)
+        .network_subgraph(config.network_subgraph)
+        .graph_node_endpoint(config.graph_node_endpoint)
+        .graphcast_namespace("testnet")
+        .subtopics(subtopics)
+        .filter_protocol(true)
         // Example ENR address
-        Some(vec![String::from("enr:-JK4QBcfVXu2YDeSKdjF2xE5EDM5f5E_1Akpkv_yw_byn1adESxDXVLVjapjDvS_ujx6MgWDu9hqO_Az_CbKLJ8azbMBgmlkgnY0gmlwhAVOUWOJc2VjcDI1NmsxoQOUZIqKLk5xkiH0RAFaMGrziGeGxypJ03kOod1-7Pum3oN0Y3CCfJyDdWRwgiMohXdha3UyDQ")]),
-        None,
-        config.id_validation,
-    )
-    .await
-    .unwrap_or_else(|e| panic!("Could not create GraphcastAgentConfig: {e}"));
+        .discv5_enrs(vec![String::from("enr:-JK4QBcfVXu2YDeSKdjF2xE5EDM5f5E_1Akpkv_yw_byn1adESxDXVLVjapjDvS_ujx6MgWDu9hqO_Az_CbKLJ8azbMBgmlkgnY0gmlwhAVOUWOJc2VjcDI1NmsxoQOUZIqKLk5xkiH0RAFaMGrziGeGxypJ03kOod1-7Pum3oN0Y3CCfJyDdWRwgiMohXdha3UyDQ")]);
+    if let Some(id_validation) = config.id_validation {
+        config_builder = config_builder.id_validation(id_validation);
+    }
+    let graphcast_agent_config = config_builder
+        .build()
+        .unwrap_or_else(|e| panic!("Could not create GraphcastAgentConfig: {e}"));
 
     debug!("Initializing the Graphcast Agent");
     let graphcast_agent = GraphcastAgent::new(graphcast_agent_config)
         .await
         .expect("Could not create Graphcast agent");
 
-    // A one-off setter to load the Graphcast Agent into the global static variable
-    _ = GRAPHCAST_AGENT.set(graphcast_agent);
+    // Incoming messages are saved after they've been validated, in order to defer their processing for later
+    let messages: Arc<AsyncMutex<Vec<GraphcastMessage<RadioPayloadMessage>>>> =
+        Arc::new(AsyncMutex::new(vec![]));
+
+    // The subscription yields decoded and validated messages and can be consumed from our own runtime
+    let mut subscription = Box::pin(graphcast_agent.subscribe::<RadioPayloadMessage>());
+    let received_messages = messages.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = subscription.next().await {
+            match msg {
+                Ok(msg) => received_messages.lock().await.push(msg.message),
+                Err(err) => {
+                    error!(
+                        error = tracing::field::debug(&err),
+                        "Failed to handle Waku signal"
+                    );
+                }
+            }
+        }
+    });
 
-    // A one-off setter to instantiate an empty vec before populating it with incoming messages
-    _ = MESSAGES.set(Arc::new(Mutex::new(vec![])));
     // Helper function to reuse message sending code
     async fn send_message(
+        graphcast_agent: &GraphcastAgent,
         payload: Option<RadioPayloadMessage>,
         network: NetworkName,
         block_number: u64,
     ) {
-        if let Err(e) = GRAPHCAST_AGENT
-            .get()
-            .expect("Could not retrieve Graphcast agent")
+        if let Err(e) = graphcast_agent
             .send_message(
                 // The identifier can be any string that suits your Radio logic
                 // If it doesn't matter for your Radio logic (like in this case), you can just use a UUID or a hardcoded string
@@ -123,42 +117,10 @@ async fn main() {
         };
     }
 
-    // The handler specifies what to do with incoming messages.
-    // There cannot be any non-deterministic (this includes async) code inside the handler.
-    // That is why we're saving the message for later processing, where we will check its content and perform some action based on it.
-    let radio_handler =
-        |msg: Result<GraphcastMessage<RadioPayloadMessage>, WakuHandlingError>| match msg {
-            Ok(msg) => {
-                MESSAGES
-                    .get()
-                    .expect("Could not retrieve messages")
-                    .lock()
-                    .expect("Could not get lock on messages")
-                    .push(msg);
-            }
-            Err(err) => {
-                error!(
-                    error = tracing::field::debug(&err),
-                    "Failed to handle Waku signal"
-                );
-            }
-        };
-
-    GRAPHCAST_AGENT
-        .get()
-        .expect("Could not retrieve Graphcast agent")
-        .register_handler(Arc::new(AsyncMutex::new(radio_handler)))
-        .expect("Could not register handler");
-
     let network = NetworkName::from_string("goerli");
 
     loop {
-        let mut network_chainhead_blocks = match GRAPHCAST_AGENT
-            .get()
-            .unwrap()
-            .callbook
-            .indexing_statuses()
-            .await
+        let mut network_chainhead_blocks = match graphcast_agent.callbook.indexing_statuses().await
         {
             Ok(res) => update_network_chainheads(res),
             Err(e) => {
@@ -183,17 +145,11 @@ async fn main() {
                 "table".to_string(),
                 std::env::args().nth(1).unwrap_or("Ping".to_string()),
             );
-            send_message(Some(msg), network, block_number).await;
+            send_message(&graphcast_agent, Some(msg), network, block_number).await;
         } else {
             // If block number is odd, process received messages
-            let messages = AsyncMutex::new(
-                MESSAGES
-                    .get()
-                    .expect("Could not retrieve messages")
-                    .lock()
-                    .expect("Could not get lock on messages"),
-            );
-            for msg in messages.lock().await.iter() {
+            let mut messages = messages.lock().await;
+            for msg in messages.iter() {
                 let payload = msg
                     .payload
                     .as_ref()
@@ -201,12 +157,12 @@ async fn main() {
                 if *payload.content == *"Ping" {
                     let replay_msg =
                         RadioPayloadMessage::new("table".to_string(), "Pong".to_string());
-                    send_message(Some(replay_msg), network, block_number).await;
+                    send_message(&graphcast_agent, Some(replay_msg), network, block_number).await;
                 };
             }
 
             // Clear message store after processing
-            messages.lock().await.clear();
+            messages.clear();
         }
 
         // Wait before next block check
@@ -0,0 +1,87 @@
+//! Feed arbitrary bytes to the decoding and validation of inbound messages, the path
+//! `handle_signal` takes for every Waku message. Malformed messages must be rejected
+//! with an error, never panic.
+//!
+//! Run with `cargo +nightly fuzz run handle_message` from the repository root.
+#![no_main]
+
+use async_graphql::SimpleObject;
+use ethers_contract::EthAbiType;
+use ethers_core::types::transaction::eip712::Eip712;
+use ethers_derive_eip712::*;
+use graphcast_sdk::{
+    callbook::CallBook,
+    graphcast_agent::{
+        heartbeat::{Heartbeat, PeerRoster},
+        message_typing::{IdentityValidation, ValidationContext},
+        seen_messages::SeenMessages,
+        transport::TransportMessage,
+        validation::{NonceValidator, SignatureValidator, TimeValidator, ValidationPipeline},
+        waku_handling::{build_content_topics, handle_message, pubsub_topic},
+        DEFAULT_MAX_CLOCK_SKEW, DEFAULT_REPLAY_WINDOW,
+    },
+};
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use waku::WakuMessage;
+
+#[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, SimpleObject)]
+#[eip712(
+    name = "Graphcast Fuzz Radio",
+    version = "0",
+    chain_id = 1,
+    verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+)]
+pub struct FuzzPayload {
+    #[prost(string, tag = "1")]
+    pub identifier: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+}
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let context = ValidationContext {
+        nonces: Arc::default(),
+        callbook: CallBook::new(String::new(), String::new(), String::new()),
+        local_sender_id: String::from("0xlocal"),
+        id_validation: IdentityValidation::NoCheck,
+        replay_window: DEFAULT_REPLAY_WINDOW,
+        max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+        accept_legacy_signatures: true,
+        seen_messages: Arc::new(SeenMessages::default()),
+        nonce_verdicts: Arc::default(),
+        check_nonces: true,
+    };
+    // Checks that do not reach remote endpoints
+    let pipeline = ValidationPipeline::<FuzzPayload>::empty()
+        .with(SignatureValidator)
+        .with(TimeValidator)
+        .with(NonceValidator);
+    let message = TransportMessage::new(
+        String::from("fuzz"),
+        pubsub_topic(None),
+        WakuMessage::new(
+            data.to_vec(),
+            build_content_topics("fuzz-radio", 0, &[String::from("Qmfuzz")]).remove(0),
+            2,
+            0,
+            vec![],
+            true,
+        ),
+    );
+    let _ = runtime.block_on(handle_message::<FuzzPayload>(message, &context, &pipeline));
+
+    if let Ok(heartbeat) = Heartbeat::decode(data) {
+        let _ = PeerRoster::default().record(
+            &heartbeat,
+            &context.local_sender_id,
+            DEFAULT_REPLAY_WINDOW,
+            DEFAULT_MAX_CLOCK_SKEW,
+        );
+    }
+});
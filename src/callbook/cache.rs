@@ -0,0 +1,402 @@
+//! Time-bounded cache for the CallBook queries.
+//!
+//! Successful responses are kept for the configured TTL and failures for the negative
+//! TTL. Concurrent lookups of the same key wait on a per-key slot, so only the first
+//! caller sends a request and the others reuse its response, even with caching disabled.
+//! Each cache keeps a bounded number of keys and evicts the least recently used.
+//!
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::trace;
+
+use crate::graphql::{client_network::Network, QueryError};
+use crate::Account;
+
+/// Default maximum number of entries kept by each cache
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Time-to-live settings of the CallBook caches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Sender to Graph Account resolutions through the registry or network subgraph
+    pub account_ttl: Duration,
+    /// Indexer stake lookups on the network subgraph
+    pub stake_ttl: Duration,
+    /// (network, block number) to block hash lookups on the graph node
+    pub block_hash_ttl: Duration,
+    /// Failed lookups of any kind
+    pub negative_ttl: Duration,
+    /// Maximum number of entries kept by each cache, the least recently used are evicted
+    pub capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            account_ttl: Duration::from_secs(600),
+            stake_ttl: Duration::from_secs(600),
+            block_hash_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(30),
+            capacity: DEFAULT_CACHE_CAPACITY,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Disable caching, every lookup is sent to the endpoints. Concurrent lookups of the
+    /// same key still share a single request
+    pub fn disabled() -> Self {
+        CacheConfig {
+            account_ttl: Duration::ZERO,
+            stake_ttl: Duration::ZERO,
+            block_hash_ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+            capacity: DEFAULT_CACHE_CAPACITY,
+        }
+    }
+}
+
+struct CacheEntry<V> {
+    value: Result<V, String>,
+    fetched_at: Instant,
+    expires_at: Instant,
+}
+
+type Slot<V> = Arc<AsyncMutex<Option<CacheEntry<V>>>>;
+
+struct Slots<K, V> {
+    /// Key to (slot, generation of its last use)
+    entries: HashMap<K, (Slot<V>, u64)>,
+    /// Keys in the order they were last used, with the generation they were used at.
+    /// Records with an outdated generation were used again later and are skipped.
+    order: VecDeque<(K, u64)>,
+    generation: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> Slots<K, V> {
+    fn new() -> Self {
+        Slots {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    /// Slot of `key`, created after evicting the least recently used keys beyond `capacity`
+    fn touch(&mut self, key: K, capacity: usize) -> Slot<V> {
+        self.generation += 1;
+        let generation = self.generation;
+        let slot = match self.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.1 = generation;
+                entry.0.clone()
+            }
+            None => {
+                while self.entries.len() >= capacity && self.pop_oldest() {}
+                let slot = Slot::default();
+                self.entries.insert(key.clone(), (slot.clone(), generation));
+                slot
+            }
+        };
+        self.order.push_back((key, generation));
+        // Keys used again leave outdated records behind, drop them once they pile up
+        if self.order.len() > 2 * self.entries.len() + 16 {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, generation)| entries.get(key).map_or(false, |e| e.1 == *generation));
+        }
+        slot
+    }
+
+    /// Drop the least recently used key, a fetch in flight on it still completes for its
+    /// waiters. Returns false when there is no key left
+    fn pop_oldest(&mut self) -> bool {
+        while let Some((key, generation)) = self.order.pop_front() {
+            if self.entries.get(&key).map_or(false, |e| e.1 == generation) {
+                self.entries.remove(&key);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Cache of query results keyed by the query arguments
+pub struct QueryCache<K, V> {
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+    slots: Mutex<Slots<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> QueryCache<K, V> {
+    pub fn new(ttl: Duration, negative_ttl: Duration, capacity: usize) -> Self {
+        QueryCache {
+            ttl,
+            negative_ttl,
+            capacity: capacity.max(1),
+            slots: Mutex::new(Slots::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the cached result for `key`, or run `fetch` and cache its result.
+    /// Concurrent calls with the same key share a single `fetch`.
+    pub async fn get_or_fetch<F, Fut>(&self, key: K, fetch: F) -> Result<V, QueryError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, QueryError>>,
+    {
+        let requested_at = Instant::now();
+        let slot = self.slot(key);
+        let mut entry = slot.lock().await;
+        // Results fetched while waiting on the slot are shared even once expired
+        if let Some(cached) = entry
+            .as_ref()
+            .filter(|e| e.expires_at > Instant::now() || e.fetched_at >= requested_at)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            trace!("Query cache hit");
+            return cached.value.clone().map_err(QueryError::CachedError);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = fetch().await;
+        *entry = Some(self.entry(&result));
+        result
+    }
+
+    /// Run `fetch` whether or not `key` has a cached result and cache its result, for
+    /// callers that must observe changes sooner than the TTL
+    pub async fn refresh<F, Fut>(&self, key: K, fetch: F) -> Result<V, QueryError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, QueryError>>,
+    {
+        let slot = self.slot(key);
+        let mut entry = slot.lock().await;
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = fetch().await;
+        *entry = Some(self.entry(&result));
+        result
+    }
+
+    fn entry(&self, result: &Result<V, QueryError>) -> CacheEntry<V> {
+        let (value, ttl) = match result {
+            Ok(v) => (Ok(v.clone()), self.ttl),
+            Err(e) => (Err(e.to_string()), self.negative_ttl),
+        };
+        let fetched_at = Instant::now();
+        CacheEntry {
+            value,
+            fetched_at,
+            expires_at: fetched_at + ttl,
+        }
+    }
+
+    fn slot(&self, key: K) -> Slot<V> {
+        self.slots.lock().unwrap().touch(key, self.capacity)
+    }
+
+    /// Number of lookups answered from the cache and sent to the endpoint
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn clear(&self) {
+        *self.slots.lock().unwrap() = Slots::new();
+    }
+}
+
+/// Caches held by a CallBook
+pub struct CallBookCache {
+    pub config: CacheConfig,
+    /// Graphcast ID to registered indexer
+    pub registry: QueryCache<String, String>,
+    /// (agent, graph account) to the Graph Account matched on the network subgraph
+    pub graph_accounts: QueryCache<(String, String), Account>,
+    /// Indexer address to network subgraph indexer status
+    pub network: QueryCache<String, Network>,
+    /// (network, block number) to block hash
+    pub block_hashes: QueryCache<(String, u64), String>,
+}
+
+impl CallBookCache {
+    pub fn new(config: CacheConfig) -> Self {
+        CallBookCache {
+            config,
+            registry: QueryCache::new(config.account_ttl, config.negative_ttl, config.capacity),
+            graph_accounts: QueryCache::new(
+                config.account_ttl,
+                config.negative_ttl,
+                config.capacity,
+            ),
+            network: QueryCache::new(config.stake_ttl, config.negative_ttl, config.capacity),
+            block_hashes: QueryCache::new(
+                config.block_hash_ttl,
+                config.negative_ttl,
+                config.capacity,
+            ),
+        }
+    }
+}
+
+impl Default for CallBookCache {
+    fn default() -> Self {
+        CallBookCache::new(CacheConfig::default())
+    }
+}
+
+impl fmt::Debug for CallBookCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallBookCache")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn test_cache_ttl_and_negative_caching() {
+        let cache: QueryCache<u64, String> =
+            QueryCache::new(Duration::from_secs(60), Duration::ZERO, 10);
+        let calls = AtomicUsize::new(0);
+        let fetch = |ok: bool| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if ok {
+                    Ok(String::from("0xhash"))
+                } else {
+                    Err(QueryError::ParseResponseError(String::from("no hash")))
+                }
+            }
+        };
+
+        assert!(cache.get_or_fetch(1, || fetch(false)).await.is_err());
+        // Failures expire right away with a zero negative ttl
+        assert_eq!(
+            cache.get_or_fetch(1, || fetch(true)).await.unwrap(),
+            "0xhash"
+        );
+        assert_eq!(
+            cache.get_or_fetch(1, || fetch(false)).await.unwrap(),
+            "0xhash"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats(), (1, 2));
+
+        let cache: QueryCache<u64, String> =
+            QueryCache::new(Duration::from_secs(60), Duration::from_secs(60), 10);
+        assert!(cache.get_or_fetch(1, || fetch(false)).await.is_err());
+        assert!(matches!(
+            cache.get_or_fetch(1, || fetch(true)).await,
+            Err(QueryError::CachedError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cache_refresh() {
+        let cache: QueryCache<u64, u64> =
+            QueryCache::new(Duration::from_secs(60), Duration::ZERO, 10);
+        cache.get_or_fetch(1, || async { Ok(1) }).await.unwrap();
+        assert_eq!(cache.refresh(1, || async { Ok(2) }).await.unwrap(), 2);
+        // The refreshed result is cached for later lookups
+        assert_eq!(cache.get_or_fetch(1, || async { Ok(3) }).await.unwrap(), 2);
+        assert_eq!(cache.stats(), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_cache_coalesces_requests() {
+        let cache: Arc<QueryCache<u64, u64>> =
+            Arc::new(QueryCache::new(Duration::from_secs(60), Duration::ZERO, 10));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookups = (0..10).map(|_| {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_fetch(7, || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Ok(42)
+                    })
+                    .await
+            })
+        });
+        for lookup in futures::future::join_all(lookups).await {
+            assert_eq!(lookup.unwrap().unwrap(), 42);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_coalesces_without_ttl() {
+        let config = CacheConfig::disabled();
+        let cache: Arc<QueryCache<u64, u64>> = Arc::new(QueryCache::new(
+            config.block_hash_ttl,
+            config.negative_ttl,
+            config.capacity,
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookup = || {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_fetch(7, || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Ok(42)
+                    })
+                    .await
+            })
+        };
+        for result in futures::future::join_all((0..10).map(|_| lookup())).await {
+            assert_eq!(result.unwrap().unwrap(), 42);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // Later lookups are not answered from the cache
+        lookup().await.unwrap().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let cache: QueryCache<u64, u64> =
+            QueryCache::new(Duration::from_secs(60), Duration::ZERO, 2);
+        for key in [0, 1, 0, 2] {
+            cache.get_or_fetch(key, || async { Ok(key) }).await.unwrap();
+        }
+        assert_eq!(cache.slots.lock().unwrap().entries.len(), 2);
+        assert_eq!(cache.stats(), (1, 3));
+        // Key 1 was the least recently used when key 2 came in
+        cache.get_or_fetch(0, || async { Ok(0) }).await.unwrap();
+        cache.get_or_fetch(1, || async { Ok(1) }).await.unwrap();
+        assert_eq!(cache.stats(), (2, 4));
+
+        for key in 0..100 {
+            cache.get_or_fetch(key, || async { Ok(key) }).await.unwrap();
+        }
+        let slots = cache.slots.lock().unwrap();
+        assert_eq!(slots.entries.len(), 2);
+        assert!(slots.order.len() <= 2 * 2 + 16);
+    }
+}
@@ -0,0 +1,160 @@
+use derive_getters::Getters;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use self::cache::{CacheConfig, CallBookCache};
+
+use crate::graphql::client_graph_account::query_graph_account;
+use crate::graphql::client_graph_node::indexing_statuses::IndexingStatusesIndexingStatuses;
+use crate::graphql::client_graph_node::{
+    get_indexing_statuses, query_graph_node_network_block_hash,
+};
+use crate::graphql::client_network::{query_network_subgraph, Network};
+use crate::graphql::client_registry::query_registry;
+use crate::graphql::{
+    client::{GraphqlClient, HttpConfig},
+    QueryError,
+};
+use crate::Account;
+
+pub mod cache;
+
+#[derive(Clone, Debug, Getters, Serialize, Deserialize)]
+pub struct CallBook {
+    /// A constant defining the graph node endpoint
+    graph_node_status: String,
+    /// A constant defining Graphcast registry subgraph endpoint
+    graphcast_registry: String,
+    /// A constant defining The Graph network subgraph endpoint
+    graph_network: String,
+    /// Cached query results shared by the clones of the CallBook
+    #[serde(skip)]
+    #[getter(skip)]
+    cache: Arc<CallBookCache>,
+    /// HTTP client shared by every query
+    #[serde(skip)]
+    #[getter(skip)]
+    client: GraphqlClient,
+}
+
+impl PartialEq for CallBook {
+    fn eq(&self, other: &Self) -> bool {
+        self.graph_node_status == other.graph_node_status
+            && self.graphcast_registry == other.graphcast_registry
+            && self.graph_network == other.graph_network
+    }
+}
+
+impl CallBook {
+    pub fn new(
+        graph_node_status: String,
+        graphcast_registry: String,
+        graph_network: String,
+    ) -> CallBook {
+        CallBook {
+            graph_node_status,
+            graphcast_registry,
+            graph_network,
+            cache: Arc::new(CallBookCache::default()),
+            client: GraphqlClient::default(),
+        }
+    }
+
+    /// Replace the HTTP client with one using the provided settings
+    pub fn with_http_config(mut self, config: HttpConfig) -> CallBook {
+        self.client = GraphqlClient::new(config);
+        self
+    }
+
+    pub fn client(&self) -> &GraphqlClient {
+        &self.client
+    }
+
+    /// Replace the query cache with one using the provided TTLs
+    pub fn with_cache_config(mut self, config: CacheConfig) -> CallBook {
+        self.cache = Arc::new(CallBookCache::new(config));
+        self
+    }
+
+    pub fn cache(&self) -> &CallBookCache {
+        &self.cache
+    }
+
+    pub async fn block_hash(
+        &self,
+        network: String,
+        block_number: u64,
+    ) -> Result<String, QueryError> {
+        self.cache
+            .block_hashes
+            .get_or_fetch((network.clone(), block_number), || {
+                query_graph_node_network_block_hash(
+                    &self.client,
+                    self.graph_node_status.clone(),
+                    network,
+                    block_number,
+                )
+            })
+            .await
+    }
+
+    pub async fn registered_indexer(&self, wallet_address: String) -> Result<String, QueryError> {
+        self.cache
+            .registry
+            .get_or_fetch(wallet_address.clone(), || {
+                query_registry(
+                    &self.client,
+                    self.graphcast_registry.clone(),
+                    wallet_address,
+                )
+            })
+            .await
+    }
+
+    /// Match the agent address with the Graph Account on the network subgraph
+    pub async fn graph_account(
+        &self,
+        agent_address: String,
+        graph_account: String,
+    ) -> Result<Account, QueryError> {
+        self.cache
+            .graph_accounts
+            .get_or_fetch((agent_address.clone(), graph_account.clone()), || {
+                query_graph_account(
+                    &self.client,
+                    self.graph_network.clone(),
+                    agent_address,
+                    graph_account,
+                )
+            })
+            .await
+    }
+
+    pub async fn indexing_statuses(
+        &self,
+    ) -> Result<Vec<IndexingStatusesIndexingStatuses>, QueryError> {
+        get_indexing_statuses(&self.client, self.graph_node_status.clone()).await
+    }
+
+    pub async fn network_subgraph(&self, indexer_address: String) -> Result<Network, QueryError> {
+        self.cache
+            .network
+            .get_or_fetch(indexer_address.clone(), || {
+                query_network_subgraph(&self.client, self.graph_network.clone(), indexer_address)
+            })
+            .await
+    }
+
+    /// Query the network subgraph past the cache, the fresh result replaces the cached one
+    pub async fn refresh_network_subgraph(
+        &self,
+        indexer_address: String,
+    ) -> Result<Network, QueryError> {
+        self.cache
+            .network
+            .refresh(indexer_address.clone(), || {
+                query_network_subgraph(&self.client, self.graph_network.clone(), indexer_address)
+            })
+            .await
+    }
+}
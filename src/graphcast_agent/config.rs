@@ -0,0 +1,1094 @@
+//! Builder and layered loading for `GraphcastAgentConfig`.
+//!
+//! `GraphcastAgentConfigBuilder` sets the agent configurations with typed setters and
+//! defaults for every optional field. `ConfigLayer` holds a partial set of
+//! configurations that can be read from a TOML file, `GRAPHCAST_` prefixed environment
+//! variables, or CLI overrides; layers are merged with later layers taking precedence.
+//!
+//! Building a config reports every invalid field at once instead of stopping at the first.
+//!
+use serde::{Deserialize, Serialize};
+use std::{env, fs, net::IpAddr, path::Path, str::FromStr, time::Duration};
+use tracing::warn;
+use url::Url;
+use waku::Multiaddr;
+
+use super::{
+    convert_to_multiaddrs,
+    heartbeat::DEFAULT_PEER_ROSTER_TTL,
+    history::StoreConfig,
+    message_typing::IdentityValidation,
+    node::{NodeLogLevel, NodeRole, NodeServices, WakuOptions},
+    nonce_store::{NonceStoreConfig, DEFAULT_NONCE_FLUSH_INTERVAL, DEFAULT_NONCE_MAX_AGE},
+    seen_messages::DEFAULT_SEEN_MESSAGES_CAPACITY,
+    ConfigError, GraphcastAgentConfig, DEFAULT_MAX_CLOCK_SKEW, DEFAULT_REPLAY_WINDOW,
+};
+use crate::{build_wallet, callbook::cache::CacheConfig, graphql::client::HttpConfig};
+
+/// Prefix of environment variables read by `ConfigLayer::from_env`
+pub const ENV_PREFIX: &str = "GRAPHCAST_";
+/// Un-prefixed Waku log level variable read before `ENV_PREFIX` was introduced, deprecated
+const LEGACY_WAKU_LOG_LEVEL_VAR: &str = "WAKU_LOG_LEVEL";
+
+/// How the agent treats the remote set up checks against the registry subgraph,
+/// the network subgraph and the graph node during start up
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default, clap::ValueEnum, Serialize, Deserialize)]
+pub enum StartupPolicy {
+    // fail to start unless every check passes
+    #[default]
+    Strict,
+    // start right away and keep retrying the checks in the background
+    Deferred,
+    // skip the remote checks
+    Offline,
+}
+
+/// Partial agent configurations, one source of the layered config loading
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub wallet_key: Option<String>,
+    pub graph_account: Option<String>,
+    pub radio_name: Option<String>,
+    pub registry_subgraph: Option<String>,
+    pub network_subgraph: Option<String>,
+    pub graph_node_endpoint: Option<String>,
+    pub boot_node_addresses: Option<Vec<String>>,
+    pub graphcast_namespace: Option<String>,
+    pub subtopics: Option<Vec<String>>,
+    pub waku_node_key: Option<String>,
+    pub waku_host: Option<String>,
+    pub waku_port: Option<String>,
+    pub waku_addr: Option<String>,
+    pub filter_protocol: Option<bool>,
+    pub discv5_enrs: Option<Vec<String>>,
+    pub discv5_port: Option<u16>,
+    pub id_validation: Option<String>,
+    pub startup_policy: Option<String>,
+    pub node_role: Option<String>,
+    pub waku_log_level: Option<String>,
+    pub radio_version: Option<u32>,
+    pub accepted_radio_versions: Option<Vec<u32>>,
+    /// Protocols served to other nodes: store and filter
+    pub node_services: Option<Vec<String>>,
+    pub store_database_url: Option<String>,
+    pub store_retention_max_messages: Option<usize>,
+    /// In seconds
+    pub store_retention_max_seconds: Option<usize>,
+    pub store_peer: Option<String>,
+    pub seen_messages_capacity: Option<usize>,
+    /// In seconds
+    pub seen_messages_ttl: Option<u64>,
+    pub accept_legacy_signatures: Option<bool>,
+}
+
+impl ConfigLayer {
+    /// Parse a layer from TOML content
+    pub fn from_toml(content: &str) -> Result<Self, ConfigError> {
+        toml::from_str(content)
+            .map_err(|e| ConfigError::ValidateInput(format!("Invalid TOML configurations: {e}")))
+    }
+
+    /// Read a layer from a TOML file
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ConfigError::ValidateInput(format!(
+                "Could not read config file {}: {e}",
+                path.display()
+            ))
+        })?;
+        ConfigLayer::from_toml(&content)
+    }
+
+    /// Read a layer from `GRAPHCAST_` prefixed environment variables, such as
+    /// `GRAPHCAST_WALLET_KEY` or `GRAPHCAST_SUBTOPICS`. List values are comma separated.
+    /// The deprecated un-prefixed `WAKU_LOG_LEVEL` is still read when
+    /// `GRAPHCAST_WAKU_LOG_LEVEL` is not set.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        ConfigLayer::from_prefixed_vars(|name| env::var(name).ok())
+    }
+
+    fn from_prefixed_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut layer = ConfigLayer::from_vars(|name| var(&format!("{ENV_PREFIX}{name}")))?;
+        if layer.waku_log_level.is_none() {
+            if let Some(level) = var(LEGACY_WAKU_LOG_LEVEL_VAR) {
+                warn!(
+                    "{LEGACY_WAKU_LOG_LEVEL_VAR} is deprecated, use {ENV_PREFIX}WAKU_LOG_LEVEL instead"
+                );
+                layer.waku_log_level = Some(level);
+            }
+        }
+        Ok(layer)
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut errors = vec![];
+        let list = |name: &str| {
+            var(name).map(|v| {
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect::<Vec<String>>()
+            })
+        };
+        let discv5_port = var("DISCV5_PORT")
+            .and_then(|v| parse_var("DISCV5_PORT", &v, "a port number", &mut errors));
+        let filter_protocol = var("FILTER_PROTOCOL")
+            .and_then(|v| parse_var("FILTER_PROTOCOL", &v, "true or false", &mut errors));
+        let radio_version = var("RADIO_VERSION")
+            .and_then(|v| parse_var("RADIO_VERSION", &v, "a version number", &mut errors));
+        let accepted_radio_versions = list("ACCEPTED_RADIO_VERSIONS").map(|versions| {
+            versions
+                .iter()
+                .filter_map(|v| {
+                    parse_var("ACCEPTED_RADIO_VERSIONS", v, "version numbers", &mut errors)
+                })
+                .collect::<Vec<u32>>()
+        });
+        let store_retention_max_messages = var("STORE_RETENTION_MAX_MESSAGES").and_then(|v| {
+            parse_var(
+                "STORE_RETENTION_MAX_MESSAGES",
+                &v,
+                "a number of messages",
+                &mut errors,
+            )
+        });
+        let store_retention_max_seconds = var("STORE_RETENTION_MAX_SECONDS").and_then(|v| {
+            parse_var(
+                "STORE_RETENTION_MAX_SECONDS",
+                &v,
+                "a number of seconds",
+                &mut errors,
+            )
+        });
+        let seen_messages_capacity = var("SEEN_MESSAGES_CAPACITY").and_then(|v| {
+            parse_var(
+                "SEEN_MESSAGES_CAPACITY",
+                &v,
+                "a number of messages",
+                &mut errors,
+            )
+        });
+        let seen_messages_ttl = var("SEEN_MESSAGES_TTL")
+            .and_then(|v| parse_var("SEEN_MESSAGES_TTL", &v, "a number of seconds", &mut errors));
+        let accept_legacy_signatures = var("ACCEPT_LEGACY_SIGNATURES")
+            .and_then(|v| parse_var("ACCEPT_LEGACY_SIGNATURES", &v, "true or false", &mut errors));
+
+        let layer = ConfigLayer {
+            wallet_key: var("WALLET_KEY"),
+            graph_account: var("GRAPH_ACCOUNT"),
+            radio_name: var("RADIO_NAME"),
+            registry_subgraph: var("REGISTRY_SUBGRAPH"),
+            network_subgraph: var("NETWORK_SUBGRAPH"),
+            graph_node_endpoint: var("GRAPH_NODE_ENDPOINT"),
+            boot_node_addresses: list("BOOT_NODE_ADDRESSES"),
+            graphcast_namespace: var("NAMESPACE"),
+            subtopics: list("SUBTOPICS"),
+            waku_node_key: var("WAKU_NODE_KEY"),
+            waku_host: var("WAKU_HOST"),
+            waku_port: var("WAKU_PORT"),
+            waku_addr: var("WAKU_ADDR"),
+            filter_protocol,
+            discv5_enrs: list("DISCV5_ENRS"),
+            discv5_port,
+            id_validation: var("ID_VALIDATION"),
+            startup_policy: var("STARTUP_POLICY"),
+            node_role: var("NODE_ROLE"),
+            waku_log_level: var("WAKU_LOG_LEVEL"),
+            radio_version,
+            accepted_radio_versions,
+            node_services: list("NODE_SERVICES"),
+            store_database_url: var("STORE_DATABASE_URL"),
+            store_retention_max_messages,
+            store_retention_max_seconds,
+            store_peer: var("STORE_PEER"),
+            seen_messages_capacity,
+            seen_messages_ttl,
+            accept_legacy_signatures,
+        };
+        ConfigError::collect(errors).map(|_| layer)
+    }
+
+    /// Merge two layers, values set in `other` take precedence
+    pub fn merge(self, other: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            wallet_key: other.wallet_key.or(self.wallet_key),
+            graph_account: other.graph_account.or(self.graph_account),
+            radio_name: other.radio_name.or(self.radio_name),
+            registry_subgraph: other.registry_subgraph.or(self.registry_subgraph),
+            network_subgraph: other.network_subgraph.or(self.network_subgraph),
+            graph_node_endpoint: other.graph_node_endpoint.or(self.graph_node_endpoint),
+            boot_node_addresses: other.boot_node_addresses.or(self.boot_node_addresses),
+            graphcast_namespace: other.graphcast_namespace.or(self.graphcast_namespace),
+            subtopics: other.subtopics.or(self.subtopics),
+            waku_node_key: other.waku_node_key.or(self.waku_node_key),
+            waku_host: other.waku_host.or(self.waku_host),
+            waku_port: other.waku_port.or(self.waku_port),
+            waku_addr: other.waku_addr.or(self.waku_addr),
+            filter_protocol: other.filter_protocol.or(self.filter_protocol),
+            discv5_enrs: other.discv5_enrs.or(self.discv5_enrs),
+            discv5_port: other.discv5_port.or(self.discv5_port),
+            id_validation: other.id_validation.or(self.id_validation),
+            startup_policy: other.startup_policy.or(self.startup_policy),
+            node_role: other.node_role.or(self.node_role),
+            waku_log_level: other.waku_log_level.or(self.waku_log_level),
+            radio_version: other.radio_version.or(self.radio_version),
+            accepted_radio_versions: other
+                .accepted_radio_versions
+                .or(self.accepted_radio_versions),
+            node_services: other.node_services.or(self.node_services),
+            store_database_url: other.store_database_url.or(self.store_database_url),
+            store_retention_max_messages: other
+                .store_retention_max_messages
+                .or(self.store_retention_max_messages),
+            store_retention_max_seconds: other
+                .store_retention_max_seconds
+                .or(self.store_retention_max_seconds),
+            store_peer: other.store_peer.or(self.store_peer),
+            seen_messages_capacity: other.seen_messages_capacity.or(self.seen_messages_capacity),
+            seen_messages_ttl: other.seen_messages_ttl.or(self.seen_messages_ttl),
+            accept_legacy_signatures: other
+                .accept_legacy_signatures
+                .or(self.accept_legacy_signatures),
+        }
+    }
+}
+
+/// Parse the value of a `GRAPHCAST_` prefixed variable, recording an error when it is invalid
+fn parse_var<T>(name: &str, value: &str, expected: &str, errors: &mut Vec<ConfigError>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map_err(|e| {
+            errors.push(ConfigError::ValidateInput(format!(
+                "{ENV_PREFIX}{name} must be {expected}: {e}"
+            )))
+        })
+        .ok()
+}
+
+/// Typed builder for `GraphcastAgentConfig`.
+///
+/// `wallet_key`, `graph_account`, `radio_name`, `registry_subgraph`, `network_subgraph`
+/// and `graph_node_endpoint` are required, every other field has a default.
+#[derive(Clone, Debug, Default)]
+pub struct GraphcastAgentConfigBuilder {
+    layer: ConfigLayer,
+    id_validation: Option<IdentityValidation>,
+    startup_policy: Option<StartupPolicy>,
+    node_role: Option<NodeRole>,
+    node_services: Option<NodeServices>,
+    waku_options: Option<WakuOptions>,
+    cache_config: CacheConfig,
+    http_config: HttpConfig,
+    nonce_store: NonceStoreConfig,
+    nonce_flush_interval: Option<Duration>,
+    nonce_max_age: Option<Duration>,
+    seen_messages_ttl: Option<Duration>,
+    replay_window: Option<Duration>,
+    max_clock_skew: Option<Duration>,
+    peer_roster_ttl: Option<Duration>,
+}
+
+impl GraphcastAgentConfigBuilder {
+    pub fn new() -> Self {
+        GraphcastAgentConfigBuilder::default()
+    }
+
+    /// Private key or mnemonic of the Graphcast ID wallet
+    pub fn wallet_key(mut self, wallet_key: impl Into<String>) -> Self {
+        self.layer.wallet_key = Some(wallet_key.into());
+        self
+    }
+
+    /// Graph account represented by the Graphcast ID
+    pub fn graph_account(mut self, graph_account: impl Into<String>) -> Self {
+        self.layer.graph_account = Some(graph_account.into());
+        self
+    }
+
+    pub fn radio_name(mut self, radio_name: impl Into<String>) -> Self {
+        self.layer.radio_name = Some(radio_name.into());
+        self
+    }
+
+    pub fn registry_subgraph(mut self, endpoint: impl Into<String>) -> Self {
+        self.layer.registry_subgraph = Some(endpoint.into());
+        self
+    }
+
+    pub fn network_subgraph(mut self, endpoint: impl Into<String>) -> Self {
+        self.layer.network_subgraph = Some(endpoint.into());
+        self
+    }
+
+    pub fn graph_node_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.layer.graph_node_endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn boot_node_addresses(mut self, addresses: Vec<String>) -> Self {
+        self.layer.boot_node_addresses = Some(addresses);
+        self
+    }
+
+    /// Namespace of the pubsub topic, defaults to "testnet"
+    pub fn graphcast_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.layer.graphcast_namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn subtopics(mut self, subtopics: Vec<String>) -> Self {
+        self.layer.subtopics = Some(subtopics);
+        self
+    }
+
+    pub fn waku_node_key(mut self, key: impl Into<String>) -> Self {
+        self.layer.waku_node_key = Some(key.into());
+        self
+    }
+
+    pub fn waku_host(mut self, host: impl Into<String>) -> Self {
+        self.layer.waku_host = Some(host.into());
+        self
+    }
+
+    pub fn waku_port(mut self, port: impl Into<String>) -> Self {
+        self.layer.waku_port = Some(port.into());
+        self
+    }
+
+    pub fn waku_addr(mut self, addr: impl Into<String>) -> Self {
+        self.layer.waku_addr = Some(addr.into());
+        self
+    }
+
+    /// Use the filter protocol (default) or the relay protocol for subscriptions, picks the
+    /// light or relay node role when `node_role` is not set
+    pub fn filter_protocol(mut self, enabled: bool) -> Self {
+        self.layer.filter_protocol = Some(enabled);
+        self
+    }
+
+    pub fn discv5_enrs(mut self, enrs: Vec<String>) -> Self {
+        self.layer.discv5_enrs = Some(enrs);
+        self
+    }
+
+    pub fn discv5_port(mut self, port: u16) -> Self {
+        self.layer.discv5_port = Some(port);
+        self
+    }
+
+    /// Sender identity validation mechanism, defaults to `RegisteredIndexer`
+    pub fn id_validation(mut self, id_validation: IdentityValidation) -> Self {
+        self.id_validation = Some(id_validation);
+        self.layer.id_validation = None;
+        self
+    }
+
+    /// Remote set up checks policy, defaults to `Strict`
+    pub fn startup_policy(mut self, startup_policy: StartupPolicy) -> Self {
+        self.startup_policy = Some(startup_policy);
+        self.layer.startup_policy = None;
+        self
+    }
+
+    /// Role of the Waku node, defaults to a light node unless `filter_protocol` is disabled
+    pub fn node_role(mut self, node_role: NodeRole) -> Self {
+        self.node_role = Some(node_role);
+        self.layer.node_role = None;
+        self
+    }
+
+    /// Protocols served to other nodes, defaults to all of them for boot nodes and none for
+    /// relay and light nodes
+    pub fn node_services(mut self, node_services: NodeServices) -> Self {
+        self.node_services = Some(node_services);
+        self.layer.node_services = None;
+        self
+    }
+
+    /// Remaining Waku node settings, defaults to the preset of the node role. A log level
+    /// set through a config layer takes precedence
+    pub fn waku_options(mut self, waku_options: WakuOptions) -> Self {
+        self.waku_options = Some(waku_options);
+        self
+    }
+
+    /// Time-to-live settings of the CallBook query cache
+    pub fn cache_config(mut self, cache_config: CacheConfig) -> Self {
+        self.cache_config = cache_config;
+        self
+    }
+
+    /// Timeout, retry and per-endpoint header settings of the CallBook HTTP client
+    pub fn http_config(mut self, http_config: HttpConfig) -> Self {
+        self.http_config = http_config;
+        self
+    }
+
+    /// Backend persisting sender nonces across restarts, defaults to memory only
+    pub fn nonce_store(mut self, nonce_store: NonceStoreConfig) -> Self {
+        self.nonce_store = nonce_store;
+        self
+    }
+
+    /// Interval between nonce flushes to a persistent store, defaults to a minute
+    pub fn nonce_flush_interval(mut self, interval: Duration) -> Self {
+        self.nonce_flush_interval = Some(interval);
+        self
+    }
+
+    /// Age after which a sender's nonce is pruned, defaults to a week
+    pub fn nonce_max_age(mut self, max_age: Duration) -> Self {
+        self.nonce_max_age = Some(max_age);
+        self
+    }
+
+    /// Number of message ids kept to drop duplicate messages
+    pub fn seen_messages_capacity(mut self, capacity: usize) -> Self {
+        self.layer.seen_messages_capacity = Some(capacity);
+        self
+    }
+
+    /// Time a message id is remembered after it was last seen, defaults to the replay window
+    /// and cannot be shorter
+    pub fn seen_messages_ttl(mut self, ttl: Duration) -> Self {
+        self.seen_messages_ttl = Some(ttl);
+        self.layer.seen_messages_ttl = None;
+        self
+    }
+
+    /// Age after which messages are dropped as replays
+    pub fn replay_window(mut self, window: Duration) -> Self {
+        self.replay_window = Some(window);
+        self
+    }
+
+    /// Tolerance for message timestamps ahead of the local clock
+    pub fn max_clock_skew(mut self, skew: Duration) -> Self {
+        self.max_clock_skew = Some(skew);
+        self
+    }
+
+    /// Accept legacy messages whose signature only covers the payload, defaults to false.
+    /// Their nonce, block and network fields can be rewritten by any relaying peer, so only
+    /// opt in while the radio's peers migrate to envelope signatures
+    pub fn accept_legacy_signatures(mut self, accept: bool) -> Self {
+        self.layer.accept_legacy_signatures = Some(accept);
+        self
+    }
+
+    /// Time a peer stays in the roster after its last heartbeat
+    pub fn peer_roster_ttl(mut self, ttl: Duration) -> Self {
+        self.peer_roster_ttl = Some(ttl);
+        self
+    }
+
+    /// Version of the radio's content topics that messages are published on, defaults to 0
+    pub fn radio_version(mut self, version: u32) -> Self {
+        self.layer.radio_version = Some(version);
+        self
+    }
+
+    /// Other versions of the radio's content topics to receive messages from, such as the
+    /// previous version while peers roll out a payload schema change
+    pub fn accepted_radio_versions(mut self, versions: Vec<u32>) -> Self {
+        self.layer.accepted_radio_versions = Some(versions);
+        self
+    }
+
+    /// Store database, retention and queried store peer of the Waku node, defaults to an
+    /// in memory store and any connected store peer
+    pub fn store(mut self, store: StoreConfig) -> Self {
+        self.layer.store_database_url = store.database_url;
+        self.layer.store_retention_max_messages = store.retention_max_messages;
+        self.layer.store_retention_max_seconds = store.retention_max_seconds;
+        self.layer.store_peer = store.store_peer;
+        self
+    }
+
+    /// Apply a layer of configurations on top of the values already set
+    pub fn layer(mut self, layer: ConfigLayer) -> Self {
+        if layer.id_validation.is_some() {
+            self.id_validation = None;
+        }
+        if layer.startup_policy.is_some() {
+            self.startup_policy = None;
+        }
+        if layer.node_role.is_some() {
+            self.node_role = None;
+        }
+        if layer.node_services.is_some() {
+            self.node_services = None;
+        }
+        if layer.seen_messages_ttl.is_some() {
+            self.seen_messages_ttl = None;
+        }
+        self.layer = self.layer.merge(layer);
+        self
+    }
+
+    /// Validate the configurations and build the config, reporting every invalid field
+    pub fn build(self) -> Result<GraphcastAgentConfig, ConfigError> {
+        let mut errors = vec![];
+        let ConfigLayer {
+            wallet_key,
+            graph_account,
+            radio_name,
+            registry_subgraph,
+            network_subgraph,
+            graph_node_endpoint,
+            boot_node_addresses,
+            graphcast_namespace,
+            subtopics,
+            waku_node_key,
+            waku_host,
+            waku_port,
+            waku_addr,
+            filter_protocol,
+            discv5_enrs,
+            discv5_port,
+            id_validation,
+            startup_policy,
+            node_role,
+            waku_log_level,
+            radio_version,
+            accepted_radio_versions,
+            node_services,
+            store_database_url,
+            store_retention_max_messages,
+            store_retention_max_seconds,
+            store_peer,
+            seen_messages_capacity,
+            seen_messages_ttl,
+            accept_legacy_signatures,
+        } = self.layer;
+
+        let mut required = |name: &str, value: Option<String>| {
+            value.unwrap_or_else(|| {
+                errors.push(ConfigError::ValidateInput(format!("Missing {name}")));
+                String::new()
+            })
+        };
+        let wallet_key = required("wallet_key", wallet_key);
+        let graph_account = required("graph_account", graph_account);
+        let radio_name = required("radio_name", radio_name);
+        let registry_subgraph = required("registry_subgraph", registry_subgraph);
+        let network_subgraph = required("network_subgraph", network_subgraph);
+        let graph_node_endpoint = required("graph_node_endpoint", graph_node_endpoint);
+
+        if !wallet_key.is_empty() {
+            if let Err(e) = build_wallet(&wallet_key) {
+                errors.push(ConfigError::ValidateInput(format!(
+                    "Invalid key to wallet, use private key or mnemonic: {e}"
+                )));
+            }
+        }
+        if !graph_account.is_empty() && ethers::types::Address::from_str(&graph_account).is_err() {
+            errors.push(ConfigError::ValidateInput(format!(
+                "Graph account {graph_account} is not a valid address"
+            )));
+        }
+        [
+            ("registry_subgraph", &registry_subgraph),
+            ("network_subgraph", &network_subgraph),
+            ("graph_node_endpoint", &graph_node_endpoint),
+        ]
+        .iter()
+        .filter(|(_, endpoint)| !endpoint.is_empty())
+        .for_each(|(name, endpoint)| {
+            if let Err(e) = Url::parse(endpoint) {
+                errors.push(ConfigError::ValidateInput(format!(
+                    "{name} {endpoint} is not a valid url: {e}"
+                )));
+            }
+        });
+
+        let boot_node_addresses = boot_node_addresses
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|address| {
+                convert_to_multiaddrs(&[address.clone()])
+                    .map_err(|e| {
+                        errors.push(ConfigError::ValidateInput(format!(
+                            "Invalid boot node address {address}: {e}"
+                        )))
+                    })
+                    .ok()
+            })
+            .flatten()
+            .collect::<Vec<Multiaddr>>();
+        if let Some(key) = &waku_node_key {
+            if waku::SecretKey::from_str(key).is_err() {
+                errors.push(ConfigError::ValidateInput(String::from(
+                    "Waku node key is not a valid secp256k1 private key",
+                )));
+            }
+        }
+        if let Some(host) = &waku_host {
+            if let Err(e) = IpAddr::from_str(host) {
+                errors.push(ConfigError::ValidateInput(format!(
+                    "Waku host {host} is not a valid IP address: {e}"
+                )));
+            }
+        }
+        if let Some(port) = &waku_port {
+            if let Err(e) = port.parse::<u16>() {
+                errors.push(ConfigError::ValidateInput(format!(
+                    "Waku port {port} is not a valid port: {e}"
+                )));
+            }
+        }
+        if let Some(addr) = &waku_addr {
+            if let Err(e) = Multiaddr::from_str(addr) {
+                errors.push(ConfigError::ValidateInput(format!(
+                    "Waku advertised address {addr} is not a valid multiaddress: {e}"
+                )));
+            }
+        }
+        let id_validation = match (self.id_validation, id_validation) {
+            (Some(id_validation), _) => Some(id_validation),
+            (None, Some(value)) => <IdentityValidation as clap::ValueEnum>::from_str(&value, true)
+                .map_err(|e| {
+                    errors.push(ConfigError::ValidateInput(format!(
+                        "Invalid identity validation mechanism {value}: {e}"
+                    )))
+                })
+                .ok(),
+            (None, None) => None,
+        };
+        let startup_policy = match (self.startup_policy, startup_policy) {
+            (Some(startup_policy), _) => startup_policy,
+            (None, Some(value)) => <StartupPolicy as clap::ValueEnum>::from_str(&value, true)
+                .unwrap_or_else(|e| {
+                    errors.push(ConfigError::ValidateInput(format!(
+                        "Invalid startup policy {value}: {e}"
+                    )));
+                    StartupPolicy::default()
+                }),
+            (None, None) => StartupPolicy::default(),
+        };
+        let node_role = match (self.node_role, node_role) {
+            (Some(node_role), _) => node_role,
+            (None, Some(value)) => <NodeRole as clap::ValueEnum>::from_str(&value, true)
+                .unwrap_or_else(|e| {
+                    errors.push(ConfigError::ValidateInput(format!(
+                        "Invalid node role {value}: {e}"
+                    )));
+                    NodeRole::default()
+                }),
+            (None, None) => NodeRole::from_filter_protocol(filter_protocol),
+        };
+        let node_services = match (self.node_services, node_services) {
+            (Some(node_services), _) => node_services,
+            (None, Some(names)) => NodeServices::from_names(&names).unwrap_or_else(|e| {
+                errors.push(e);
+                node_role.default_services()
+            }),
+            (None, None) => node_role.default_services(),
+        };
+        if let Err(e) = node_services.validate(node_role) {
+            errors.push(e);
+        }
+        let mut waku_options = self
+            .waku_options
+            .unwrap_or_else(|| WakuOptions::for_role(node_role));
+        if let Some(value) = waku_log_level {
+            match <NodeLogLevel as clap::ValueEnum>::from_str(&value, true) {
+                Ok(log_level) => waku_options.log_level = log_level,
+                Err(e) => errors.push(ConfigError::ValidateInput(format!(
+                    "Invalid Waku log level {value}: {e}"
+                ))),
+            }
+        }
+        if let Err(e) = waku_options.validate(node_role) {
+            errors.push(e);
+        }
+        // Message ids must be remembered as long as the messages pass the timestamp check,
+        // as the nonce check accepts a replayed message with the sender's latest nonce
+        let replay_window = self.replay_window.unwrap_or(DEFAULT_REPLAY_WINDOW);
+        let seen_messages_ttl = self
+            .seen_messages_ttl
+            .or(seen_messages_ttl.map(Duration::from_secs))
+            .unwrap_or(replay_window);
+        if seen_messages_ttl < replay_window {
+            errors.push(ConfigError::ValidateInput(format!(
+                "Seen messages TTL {seen_messages_ttl:?} is shorter than the replay window {replay_window:?}"
+            )));
+        }
+
+        ConfigError::collect(errors)?;
+        Ok(GraphcastAgentConfig {
+            wallet_key,
+            graph_account,
+            radio_name,
+            registry_subgraph,
+            network_subgraph,
+            graph_node_endpoint,
+            boot_node_addresses,
+            graphcast_namespace,
+            subtopics: subtopics.unwrap_or_default(),
+            waku_node_key,
+            waku_host,
+            waku_port,
+            waku_addr,
+            // Light nodes, the default role, receive messages through the filter protocol
+            filter_protocol: Some(!node_role.relay()),
+            discv5_enrs: discv5_enrs.unwrap_or_default(),
+            discv5_port,
+            id_validation,
+            startup_policy,
+            node_role,
+            node_services,
+            waku_options,
+            cache_config: self.cache_config,
+            http_config: self.http_config,
+            nonce_store: self.nonce_store,
+            nonce_flush_interval: self
+                .nonce_flush_interval
+                .unwrap_or(DEFAULT_NONCE_FLUSH_INTERVAL),
+            nonce_max_age: self.nonce_max_age.unwrap_or(DEFAULT_NONCE_MAX_AGE),
+            seen_messages_capacity: seen_messages_capacity
+                .unwrap_or(DEFAULT_SEEN_MESSAGES_CAPACITY),
+            seen_messages_ttl,
+            replay_window,
+            max_clock_skew: self.max_clock_skew.unwrap_or(DEFAULT_MAX_CLOCK_SKEW),
+            accept_legacy_signatures: accept_legacy_signatures.unwrap_or(false),
+            peer_roster_ttl: self.peer_roster_ttl.unwrap_or(DEFAULT_PEER_ROSTER_TTL),
+            radio_version: radio_version.unwrap_or_default(),
+            accepted_radio_versions: accepted_radio_versions.unwrap_or_default(),
+            store: StoreConfig {
+                database_url: store_database_url,
+                retention_max_messages: store_retention_max_messages,
+                retention_max_seconds: store_retention_max_seconds,
+                store_peer,
+            },
+        })
+    }
+}
+
+impl GraphcastAgentConfig {
+    pub fn builder() -> GraphcastAgentConfigBuilder {
+        GraphcastAgentConfigBuilder::new()
+    }
+
+    /// Load configurations from an optional TOML file, then `GRAPHCAST_` prefixed environment
+    /// variables, then the CLI overrides, with later sources taking precedence
+    pub fn load(path: Option<&Path>, overrides: ConfigLayer) -> Result<Self, ConfigError> {
+        let file = match path {
+            Some(path) => ConfigLayer::from_file(path)?,
+            None => ConfigLayer::default(),
+        };
+        let env = ConfigLayer::from_env()?;
+        GraphcastAgentConfig::builder()
+            .layer(file)
+            .layer(env)
+            .layer(overrides)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn required_builder() -> GraphcastAgentConfigBuilder {
+        GraphcastAgentConfig::builder()
+            .wallet_key("1231231231231231231231231231231231231231231231231231231231231230")
+            .graph_account("0xe9a1cabd57700b17945fd81feefba82340d9568f")
+            .radio_name("test-radio")
+            .registry_subgraph(
+                "https://api.thegraph.com/subgraphs/name/hopeyen/gossip-registry-test",
+            )
+            .network_subgraph("https://gateway.testnet.thegraph.com/network")
+            .graph_node_endpoint("http://localhost:8030/graphql")
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let config = required_builder().build().unwrap();
+        assert_eq!(config.radio_name, "test-radio");
+        assert_eq!(config.filter_protocol, Some(true));
+        assert!(config.subtopics.is_empty());
+        assert!(config.boot_node_addresses.is_empty());
+        assert!(config.id_validation.is_none());
+        assert_eq!(config.startup_policy, StartupPolicy::Strict);
+        assert_eq!(config.node_role, NodeRole::Light);
+        assert!(!config.node_services.any());
+        assert!(!config.accept_legacy_signatures);
+    }
+
+    #[test]
+    fn test_node_role() {
+        let config = required_builder().filter_protocol(false).build().unwrap();
+        assert_eq!(config.node_role, NodeRole::Relay);
+
+        let config = required_builder()
+            .layer(ConfigLayer {
+                node_role: Some(String::from("boot")),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.node_role, NodeRole::Boot);
+        assert_eq!(config.filter_protocol, Some(false));
+        assert!(config.node_services.store);
+        assert_eq!(
+            config.waku_options.gossipsub.history_length,
+            WakuOptions::relay().gossipsub.history_length
+        );
+
+        assert!(required_builder()
+            .node_role(NodeRole::Light)
+            .node_services(NodeServices {
+                store: true,
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+        assert!(required_builder()
+            .layer(ConfigLayer {
+                node_role: Some(String::from("full")),
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_seen_messages_ttl() {
+        let window = Duration::from_secs(7200);
+        let config = required_builder().replay_window(window).build().unwrap();
+        assert_eq!(config.seen_messages_ttl, window);
+        assert!(required_builder()
+            .replay_window(window)
+            .seen_messages_ttl(Duration::from_secs(3600))
+            .build()
+            .is_err());
+        assert!(required_builder()
+            .replay_window(window)
+            .seen_messages_ttl(2 * window)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_builder_reports_all_errors() {
+        let err = GraphcastAgentConfig::builder()
+            .wallet_key("not a key")
+            .graph_account("0xe9a1cabd57700b17945fd81feefba82340d9568f")
+            .graph_node_endpoint("not a url")
+            .waku_port("port")
+            .boot_node_addresses(vec![String::from("")])
+            .build()
+            .unwrap_err();
+        match err {
+            // missing radio_name, registry_subgraph, network_subgraph
+            // and invalid wallet, endpoint, port, boot node
+            ConfigError::Invalid(errors) => assert_eq!(errors.len(), 7),
+            e => panic!("Unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn test_layers_precedence() {
+        let file = ConfigLayer::from_toml(
+            r#"
+            radio_name = "file-radio"
+            subtopics = ["Qmfile"]
+            filter_protocol = false
+            id_validation = "no-check"
+            startup_policy = "offline"
+            "#,
+        )
+        .unwrap();
+        let vars: HashMap<&str, &str> = [("RADIO_NAME", "env-radio"), ("SUBTOPICS", "Qma, Qmb")]
+            .into_iter()
+            .collect();
+        let env = ConfigLayer::from_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        let overrides = ConfigLayer {
+            subtopics: Some(vec![String::from("Qmcli")]),
+            ..Default::default()
+        };
+
+        let config = required_builder()
+            .layer(file.merge(env).merge(overrides))
+            .build()
+            .unwrap();
+        assert_eq!(config.radio_name, "env-radio");
+        assert_eq!(config.subtopics, vec![String::from("Qmcli")]);
+        assert_eq!(config.filter_protocol, Some(false));
+        assert_eq!(config.id_validation, Some(IdentityValidation::NoCheck));
+        assert_eq!(config.startup_policy, StartupPolicy::Offline);
+    }
+
+    #[test]
+    fn test_legacy_waku_log_level() {
+        let vars: HashMap<&str, &str> = [("WAKU_LOG_LEVEL", "debug")].into_iter().collect();
+        let layer =
+            ConfigLayer::from_prefixed_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(layer.waku_log_level, Some(String::from("debug")));
+
+        let vars: HashMap<&str, &str> = [
+            ("WAKU_LOG_LEVEL", "debug"),
+            ("GRAPHCAST_WAKU_LOG_LEVEL", "error"),
+        ]
+        .into_iter()
+        .collect();
+        let layer =
+            ConfigLayer::from_prefixed_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(layer.waku_log_level, Some(String::from("error")));
+    }
+
+    #[test]
+    fn test_waku_options() {
+        let vars: HashMap<&str, &str> = [("WAKU_LOG_LEVEL", "debug")].into_iter().collect();
+        let env = ConfigLayer::from_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        let config = required_builder()
+            .waku_options(WakuOptions {
+                min_peers_to_publish: 2,
+                ..WakuOptions::light()
+            })
+            .layer(env)
+            .build()
+            .unwrap();
+        assert_eq!(config.waku_options.log_level, NodeLogLevel::Debug);
+        assert_eq!(config.waku_options.min_peers_to_publish, 2);
+
+        assert!(required_builder()
+            .layer(ConfigLayer {
+                waku_log_level: Some(String::from("verbose")),
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+        let mut options = WakuOptions::relay();
+        options.gossipsub.d_low = Some(8);
+        options.gossipsub.d = Some(6);
+        assert!(required_builder()
+            .node_role(NodeRole::Relay)
+            .waku_options(options)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_radio_versions_layers() {
+        let file = ConfigLayer::from_toml(
+            r#"
+            radio_version = 1
+            accepted_radio_versions = [0]
+            "#,
+        )
+        .unwrap();
+        let config = required_builder().layer(file.clone()).build().unwrap();
+        assert_eq!(config.radio_version, 1);
+        assert_eq!(config.accepted_radio_versions, vec![0]);
+
+        let vars: HashMap<&str, &str> =
+            [("RADIO_VERSION", "2"), ("ACCEPTED_RADIO_VERSIONS", "0, 1")]
+                .into_iter()
+                .collect();
+        let env = ConfigLayer::from_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        let config = required_builder()
+            .radio_version(3)
+            .layer(file.merge(env))
+            .build()
+            .unwrap();
+        assert_eq!(config.radio_version, 2);
+        assert_eq!(config.accepted_radio_versions, vec![0, 1]);
+
+        let config = required_builder().build().unwrap();
+        assert_eq!(config.radio_version, 0);
+        assert!(config.accepted_radio_versions.is_empty());
+    }
+
+    #[test]
+    fn test_message_and_service_layers() {
+        let file = ConfigLayer::from_toml(
+            r#"
+            node_role = "relay"
+            node_services = ["store"]
+            store_database_url = "sqlite3://store.db"
+            store_retention_max_messages = 1000
+            store_retention_max_seconds = 3600
+            seen_messages_capacity = 500
+            seen_messages_ttl = 7200
+            accept_legacy_signatures = true
+            "#,
+        )
+        .unwrap();
+        let vars: HashMap<&str, &str> = [
+            ("STORE_PEER", "16Uiu2HAmStorePeer"),
+            ("STORE_RETENTION_MAX_MESSAGES", "2000"),
+            ("ACCEPT_LEGACY_SIGNATURES", "false"),
+        ]
+        .into_iter()
+        .collect();
+        let env = ConfigLayer::from_vars(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        let config = required_builder()
+            .seen_messages_ttl(Duration::from_secs(10800))
+            .layer(file.merge(env))
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.node_services,
+            NodeServices {
+                store: true,
+                filter: false,
+            }
+        );
+        assert_eq!(
+            config.store,
+            StoreConfig {
+                database_url: Some(String::from("sqlite3://store.db")),
+                retention_max_messages: Some(2000),
+                retention_max_seconds: Some(3600),
+                store_peer: Some(String::from("16Uiu2HAmStorePeer")),
+            }
+        );
+        assert_eq!(config.seen_messages_capacity, 500);
+        assert_eq!(config.seen_messages_ttl, Duration::from_secs(7200));
+        assert!(!config.accept_legacy_signatures);
+
+        assert!(required_builder()
+            .layer(ConfigLayer {
+                node_services: Some(vec![String::from("store")]),
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+        assert!(required_builder()
+            .layer(ConfigLayer {
+                seen_messages_ttl: Some(60),
+                ..Default::default()
+            })
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_layers() {
+        assert!(ConfigLayer::from_toml("unknown_field = 1").is_err());
+        let vars: HashMap<&str, &str> = [
+            ("DISCV5_PORT", "port"),
+            ("FILTER_PROTOCOL", "maybe"),
+            ("RADIO_VERSION", "-1"),
+            ("ACCEPTED_RADIO_VERSIONS", "0, v1"),
+            ("SEEN_MESSAGES_TTL", "1h"),
+            ("ACCEPT_LEGACY_SIGNATURES", "yes"),
+        ]
+        .into_iter()
+        .collect();
+        match ConfigLayer::from_vars(|name| vars.get(name).map(|v| v.to_string())) {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors.len(), 6),
+            _ => panic!("Expected invalid environment variables"),
+        }
+    }
+}
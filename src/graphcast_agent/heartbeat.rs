@@ -0,0 +1,434 @@
+//! Heartbeats and the roster of live peers.
+//!
+//! A `Heartbeat` is a signed envelope without radio payload. Agents publish it on the
+//! reserved `HEARTBEAT_TOPIC` content topic of their radio to announce liveness, their radio
+//! version and the subtopics they are subscribed to. Received heartbeats are verified and
+//! recorded in a `PeerRoster`, they are never delivered to the radio's message subscriptions.
+//!
+//! Graph accounts in the roster are the ones claimed by the signed heartbeats, they are not
+//! checked against the registry or the network subgraph.
+//!
+use chrono::Utc;
+use ethers::signers::{Signer, Wallet};
+use ethers_core::{
+    abi::{encode, Token},
+    k256::ecdsa::SigningKey,
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712, Eip712Error},
+        Signature, I256, U256,
+    },
+    utils::keccak256,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, sync::Mutex, time::Duration};
+use tracing::trace;
+use waku::{WakuContentTopic, WakuMessage, WakuPubSubTopic};
+
+use super::{
+    message_typing::{check_timestamp, BuildMessageError, ValidationError},
+    transport::GraphcastTransport,
+    waku_handling::{build_content_topics, WakuHandlingError},
+};
+
+/// Content topic name reserved for heartbeats, radios should not use it as a subtopic
+pub const HEARTBEAT_TOPIC: &str = "graphcast-heartbeat";
+/// Default time a peer stays in the roster after the timestamp of its last heartbeat
+pub const DEFAULT_PEER_ROSTER_TTL: Duration = Duration::from_secs(300);
+/// EIP-712 type of a heartbeat
+const HEARTBEAT_TYPE: &str = "Heartbeat(string radioName,uint32 radioVersion,string[] subtopics,int64 nonce,string graphAccount)";
+
+/// Heartbeat content topic of the radio
+pub fn heartbeat_topic(radio_name: &str, radio_version: u32) -> WakuContentTopic {
+    build_content_topics(
+        radio_name,
+        radio_version as usize,
+        &[HEARTBEAT_TOPIC.to_string()],
+    )
+    .swap_remove(0)
+}
+
+/// Signed liveness announcement of an agent
+#[derive(Clone, PartialEq, Eq, Message, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// Radio the agent runs
+    #[prost(string, tag = "1")]
+    pub radio_name: String,
+    /// Version of the radio's content topics
+    #[prost(uint32, tag = "2")]
+    pub radio_version: u32,
+    /// Subtopics the agent is subscribed to
+    #[prost(string, repeated, tag = "3")]
+    pub subtopics: Vec<String>,
+    /// Timestamp in seconds, used as nonce
+    #[prost(int64, tag = "4")]
+    pub nonce: i64,
+    /// Graph account claimed by the sender
+    #[prost(string, tag = "5")]
+    pub graph_account: String,
+    /// Signature over every other field
+    #[prost(string, tag = "6")]
+    pub signature: String,
+}
+
+impl Heartbeat {
+    /// Construct a heartbeat and sign it
+    pub async fn build(
+        wallet: &Wallet<SigningKey>,
+        radio_name: String,
+        radio_version: u32,
+        subtopics: Vec<String>,
+        graph_account: String,
+    ) -> Result<Self, BuildMessageError> {
+        let mut heartbeat = Heartbeat {
+            radio_name,
+            radio_version,
+            subtopics,
+            nonce: Utc::now().timestamp(),
+            graph_account,
+            signature: String::new(),
+        };
+        let sig = wallet
+            .sign_typed_data(&heartbeat)
+            .await
+            .map_err(|_| BuildMessageError::Signing)?;
+        heartbeat.signature = sig.to_string();
+        Ok(heartbeat)
+    }
+
+    /// Recover the sender address from the signature
+    pub fn recover_sender_address(&self) -> Result<String, BuildMessageError> {
+        let invalid = |reason: String| ValidationError::InvalidSignature { reason };
+        let signed_data = self.encode_eip712().map_err(|e| invalid(e.to_string()))?;
+        let address = Signature::from_str(&self.signature)
+            .and_then(|sig| sig.recover(signed_data))
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(format!("{address:#x}"))
+    }
+
+    /// Publish the heartbeat on the content topic
+    pub fn send<N: GraphcastTransport>(
+        &self,
+        transport: &N,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topic: WakuContentTopic,
+    ) -> Result<String, WakuHandlingError> {
+        let waku_message = WakuMessage::new(
+            self.encode_to_vec(),
+            content_topic,
+            2,
+            Utc::now().timestamp() as usize,
+            vec![],
+            true,
+        );
+        trace!(
+            heartbeat = tracing::field::debug(&self),
+            "Sending heartbeat"
+        );
+        transport.publish(pubsub_topic, &waku_message)
+    }
+}
+
+impl Eip712 for Heartbeat {
+    type Error = Eip712Error;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        Ok(EIP712Domain {
+            name: Some(String::from("Graphcast Heartbeat")),
+            version: Some(String::from("0")),
+            chain_id: None,
+            verifying_contract: None,
+            salt: None,
+        })
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(HEARTBEAT_TYPE))
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        let subtopics: Vec<u8> = self
+            .subtopics
+            .iter()
+            .flat_map(|topic| keccak256(topic).to_vec())
+            .collect();
+        Ok(keccak256(encode(&[
+            Token::FixedBytes(Self::type_hash()?.to_vec()),
+            Token::FixedBytes(keccak256(&self.radio_name).to_vec()),
+            Token::Uint(U256::from(self.radio_version)),
+            Token::FixedBytes(keccak256(subtopics).to_vec()),
+            Token::Int(I256::from(self.nonce).into_raw()),
+            Token::FixedBytes(keccak256(&self.graph_account).to_vec()),
+        ])))
+    }
+}
+
+/// A peer that sent a heartbeat recently
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RosterPeer {
+    /// Address recovered from the heartbeat signature
+    pub sender: String,
+    /// Graph account claimed by the peer
+    pub graph_account: String,
+    pub radio_name: String,
+    pub radio_version: u32,
+    pub subtopics: Vec<String>,
+    /// Timestamp of the latest heartbeat, in seconds
+    pub last_heartbeat: i64,
+}
+
+/// Peers whose latest heartbeat is signed within the TTL, keyed by sender address. Liveness
+/// is measured from the signed heartbeat timestamp, so a relayed copy of an old heartbeat does
+/// not keep its sender live
+#[derive(Debug)]
+pub struct PeerRoster {
+    ttl: Duration,
+    peers: Mutex<HashMap<String, RosterPeer>>,
+}
+
+impl Default for PeerRoster {
+    fn default() -> Self {
+        PeerRoster::new(DEFAULT_PEER_ROSTER_TTL)
+    }
+}
+
+impl PeerRoster {
+    pub fn new(ttl: Duration) -> Self {
+        PeerRoster {
+            ttl,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Verify the heartbeat and record its sender. Heartbeats from self, outside the
+    /// timestamp window, or not newer than the sender's latest heartbeat are rejected
+    pub fn record(
+        &self,
+        heartbeat: &Heartbeat,
+        local_sender_id: &str,
+        replay_window: Duration,
+        max_clock_skew: Duration,
+    ) -> Result<RosterPeer, BuildMessageError> {
+        let sender = heartbeat.recover_sender_address()?;
+        if sender == local_sender_id {
+            return Err(ValidationError::FromSelf { sender }.into());
+        }
+        check_timestamp(heartbeat.nonce, replay_window, max_clock_skew)?;
+
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get(&sender) {
+            if heartbeat.nonce <= peer.last_heartbeat {
+                return Err(ValidationError::NonceRegression {
+                    identifier: HEARTBEAT_TOPIC.to_string(),
+                    sender,
+                    nonce: heartbeat.nonce,
+                    saved_nonce: peer.last_heartbeat,
+                }
+                .into());
+            }
+        }
+        let peer = RosterPeer {
+            sender: sender.clone(),
+            graph_account: heartbeat.graph_account.clone(),
+            radio_name: heartbeat.radio_name.clone(),
+            radio_version: heartbeat.radio_version,
+            subtopics: heartbeat.subtopics.clone(),
+            last_heartbeat: heartbeat.nonce,
+        };
+        peers.insert(sender, peer.clone());
+        Ok(peer)
+    }
+
+    /// Live peers sorted by sender address, peers past the TTL are dropped
+    pub fn peers(&self) -> Vec<RosterPeer> {
+        let now = Utc::now().timestamp();
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, peer| self.live(peer, now));
+        let mut live: Vec<RosterPeer> = peers.values().cloned().collect();
+        live.sort_by(|a, b| a.sender.cmp(&b.sender));
+        live
+    }
+
+    /// Latest heartbeat of the sender, if it is still live
+    pub fn get(&self, sender: &str) -> Option<RosterPeer> {
+        let now = Utc::now().timestamp();
+        self.peers
+            .lock()
+            .unwrap()
+            .get(sender)
+            .filter(|peer| self.live(peer, now))
+            .cloned()
+    }
+
+    /// Whether the peer's latest heartbeat was signed within the TTL of `now`, in seconds
+    fn live(&self, peer: &RosterPeer, now: i64) -> bool {
+        now.saturating_sub(peer.last_heartbeat) < self.ttl.as_secs() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_wallet;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        test_support::{test_config, wallet_key, LoopbackPayload},
+        GraphcastAgent,
+    };
+    use futures::{pin_mut, StreamExt};
+
+    fn key() -> String {
+        format!("{:0>64}", 1)
+    }
+
+    async fn heartbeat_at(nonce: i64) -> Heartbeat {
+        let mut heartbeat = Heartbeat {
+            radio_name: String::from("test-radio"),
+            radio_version: 0,
+            subtopics: vec![String::from("Qmone"), String::from("Qmtwo")],
+            nonce,
+            graph_account: String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+            signature: String::new(),
+        };
+        heartbeat.signature = build_wallet(&key())
+            .unwrap()
+            .sign_typed_data(&heartbeat)
+            .await
+            .unwrap()
+            .to_string();
+        heartbeat
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_signature() {
+        let sender = format!("{:#x}", build_wallet(&key()).unwrap().address());
+        let heartbeat = Heartbeat::build(
+            &build_wallet(&key()).unwrap(),
+            String::from("test-radio"),
+            0,
+            vec![String::from("Qmone")],
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        )
+        .await
+        .unwrap();
+        let decoded = Heartbeat::decode(heartbeat.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, heartbeat);
+        assert_eq!(decoded.recover_sender_address().unwrap(), sender);
+
+        let mut rewritten = heartbeat;
+        rewritten.subtopics.pop();
+        assert_ne!(rewritten.recover_sender_address().unwrap(), sender);
+    }
+
+    #[tokio::test]
+    async fn test_roster() {
+        let window = Duration::from_secs(3600);
+        let skew = Duration::from_secs(30);
+        let now = Utc::now().timestamp();
+        let heartbeat = heartbeat_at(now).await;
+        let sender = heartbeat.recover_sender_address().unwrap();
+
+        let roster = PeerRoster::new(Duration::from_secs(60));
+        assert!(matches!(
+            roster.record(&heartbeat, &sender, window, skew),
+            Err(BuildMessageError::Validation(
+                ValidationError::FromSelf { .. }
+            ))
+        ));
+        let peer = roster.record(&heartbeat, "0xlocal", window, skew).unwrap();
+        assert_eq!(peer.subtopics, heartbeat.subtopics);
+        assert_eq!(roster.peers(), vec![peer.clone()]);
+        assert_eq!(roster.get(&sender), Some(peer));
+
+        assert!(matches!(
+            roster.record(&heartbeat_at(now - 10).await, "0xlocal", window, skew),
+            Err(BuildMessageError::Validation(
+                ValidationError::NonceRegression { .. }
+            ))
+        ));
+        // Replays of the latest heartbeat are rejected
+        assert!(matches!(
+            roster.record(&heartbeat, "0xlocal", window, skew),
+            Err(BuildMessageError::Validation(
+                ValidationError::NonceRegression { .. }
+            ))
+        ));
+        assert!(matches!(
+            roster.record(&heartbeat_at(now - 7200).await, "0xlocal", window, skew),
+            Err(BuildMessageError::Validation(
+                ValidationError::Expired { .. }
+            ))
+        ));
+
+        let expiring = PeerRoster::new(Duration::from_secs(60));
+        expiring
+            .record(&heartbeat_at(now - 61).await, "0xlocal", window, skew)
+            .unwrap();
+        assert!(expiring.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_roster_replay_after_ttl() {
+        let window = Duration::from_secs(3600);
+        let skew = Duration::from_secs(30);
+        let roster = PeerRoster::new(Duration::from_secs(60));
+        // The sender's last heartbeat, relayed again once the sender left
+        let heartbeat = heartbeat_at(Utc::now().timestamp() - 120).await;
+        let sender = heartbeat.recover_sender_address().unwrap();
+        roster.record(&heartbeat, "0xlocal", window, skew).unwrap();
+        assert!(roster.get(&sender).is_none());
+
+        // Within the replay window but past the TTL, the replay does not revive the sender
+        assert!(matches!(
+            roster.record(&heartbeat, "0xlocal", window, skew),
+            Err(BuildMessageError::Validation(
+                ValidationError::NonceRegression { .. }
+            ))
+        ));
+        assert!(roster.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_roster() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmone".to_string(), "Qmtwo".to_string()];
+        let sender = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let receiver = GraphcastAgent::with_transport(
+            test_config(&wallet_key(2), vec!["Qmone".to_string()]),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let messages = receiver.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+
+        sender.send_heartbeat().await.unwrap();
+        let mut roster = receiver.roster();
+        for _ in 0..100 {
+            if !roster.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            roster = receiver.roster();
+        }
+        assert_eq!(roster.len(), 1);
+        assert_eq!(roster[0].sender, sender.graphcast_identity.graphcast_id);
+        assert_eq!(
+            roster[0].graph_account,
+            sender.graphcast_identity.graph_account
+        );
+        assert_eq!(roster[0].subtopics, subtopics);
+        // Heartbeats are not delivered to the radio's subscriptions
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), messages.next())
+                .await
+                .is_err()
+        );
+        assert!(sender.roster().is_empty());
+    }
+}
@@ -0,0 +1,214 @@
+//! Message history from Waku store nodes.
+//!
+//! Store nodes persist the messages relayed on their pubsub topics for a retention period.
+//! Agents joining the network, or coming back after downtime, can query them for the
+//! messages they missed. History messages are returned by the transport like live ones
+//! and go through the same decoding and validation pipeline, with their own seen messages
+//! and without the nonce ordering check, which only applies to live messages.
+//!
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of messages requested per page of a store query
+pub const DEFAULT_HISTORY_PAGE_SIZE: usize = 100;
+/// Upper bound on the pages fetched by a single history query
+pub const MAX_HISTORY_PAGES: usize = 50;
+/// Time given to a store node to answer a page request
+pub const DEFAULT_STORE_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Store protocol settings of the agent's Waku node. Messages are only persisted by nodes
+/// serving the store protocol, see `NodeServices::store`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreConfig {
+    /// Database of the stored messages, such as `sqlite3://store.db`. In memory when unset
+    pub database_url: Option<String>,
+    /// Maximum number of stored messages
+    pub retention_max_messages: Option<usize>,
+    /// Maximum age of stored messages, in seconds
+    pub retention_max_seconds: Option<usize>,
+    /// Peer id of the store node queried for history, any connected store peer when unset
+    pub store_peer: Option<String>,
+}
+
+/// Time range of a history query, in seconds since the epoch like message timestamps.
+/// Open ends are unbounded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+impl TimeRange {
+    pub fn new(start: i64, end: i64) -> Self {
+        TimeRange {
+            start: Some(start),
+            end: Some(end),
+        }
+    }
+
+    /// Messages from `start` onwards
+    pub fn since(start: i64) -> Self {
+        TimeRange {
+            start: Some(start),
+            end: None,
+        }
+    }
+
+    /// Messages of the last `duration`
+    pub fn last(duration: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        TimeRange::since(now.saturating_sub(duration.as_secs()) as i64)
+    }
+
+    /// Time elapsed since the start of the range, None when the start is open
+    pub fn start_age(&self) -> Option<Duration> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        self.start
+            .map(|start| Duration::from_secs(now.saturating_sub(start).max(0) as u64))
+    }
+
+    /// Whether `timestamp` falls within the range, both ends included
+    pub fn contains(&self, timestamp: i64) -> bool {
+        self.start.map_or(true, |start| timestamp >= start)
+            && self.end.map_or(true, |end| timestamp <= end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        message_typing::ValidationError,
+        test_support::{cache_block_hash, next, send, test_config, wallet_key, LoopbackPayload},
+        waku_handling::{build_content_topics, WakuHandlingError},
+        GraphcastAgent,
+    };
+    use futures::pin_mut;
+
+    #[test]
+    fn test_time_range() {
+        let range = TimeRange::new(100, 200);
+        assert!(range.contains(100));
+        assert!(range.contains(200));
+        assert!(!range.contains(99));
+        assert!(!range.contains(201));
+
+        assert!(TimeRange::since(100).contains(i64::MAX));
+        assert!(TimeRange::default().contains(0));
+
+        let last = TimeRange::last(Duration::from_secs(60));
+        assert!(last.end.is_none());
+        assert!(!last.contains(last.start.unwrap() - 1));
+        assert!(last.start_age().unwrap() >= Duration::from_secs(60));
+        assert!(TimeRange::default().start_age().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_query_history() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let sender = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let _peer = hub.transport();
+        for content in ["First", "Second"] {
+            send(&sender, "Qmloopback", LoopbackPayload::new(content)).await;
+        }
+
+        // An agent joining later catches up on the messages it missed
+        let late =
+            GraphcastAgent::with_transport(test_config(&wallet_key(2), subtopics), hub.transport())
+                .await
+                .unwrap();
+        cache_block_hash(&late).await;
+        let content_topic = late
+            .match_content_topic("Qmloopback".to_string())
+            .await
+            .unwrap();
+        let history = late
+            .query_history::<LoopbackPayload>(
+                &[content_topic.clone()],
+                TimeRange::last(Duration::from_secs(60)),
+            )
+            .await
+            .unwrap();
+        let contents: Vec<String> = history
+            .into_iter()
+            .map(|msg| msg.unwrap().message.payload.unwrap().content)
+            .collect();
+        assert_eq!(contents, vec!["First", "Second"]);
+
+        let later = TimeRange::since(chrono::Utc::now().timestamp() + 60);
+        assert!(late
+            .query_history::<LoopbackPayload>(&[content_topic], later)
+            .await
+            .unwrap()
+            .is_empty());
+        let other_topic = build_content_topics("radio", 0, &["Qmother".to_string()]);
+        assert!(late
+            .query_history::<LoopbackPayload>(&other_topic, TimeRange::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_history_after_live() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let sender = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let receiver =
+            GraphcastAgent::with_transport(test_config(&wallet_key(2), subtopics), hub.transport())
+                .await
+                .unwrap();
+        cache_block_hash(&receiver).await;
+        let messages = receiver.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+
+        for content in ["First", "Second"] {
+            send(&sender, "Qmloopback", LoopbackPayload::new(content)).await;
+        }
+        assert!(matches!(
+            next(&mut messages).await,
+            Err(WakuHandlingError::Validation(
+                ValidationError::FirstSeenTopic { .. }
+            ))
+        ));
+        let live = next(&mut messages).await.unwrap();
+        let nonces = receiver.nonces.lock().await.clone();
+
+        // The default pipeline accepts the history of messages already received live,
+        // without checking them against or recording them in the live nonces
+        let history = receiver
+            .query_history::<LoopbackPayload>(
+                &[live.content_topic.clone()],
+                TimeRange::last(Duration::from_secs(60)),
+            )
+            .await
+            .unwrap();
+        let contents: Vec<String> = history
+            .into_iter()
+            .map(|msg| msg.unwrap().message.payload.unwrap().content)
+            .collect();
+        assert_eq!(contents, vec!["First", "Second"]);
+        assert_eq!(*receiver.nonces.lock().await, nonces);
+        assert_eq!(
+            nonces["Qmloopback"][&sender.graphcast_identity.graphcast_id],
+            live.message.nonce
+        );
+    }
+}
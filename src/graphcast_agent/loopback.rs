@@ -0,0 +1,453 @@
+//! In-process loopback transport.
+//!
+//! A `LoopbackHub` routes messages between any number of `LoopbackTransport`s living
+//! in the same process, so several Graphcast agents can gossip to each other in tests
+//! without a Waku node or network access. Pubsub and content topic matching follows
+//! the Waku protocols: relay transports receive every message on their subscribed
+//! pubsub topics, filter transports only receive the subscribed content topics.
+//!
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex, RwLock,
+};
+use std::thread;
+use tracing::{debug, trace};
+use waku::{WakuContentTopic, WakuMessage, WakuPubSubTopic};
+
+use super::{
+    history::TimeRange,
+    transport::{GraphcastTransport, MessageHandler, PeerInfo, SendReport, TransportMessage},
+    waku_handling::WakuHandlingError,
+};
+
+/// Number of published messages the hub keeps for history queries
+pub const LOOPBACK_HISTORY_CAPACITY: usize = 1024;
+
+/// Subscriptions of a hub member and the channel to deliver its messages
+struct Member {
+    relay: bool,
+    pubsub_topics: Vec<WakuPubSubTopic>,
+    content_topics: Vec<(WakuPubSubTopic, WakuContentTopic)>,
+    sender: Sender<TransportMessage>,
+}
+
+impl Member {
+    fn accepts(&self, pubsub_topic: &WakuPubSubTopic, content_topic: &WakuContentTopic) -> bool {
+        if self.relay {
+            self.pubsub_topics.contains(pubsub_topic)
+        } else {
+            self.content_topics
+                .iter()
+                .any(|(p, c)| p == pubsub_topic && c == content_topic)
+        }
+    }
+}
+
+/// In-memory message hub shared by loopback transports
+#[derive(Clone, Default)]
+pub struct LoopbackHub {
+    members: Arc<Mutex<HashMap<String, Member>>>,
+    /// Published messages, oldest first, like a store node serving the whole hub
+    history: Arc<Mutex<VecDeque<TransportMessage>>>,
+    peer_counter: Arc<AtomicUsize>,
+}
+
+impl LoopbackHub {
+    pub fn new() -> Self {
+        LoopbackHub::default()
+    }
+
+    /// Create a transport that behaves like a filter protocol light node
+    pub fn transport(&self) -> LoopbackTransport {
+        self.join(false)
+    }
+
+    /// Create a transport that behaves like a relay node, receiving all messages on the pubsub topic
+    pub fn relay_transport(&self) -> LoopbackTransport {
+        self.join(true)
+    }
+
+    fn join(&self, relay: bool) -> LoopbackTransport {
+        let peer_id = format!(
+            "loopback-peer-{}",
+            self.peer_counter.fetch_add(1, Ordering::SeqCst)
+        );
+        let (sender, receiver) = channel();
+        self.members.lock().unwrap().insert(
+            peer_id.clone(),
+            Member {
+                relay,
+                pubsub_topics: vec![],
+                content_topics: vec![],
+                sender,
+            },
+        );
+        debug!(peer_id, relay, "Loopback peer joined the hub");
+        LoopbackTransport {
+            hub: self.clone(),
+            peer_id,
+            receiver: Mutex::new(Some(receiver)),
+            handler: Arc::new(RwLock::new(None)),
+            disconnected: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Number of transports currently connected to the hub
+    pub fn member_count(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    fn store(&self, message: TransportMessage) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == LOOPBACK_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(message);
+    }
+}
+
+/// Transport delivering messages through a `LoopbackHub`
+pub struct LoopbackTransport {
+    hub: LoopbackHub,
+    peer_id: String,
+    receiver: Mutex<Option<Receiver<TransportMessage>>>,
+    handler: Arc<RwLock<Option<MessageHandler>>>,
+    /// Hub members this transport disconnected from, they are not sent messages
+    disconnected: Mutex<HashSet<String>>,
+}
+
+impl GraphcastTransport for LoopbackTransport {
+    fn local_peer_id(&self) -> Result<String, WakuHandlingError> {
+        Ok(self.peer_id.clone())
+    }
+
+    fn peers(&self) -> Result<Vec<PeerInfo>, WakuHandlingError> {
+        let disconnected = self.disconnected.lock().unwrap();
+        Ok(self
+            .hub
+            .members
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|&id| id != &self.peer_id)
+            .map(|id| PeerInfo {
+                peer_id: id.clone(),
+                addresses: vec![],
+                connected: !disconnected.contains(id),
+            })
+            .collect())
+    }
+
+    fn connect_peer(&self, peer_id: &str) -> Result<(), WakuHandlingError> {
+        if !self.hub.members.lock().unwrap().contains_key(peer_id) {
+            return Err(WakuHandlingError::ConnectPeer(format!(
+                "{peer_id} is not in the loopback hub"
+            )));
+        }
+        self.disconnected.lock().unwrap().remove(peer_id);
+        Ok(())
+    }
+
+    fn disconnect_peer(&self, peer_id: &str) -> Result<(), WakuHandlingError> {
+        self.disconnected
+            .lock()
+            .unwrap()
+            .insert(peer_id.to_string());
+        Ok(())
+    }
+
+    fn publish(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<String, WakuHandlingError> {
+        self.publish_report(pubsub_topic, message)?.into_result()
+    }
+
+    /// The message is published once any other peer is in the hub, like gossip on the pubsub
+    /// topic. Peers subscribed to the message's topics are listed as accepting it
+    fn publish_report(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<SendReport, WakuHandlingError> {
+        let members = self.hub.members.lock().unwrap();
+        if !members.contains_key(&self.peer_id) {
+            return Err(WakuHandlingError::NodeStopped);
+        }
+        let mut report = SendReport::default();
+        if members.keys().all(|id| id == &self.peer_id) {
+            return Ok(report);
+        }
+        let message = TransportMessage::hashed(pubsub_topic.clone(), message.clone());
+        let disconnected = self.disconnected.lock().unwrap();
+        members
+            .iter()
+            .filter(|(id, member)| {
+                *id != &self.peer_id
+                    && !disconnected.contains(*id)
+                    && member.accepts(pubsub_topic, message.content_topic())
+            })
+            .for_each(|(id, member)| {
+                trace!(
+                    peer = id,
+                    id = message.message_id,
+                    "Deliver loopback message"
+                );
+                match member.sender.send(message.clone()) {
+                    Ok(()) => report.accepted.push(id.clone()),
+                    // A closed channel means the peer is shutting down
+                    Err(_) => report.fail(id.clone(), "Peer is shutting down"),
+                }
+            });
+        report.message_id = Some(message.message_id.clone());
+        self.hub.store(message);
+        Ok(report)
+    }
+
+    fn subscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError> {
+        let mut members = self.hub.members.lock().unwrap();
+        let member = members.get_mut(&self.peer_id).ok_or_else(|| {
+            WakuHandlingError::ContentTopicsError("Peer left the loopback hub".to_string())
+        })?;
+        if !member.pubsub_topics.contains(pubsub_topic) {
+            member.pubsub_topics.push(pubsub_topic.clone());
+        }
+        content_topics.iter().for_each(|topic| {
+            let subscription = (pubsub_topic.clone(), topic.clone());
+            if !member.content_topics.contains(&subscription) {
+                member.content_topics.push(subscription);
+            }
+        });
+        Ok(())
+    }
+
+    fn unsubscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError> {
+        let mut members = self.hub.members.lock().unwrap();
+        let member = members.get_mut(&self.peer_id).ok_or_else(|| {
+            WakuHandlingError::ContentTopicsError("Peer left the loopback hub".to_string())
+        })?;
+        member
+            .content_topics
+            .retain(|(p, c)| !(p == pubsub_topic && content_topics.contains(c)));
+        Ok(())
+    }
+
+    fn query_history(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+        time_range: TimeRange,
+    ) -> Result<Vec<TransportMessage>, WakuHandlingError> {
+        Ok(self
+            .hub
+            .history
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| {
+                &message.pubsub_topic == pubsub_topic
+                    && content_topics.contains(message.content_topic())
+                    && time_range.contains(message.waku_message.timestamp() as i64)
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn set_message_handler(&self, handler: MessageHandler) {
+        *self.handler.write().unwrap() = Some(handler);
+        // Start delivering on a dedicated thread, like the Waku event callback
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            let handler = self.handler.clone();
+            thread::spawn(move || {
+                for message in receiver {
+                    if let Some(handler) = handler.read().unwrap().as_ref() {
+                        handler(message);
+                    }
+                }
+            });
+        }
+    }
+
+    fn clear_message_handler(&self) {
+        *self.handler.write().unwrap() = None;
+    }
+
+    fn stop(&self) -> Result<(), WakuHandlingError> {
+        // Removing the member closes its channel and ends the delivery thread
+        if let Ok(mut members) = self.hub.members.lock() {
+            if members.remove(&self.peer_id).is_some() {
+                debug!(peer_id = self.peer_id, "Loopback peer left the hub");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        message_typing::GraphcastMessage,
+        test_support::{send, test_config, test_message, wallet_key, LoopbackPayload},
+        waku_handling::{build_content_topics, pubsub_topic},
+        GraphcastAgent,
+    };
+    use prost::Message;
+    use std::time::Duration;
+
+    #[test]
+    fn test_topic_routing() {
+        let hub = LoopbackHub::new();
+        let sender = hub.transport();
+        let filter_peer = hub.transport();
+        let other_topic_peer = hub.transport();
+        let relay_peer = hub.relay_transport();
+
+        let pubsub_topic = pubsub_topic(Some("testnet"));
+        let topics = build_content_topics("radio", 0, &["Qmone".to_string()]);
+        let other_topics = build_content_topics("radio", 0, &["Qmtwo".to_string()]);
+        filter_peer.subscribe(&pubsub_topic, &topics).unwrap();
+        other_topic_peer
+            .subscribe(&pubsub_topic, &other_topics)
+            .unwrap();
+        relay_peer.subscribe(&pubsub_topic, &[]).unwrap();
+
+        let received = |transport: &LoopbackTransport| {
+            let (tx, rx) = channel();
+            let tx = Mutex::new(tx);
+            transport.set_message_handler(Box::new(move |msg| {
+                let _ = tx.lock().unwrap().send(msg);
+            }));
+            rx
+        };
+        let filter_rx = received(&filter_peer);
+        let other_rx = received(&other_topic_peer);
+        let relay_rx = received(&relay_peer);
+
+        let id = test_message("Qmone")
+            .send_to_waku(&sender, pubsub_topic.clone(), topics[0].clone())
+            .unwrap();
+
+        let msg = filter_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(msg.message_id, id);
+        assert_eq!(msg.content_topic(), &topics[0]);
+        assert_eq!(
+            relay_rx
+                .recv_timeout(Duration::from_secs(1))
+                .unwrap()
+                .message_id,
+            id
+        );
+        assert!(other_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Unsubscribed content topics are no longer delivered
+        filter_peer.unsubscribe(&pubsub_topic, &topics).unwrap();
+        test_message("Qmone")
+            .send_to_waku(&sender, pubsub_topic, topics[0].clone())
+            .unwrap();
+        assert!(filter_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_send_report() {
+        let hub = LoopbackHub::new();
+        let sender = hub.transport();
+        let pubsub_topic = pubsub_topic(Some("testnet"));
+        let topics = build_content_topics("radio", 0, &["Qmone".to_string()]);
+        let report = test_message("Qmone")
+            .send_with_report(&sender, pubsub_topic.clone(), topics[0].clone())
+            .unwrap();
+        assert!(!report.is_sent());
+        assert!(report.into_result().is_err());
+
+        let subscriber = hub.transport();
+        subscriber.subscribe(&pubsub_topic, &topics).unwrap();
+        let _other_topic_peer = hub.transport();
+        let report = test_message("Qmone")
+            .send_with_report(&sender, pubsub_topic, topics[0].clone())
+            .unwrap();
+        assert!(report.is_sent());
+        assert_eq!(report.accepted, vec![subscriber.local_peer_id().unwrap()]);
+        assert!(report.failed.is_empty());
+    }
+
+    #[test]
+    fn test_peers_leave_hub() {
+        let hub = LoopbackHub::new();
+        let transport = hub.transport();
+        {
+            let peer = hub.transport();
+            assert_eq!(transport.peer_count().unwrap(), 1);
+            assert_eq!(
+                transport.peers().unwrap()[0].peer_id,
+                peer.local_peer_id().unwrap()
+            );
+        }
+        assert_eq!(hub.member_count(), 1);
+        assert!(transport
+            .publish(
+                &pubsub_topic(None),
+                &WakuMessage::new(
+                    vec![],
+                    build_content_topics("radio", 0, &["Qm".to_string()])[0].clone(),
+                    2,
+                    0,
+                    vec![],
+                    true
+                ),
+            )
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_agents_gossip() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let sender = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let receiver =
+            GraphcastAgent::with_transport(test_config(&wallet_key(2), subtopics), hub.transport())
+                .await
+                .unwrap();
+
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        receiver.transport.set_message_handler(Box::new(move |msg| {
+            let _ = tx.lock().unwrap().send(msg);
+        }));
+        send(&sender, "Qmloopback", LoopbackPayload::new("Ping")).await;
+
+        let received = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let decoded =
+            <GraphcastMessage<LoopbackPayload> as Message>::decode(received.waku_message.payload())
+                .unwrap();
+        assert_eq!(decoded.identifier, "Qmloopback");
+        assert_eq!(
+            decoded
+                .recover_sender_address(received.content_topic())
+                .unwrap(),
+            sender.graphcast_identity.graphcast_id
+        );
+    }
+}
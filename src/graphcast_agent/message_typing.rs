@@ -1,28 +1,60 @@
-use anyhow::anyhow;
 use async_graphql::SimpleObject;
 use chrono::Utc;
 use ethers::signers::{Signer, Wallet};
-use ethers_core::{k256::ecdsa::SigningKey, types::Signature};
+use ethers_core::{
+    abi::{encode, Token},
+    k256::ecdsa::SigningKey,
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712, Eip712Error},
+        Signature, I256, U256,
+    },
+    utils::keccak256,
+};
 use num_traits::ToPrimitive;
 use prost::Message;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
 use tracing::{debug, error, trace};
-use waku::{Running, WakuContentTopic, WakuMessage, WakuNodeHandle, WakuPeerData, WakuPubSubTopic};
+use waku::{WakuContentTopic, WakuMessage, WakuPubSubTopic};
 
 use crate::{
-    callbook::CallBook,
-    graphql::{
-        client_graph_node::query_graph_node_network_block_hash,
-        client_network::query_network_subgraph, QueryError,
-    },
-    networks::NetworkName,
-    Account, NetworkBlockError, NoncesMap,
+    callbook::CallBook, graphql::QueryError, networks::NetworkName, Account, NetworkBlockError,
+    NoncesMap,
 };
 
-use super::{waku_handling::WakuHandlingError, MSG_REPLAY_LIMIT};
+use super::{
+    seen_messages::SeenMessages,
+    transport::{GraphcastTransport, SendReport},
+    validation::{NonceVerdicts, ValidationPipeline},
+    waku_handling::WakuHandlingError,
+};
+
+/// Check that a message timestamp in seconds is within the replay window and at most
+/// `max_clock_skew` ahead of the local clock
+pub fn check_timestamp(
+    timestamp: i64,
+    replay_window: Duration,
+    max_clock_skew: Duration,
+) -> Result<(), ValidationError> {
+    //Can store for measuring overall Graphcast message latency
+    let message_age = Utc::now().timestamp() - timestamp;
+    let offset = Duration::from_secs(message_age.unsigned_abs());
+    if message_age < 0 && offset > max_clock_skew {
+        Err(ValidationError::FutureTimestamp {
+            ahead: offset,
+            tolerance: max_clock_skew,
+        })
+    } else if message_age > 0 && offset > replay_window {
+        Err(ValidationError::Expired {
+            age: offset,
+            window: replay_window,
+        })
+    } else {
+        Ok(())
+    }
+}
 
 /// Prepare sender:nonce to update
 fn prepare_nonces(
@@ -38,15 +70,22 @@ fn prepare_nonces(
 
 pub async fn get_indexer_stake(
     indexer_address: String,
-    network_subgraph: &str,
+    callbook: &CallBook,
 ) -> Result<f32, QueryError> {
-    Ok(
-        query_network_subgraph(network_subgraph.to_string(), indexer_address)
-            .await?
-            .indexer_stake(),
-    )
+    Ok(callbook
+        .network_subgraph(indexer_address)
+        .await?
+        .indexer_stake())
 }
 
+/// Signature over the radio payload only, the other message fields are not signed
+pub const LEGACY_SIGNATURE_VERSION: u32 = 0;
+/// Signature over the message envelope: the radio name and version of the content topic the
+/// message is sent on, the payload and every other message field
+pub const ENVELOPE_SIGNATURE_VERSION: u32 = 1;
+/// EIP-712 type of the message envelope, the payload is included by its struct hash
+const ENVELOPE_TYPE: &str = "GraphcastMessage(string radioName,uint32 radioVersion,string identifier,bytes32 payload,int64 nonce,string network,uint64 blockNumber,string blockHash,string graphAccount)";
+
 /// GraphcastMessage type casts over radio payload
 #[derive(Clone, Message, Serialize, Deserialize, SimpleObject)]
 pub struct GraphcastMessage<T>
@@ -79,9 +118,13 @@ where
     /// Graph account sender
     #[prost(string, tag = "7")]
     pub graph_account: String,
-    /// signature over radio payload
+    /// signature over the message envelope, or radio payload for legacy messages
     #[prost(string, tag = "8")]
     pub signature: String,
+    /// signing scheme of the signature, messages without it are legacy payload-only signatures
+    #[prost(uint32, tag = "9")]
+    pub signature_version: u32,
+    // Tag 10 carried an unsigned radio version, the version now comes from the content topic
 }
 
 impl<
@@ -93,7 +136,7 @@ impl<
             + async_graphql::OutputType,
     > GraphcastMessage<T>
 {
-    /// Create a graphcast message
+    /// Create a graphcast message, `signature` is expected over the message envelope
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         identifier: String,
@@ -115,6 +158,7 @@ impl<
                 block_hash,
                 graph_account,
                 signature,
+                signature_version: ENVELOPE_SIGNATURE_VERSION,
             })
         } else {
             Err(BuildMessageError::TypeCast(format!(
@@ -123,9 +167,13 @@ impl<
         }
     }
 
-    /// Signs the radio payload and construct graphcast message
+    /// Construct graphcast message and sign its envelope for `content_topic`, the message is
+    /// only valid on that content topic. Messages without payload act as pings, the signature
+    /// still covers every other field
+    #[allow(clippy::too_many_arguments)]
     pub async fn build(
         wallet: &Wallet<SigningKey>,
+        content_topic: &WakuContentTopic,
         identifier: String,
         payload: Option<T>,
         network: NetworkName,
@@ -133,140 +181,110 @@ impl<
         block_hash: String,
         graph_account: String,
     ) -> Result<Self, BuildMessageError> {
-        let payload = payload.ok_or(BuildMessageError::Payload)?;
-        let sig = wallet
-            .sign_typed_data(&payload)
-            .await
-            .map_err(|_| BuildMessageError::Signing)?;
-
-        GraphcastMessage::new(
+        let mut message = GraphcastMessage::new(
             identifier,
-            Some(payload),
+            payload,
             Utc::now().timestamp(),
             network,
             block_number,
             block_hash.to_string(),
             graph_account,
-            sig.to_string(),
-        )
+            String::new(),
+        )?;
+        let sig = wallet
+            .sign_typed_data(&message.envelope(content_topic))
+            .await
+            .map_err(|_| BuildMessageError::Signing)?;
+        message.signature = sig.to_string();
+        Ok(message)
+    }
+
+    /// EIP-712 typed data signed by the sender under `ENVELOPE_SIGNATURE_VERSION`, for a
+    /// message sent on `content_topic`
+    pub fn envelope<'a>(&'a self, content_topic: &'a WakuContentTopic) -> MessageEnvelope<'a, T> {
+        MessageEnvelope {
+            message: self,
+            content_topic,
+        }
     }
 
     /// Send Graphcast message to the Waku relay network
-    pub fn send_to_waku(
+    pub fn send_to_waku<N: GraphcastTransport>(
         &self,
-        node_handle: &WakuNodeHandle<Running>,
+        transport: &N,
         pubsub_topic: WakuPubSubTopic,
         content_topic: WakuContentTopic,
     ) -> Result<String, WakuHandlingError> {
-        let mut buff = Vec::new();
-        Message::encode(self, &mut buff).expect("Could not encode :(");
+        trace!(message = tracing::field::debug(&self), "Sending message");
+        transport.publish(&pubsub_topic, &self.waku_message(content_topic))
+    }
+
+    /// Send Graphcast message to the Waku network, reporting the peers that accepted it
+    pub fn send_with_report<N: GraphcastTransport>(
+        &self,
+        transport: &N,
+        pubsub_topic: WakuPubSubTopic,
+        content_topic: WakuContentTopic,
+    ) -> Result<SendReport, WakuHandlingError> {
+        trace!(message = tracing::field::debug(&self), "Sending message");
+        transport.publish_report(&pubsub_topic, &self.waku_message(content_topic))
+    }
 
-        let waku_message = WakuMessage::new(
-            buff,
+    fn waku_message(&self, content_topic: WakuContentTopic) -> WakuMessage {
+        WakuMessage::new(
+            self.encode_to_vec(),
             content_topic,
             2,
             Utc::now().timestamp() as usize,
             vec![],
             true,
-        );
-        trace!(message = tracing::field::debug(&self), "Sending message");
-
-        let sent_result: Vec<Result<String, WakuHandlingError>> = node_handle
-            .peers()
-            .map_err(WakuHandlingError::RetrievePeersError)
-            .unwrap_or_default()
-            .iter()
-            .filter(|&peer| {
-                // Filter out local peer_id to prevent self dial
-                peer.peer_id().as_str()
-                    != node_handle
-                        .peer_id()
-                        .expect("Failed to find local node's peer id")
-                        .as_str()
-            })
-            .map(|peer: &WakuPeerData| {
-                // Filter subscribe to all other peers
-                node_handle
-                    .lightpush_publish(
-                        &waku_message,
-                        Some(pubsub_topic.clone()),
-                        peer.peer_id().to_string(),
-                        None,
-                    )
-                    .map_err(|e| {
-                        debug!(
-                            error = tracing::field::debug(&e),
-                            "Failed to send message to Waku peer"
-                        );
-                        WakuHandlingError::PublishMessage(e)
-                    })
-            })
-            .collect();
-        // The message id is the same for all successful publish
-        sent_result
-            .into_iter()
-            .find_map(|res| res.ok())
-            .ok_or(WakuHandlingError::PublishMessage(
-                "Message could not be sent to any peers".to_string(),
-            ))
+        )
     }
 
-    /// Check message from valid sender: resolve indexer address and self stake
+    /// Check message from valid sender: resolve indexer address and self stake.
+    /// Lookups go through the CallBook and are served from its cache when possible
     pub async fn valid_sender(
         &self,
-        registry_subgraph: &str,
-        network_subgraph: &str,
+        content_topic: &WakuContentTopic,
+        callbook: &CallBook,
         local_sender_id: String,
         id_validation: IdentityValidation,
     ) -> Result<&Self, BuildMessageError> {
         match id_validation {
             IdentityValidation::NoCheck => (),
             IdentityValidation::ValidAddress => {
-                let _ = self.remote_account(local_sender_id)?;
+                let _ = self.remote_account(content_topic, local_sender_id)?;
             }
             IdentityValidation::GraphcastRegistered => {
-                let claimed_account = self.remote_account(local_sender_id)?;
+                let claimed_account = self.remote_account(content_topic, local_sender_id)?;
                 // Simply check if the message signer is registered at Graphcast Registry, make no validation on Graph Account field
-                let _ = claimed_account
-                    .account_from_registry(registry_subgraph)
+                let verified_account = claimed_account
+                    .account_from_registry(callbook)
                     .await
-                    .map(|verified_account| {
-                        if verified_account.account == claimed_account.account {Ok(verified_account)} else {
-                            Err(BuildMessageError::InvalidFields(anyhow!("Failed to match signature with a Graph account by `GraphcastRegistered` validation mechanism, drop message")))
-                        }
-                    })
                     .map_err(BuildMessageError::FieldDerivations)?;
+                claimed_account.matches(&verified_account, id_validation)?;
             }
             IdentityValidation::GraphNetworkAccount => {
-                let claimed_account = self.remote_account(local_sender_id)?;
+                let claimed_account = self.remote_account(content_topic, local_sender_id)?;
                 // allow any Graph account matched with message signer and the self-claimed graph account
-                let _ = claimed_account
-                    .account_from_network(network_subgraph)
+                let verified_account = claimed_account
+                    .account_from_network(callbook)
                     .await
-                    .map(|verified_account| {
-                        if verified_account.account == claimed_account.account {Ok(verified_account)} else {
-                            Err(BuildMessageError::InvalidFields(anyhow!("Failed to match signature with a Graph account by `GraphcastRegistered` validation mechanism, drop message")))
-                        }
-                    })
                     .map_err(BuildMessageError::FieldDerivations)?;
+                claimed_account.matches(&verified_account, id_validation)?;
             }
             IdentityValidation::RegisteredIndexer => {
-                let claimed_account = self.remote_account(local_sender_id)?;
+                let claimed_account = self.remote_account(content_topic, local_sender_id)?;
                 let verified_account = claimed_account
-                    .account_from_registry(registry_subgraph)
+                    .account_from_registry(callbook)
                     .await
                     .map_err(BuildMessageError::FieldDerivations)?;
-                if verified_account.account() != claimed_account.account() {
-                    return Err(BuildMessageError::InvalidFields(anyhow!("Failed to match signature with a Graph account by `RegisteredIndexer` validation mechanism, drop message")));
-                };
-                verified_account.valid_indexer(network_subgraph).await?;
+                claimed_account.matches(&verified_account, id_validation)?;
+                verified_account.valid_indexer(callbook).await?;
             }
             IdentityValidation::Indexer => {
-                let claimed_account = self.remote_account(local_sender_id)?;
-                let verified_account = match claimed_account
-                    .account_from_registry(registry_subgraph)
-                    .await
-                {
+                let claimed_account = self.remote_account(content_topic, local_sender_id)?;
+                let verified_account = match claimed_account.account_from_registry(callbook).await {
                     Ok(a) => a,
                     Err(e) => {
                         debug!(
@@ -275,43 +293,40 @@ impl<
                             "Signer is not registered at Graphcast Registry. Check Graph Network"
                         );
                         claimed_account
-                            .account_from_network(network_subgraph)
+                            .account_from_network(callbook)
                             .await
                             .map_err(BuildMessageError::FieldDerivations)?
                     }
                 };
-                if verified_account.account() != claimed_account.account() {
-                    return Err(BuildMessageError::InvalidFields(anyhow!(format!("Failed to match signature with a Graph account by `Indexer` validation mechanism, drop message. Verified account: {:#?}\n account claimed by message: {:#?}", verified_account, claimed_account))));
-                };
-                let _ = verified_account.valid_indexer(network_subgraph).await;
+                claimed_account.matches(&verified_account, id_validation)?;
+                let _ = verified_account.valid_indexer(callbook).await;
             }
         };
         Ok(self)
     }
 
-    /// Check timestamp: prevent past message replay
-    pub fn valid_time(&self) -> Result<&Self, BuildMessageError> {
-        //Can store for measuring overall Graphcast message latency
-        let message_age = Utc::now().timestamp() - self.nonce;
-        // 0 allow instant atomic messaging, use 1 to exclude them
-        if (0..MSG_REPLAY_LIMIT).contains(&message_age) {
-            Ok(self)
-        } else {
-            Err(BuildMessageError::InvalidFields(anyhow!(
-                "Message timestamp {} outside acceptable range {}, drop message",
-                message_age,
-                MSG_REPLAY_LIMIT
-            )))
-        }
+    /// Check timestamp: prevent past message replay, and tolerate senders with clocks
+    /// up to `max_clock_skew` ahead of the local clock
+    pub fn valid_time(
+        &self,
+        replay_window: Duration,
+        max_clock_skew: Duration,
+    ) -> Result<&Self, BuildMessageError> {
+        check_timestamp(self.nonce, replay_window, max_clock_skew)?;
+        Ok(self)
     }
 
-    pub fn remote_account(&self, local_sender_id: String) -> Result<Account, BuildMessageError> {
+    pub fn remote_account(
+        &self,
+        content_topic: &WakuContentTopic,
+        local_sender_id: String,
+    ) -> Result<Account, BuildMessageError> {
         debug!(
             "recovered sender address: {:#?}\nlocal sender id: {:#?}",
-            self.recover_sender_address(),
+            self.recover_sender_address(content_topic),
             local_sender_id
         );
-        let sender_address = self.recover_sender_address().and_then(|a| {
+        let sender_address = self.recover_sender_address(content_topic).and_then(|a| {
             debug!(
                 "recovered sender address: {:#?}\nlocal sender id: {:#?}",
                 a,
@@ -320,23 +335,18 @@ impl<
             if a != local_sender_id {
                 Ok(a)
             } else {
-                Err(BuildMessageError::InvalidFields(anyhow!(
-                    "Message is from self, drop message"
-                )))
+                Err(ValidationError::FromSelf { sender: a }.into())
             }
         })?;
         Ok(Account::new(sender_address, self.graph_account.clone()))
     }
 
     /// Check timestamp: prevent messages with incorrect graph node's block provider
-    pub async fn valid_hash(&self, graph_node_endpoint: &str) -> Result<&Self, BuildMessageError> {
-        let block_hash: String = query_graph_node_network_block_hash(
-            graph_node_endpoint.to_string(),
-            self.network.clone(),
-            self.block_number,
-        )
-        .await
-        .map_err(BuildMessageError::FieldDerivations)?;
+    pub async fn valid_hash(&self, callbook: &CallBook) -> Result<&Self, BuildMessageError> {
+        let block_hash: String = callbook
+            .block_hash(self.network.clone(), self.block_number)
+            .await
+            .map_err(BuildMessageError::FieldDerivations)?;
 
         trace!(
             network = tracing::field::debug(self.network.clone()),
@@ -348,37 +358,76 @@ impl<
         if self.block_hash == block_hash {
             Ok(self)
         } else {
-            Err(BuildMessageError::InvalidFields(anyhow!(
-                "Message hash ({}) differ from trusted provider response ({}), drop message",
-                self.block_hash,
-                block_hash
-            )))
+            Err(ValidationError::BlockHashMismatch {
+                identifier: self.identifier.clone(),
+                network: self.network.clone(),
+                block_number: self.block_number,
+                expected: block_hash,
+                actual: self.block_hash.clone(),
+            }
+            .into())
         }
     }
 
-    /// Recover sender address from Graphcast message radio payload
-    pub fn recover_sender_address(&self) -> Result<String, BuildMessageError> {
-        let signed_data = self
-            .payload
-            .as_ref()
-            .expect("No payload in the radio message, just a ping")
-            .encode_eip712()
-            .expect("Could not encode payload using EIP712");
+    /// Check the signing scheme, legacy signatures leave every field but the payload unsigned
+    pub fn valid_signature(&self, accept_legacy: bool) -> Result<&Self, BuildMessageError> {
+        match self.signature_version {
+            ENVELOPE_SIGNATURE_VERSION => Ok(self),
+            LEGACY_SIGNATURE_VERSION if accept_legacy => Ok(self),
+            LEGACY_SIGNATURE_VERSION => Err(ValidationError::LegacySignature {
+                identifier: self.identifier.clone(),
+            }
+            .into()),
+            version => Err(ValidationError::UnsupportedSignatureVersion { version }.into()),
+        }
+    }
+
+    /// Recover sender address from the signature over the message envelope for the content
+    /// topic the message arrived on, or over the radio payload for legacy messages. A message
+    /// relayed on another radio's or version's content topic recovers another address
+    pub fn recover_sender_address(
+        &self,
+        content_topic: &WakuContentTopic,
+    ) -> Result<String, BuildMessageError> {
+        let signed_data = match self.signature_version {
+            LEGACY_SIGNATURE_VERSION => self
+                .payload
+                .as_ref()
+                .ok_or_else(|| ValidationError::MissingPayload {
+                    identifier: self.identifier.clone(),
+                })?
+                .encode_eip712()
+                .map_err(|e| ValidationError::InvalidSignature {
+                    reason: e.to_string(),
+                })?,
+            ENVELOPE_SIGNATURE_VERSION => {
+                self.envelope(content_topic).encode_eip712().map_err(|e| {
+                    ValidationError::InvalidSignature {
+                        reason: e.to_string(),
+                    }
+                })?
+            }
+            version => return Err(ValidationError::UnsupportedSignatureVersion { version }.into()),
+        };
         match Signature::from_str(&self.signature).and_then(|sig| sig.recover(signed_data)) {
             Ok(addr) => {
                 debug!("{}", format!("{addr:#x}"));
                 Ok(format!("{addr:#x}"))
             }
-            Err(x) => Err(BuildMessageError::InvalidFields(x.into())),
+            Err(e) => Err(ValidationError::InvalidSignature {
+                reason: e.to_string(),
+            }
+            .into()),
         }
     }
 
     /// Check historic nonce: ensure message sequencing
     pub async fn valid_nonce(
         &self,
+        content_topic: &WakuContentTopic,
         nonces: &Arc<Mutex<NoncesMap>>,
     ) -> Result<&Self, BuildMessageError> {
-        let address = self.recover_sender_address()?;
+        let address = self.recover_sender_address(content_topic)?;
 
         let mut nonces = nonces.lock().await;
         let nonces_per_subgraph = nonces.get(&self.identifier);
@@ -396,10 +445,13 @@ impl<
                         );
 
                         if nonce > &self.nonce {
-                            Err(BuildMessageError::InvalidFields(anyhow!(
-                                    "Invalid nonce for subgraph {} and address {}! Received nonce - {} is smaller than currently saved one - {}, skipping message...",
-                                    self.identifier, address, self.nonce, nonce
-                                )))
+                            Err(ValidationError::NonceRegression {
+                                identifier: self.identifier.clone(),
+                                sender: address,
+                                nonce: self.nonce,
+                                saved_nonce: *nonce,
+                            }
+                            .into())
                         } else {
                             let updated_nonces =
                                 prepare_nonces(nonces_per_subgraph, address, self.nonce);
@@ -411,30 +463,164 @@ impl<
                         let updated_nonces =
                             prepare_nonces(nonces_per_subgraph, address.clone(), self.nonce);
                         nonces.insert(self.identifier.clone(), updated_nonces);
-                        Err(BuildMessageError::InvalidFields(anyhow!(
-                                    "No saved nonce for address {} on topic {}, saving this one and skipping message...",
-                                    address, self.identifier
-                                )))
+                        Err(ValidationError::FirstSeenSender {
+                            identifier: self.identifier.clone(),
+                            sender: address,
+                            nonce: self.nonce,
+                        }
+                        .into())
                     }
                 }
             }
             None => {
-                let updated_nonces = prepare_nonces(&HashMap::new(), address, self.nonce);
+                let updated_nonces = prepare_nonces(&HashMap::new(), address.clone(), self.nonce);
                 nonces.insert(self.identifier.clone(), updated_nonces);
-                Err(BuildMessageError::InvalidFields(anyhow!(
-                            "First time receiving message for subgraph {}. Saving sender and nonce, skipping message...",
-                            self.identifier
-                        )))
+                Err(ValidationError::FirstSeenTopic {
+                    identifier: self.identifier.clone(),
+                    sender: address,
+                    nonce: self.nonce,
+                }
+                .into())
             }
         }
     }
 }
 
-/// Check validity of the message:
-/// Sender check verifies sender's on-chain identity with Graphcast registry
-/// Time check verifies that message was from within the acceptable timestamp
-/// Block hash check verifies sender's access to valid Ethereum node provider and blocks
-/// Nonce check ensures the ordering of the messages and avoids past messages
+/// Message received on a content topic. The envelope signature is verified against the radio
+/// name and version of that topic, the radio version tells the schema of the payload
+#[derive(Clone, Debug)]
+pub struct ReceivedMessage<T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    pub message: GraphcastMessage<T>,
+    /// Content topic the message arrived on
+    pub content_topic: WakuContentTopic,
+}
+
+impl<T> ReceivedMessage<T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    pub fn new(message: GraphcastMessage<T>, content_topic: WakuContentTopic) -> Self {
+        ReceivedMessage {
+            message,
+            content_topic,
+        }
+    }
+
+    /// Radio version of the content topic the message arrived on
+    pub fn radio_version(&self) -> u32 {
+        self.content_topic.version as u32
+    }
+
+    /// Sender address recovered for the content topic the message arrived on
+    pub fn recover_sender_address(&self) -> Result<String, BuildMessageError> {
+        self.message.recover_sender_address(&self.content_topic)
+    }
+}
+
+/// EIP-712 typed data of a message envelope, binding the payload to the content topic's radio
+/// and the other message fields under the payload's domain
+pub struct MessageEnvelope<'a, T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    message: &'a GraphcastMessage<T>,
+    content_topic: &'a WakuContentTopic,
+}
+
+impl<T> Eip712 for MessageEnvelope<'_, T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    type Error = Eip712Error;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        match &self.message.payload {
+            Some(payload) => payload.domain(),
+            None => T::default().domain(),
+        }
+        .map_err(|e| Eip712Error::Message(e.to_string()))
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        Ok(keccak256(ENVELOPE_TYPE))
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        let message = self.message;
+        let payload_hash = match &message.payload {
+            Some(payload) => payload
+                .struct_hash()
+                .map_err(|e| Eip712Error::Message(e.to_string()))?,
+            None => [0; 32],
+        };
+        Ok(keccak256(encode(&[
+            Token::FixedBytes(Self::type_hash()?.to_vec()),
+            Token::FixedBytes(keccak256(self.content_topic.application_name.as_bytes()).to_vec()),
+            Token::Uint(U256::from(self.content_topic.version)),
+            Token::FixedBytes(keccak256(&message.identifier).to_vec()),
+            Token::FixedBytes(payload_hash.to_vec()),
+            Token::Int(I256::from(message.nonce).into_raw()),
+            Token::FixedBytes(keccak256(&message.network).to_vec()),
+            Token::Uint(U256::from(message.block_number)),
+            Token::FixedBytes(keccak256(&message.block_hash).to_vec()),
+            Token::FixedBytes(keccak256(&message.graph_account).to_vec()),
+        ])))
+    }
+}
+
+/// State used to validate inbound messages, cheap to clone into handler tasks and streams
+#[derive(Clone)]
+pub struct ValidationContext {
+    /// Nonces map for caching sender nonces in each subtopic
+    pub nonces: Arc<Mutex<NoncesMap>>,
+    /// Callbook that make query requests
+    pub callbook: CallBook,
+    /// Graphcast id of the local agent, used to drop messages from self
+    pub local_sender_id: String,
+    /// Sender identity validation mechanism
+    pub id_validation: IdentityValidation,
+    /// Age after which messages are dropped as replays
+    pub replay_window: Duration,
+    /// Tolerance for message timestamps ahead of the local clock
+    pub max_clock_skew: Duration,
+    /// Accept legacy signatures over the payload only
+    pub accept_legacy_signatures: bool,
+    /// Ids of messages sent from or received by the agent
+    pub seen_messages: Arc<SeenMessages>,
+    /// Nonce check outcomes shared by the subscribers receiving the same message
+    pub nonce_verdicts: Arc<NonceVerdicts>,
+    /// Check the ordering of messages against the sender nonces and record them, disabled
+    /// for history messages that predate the live ones
+    pub check_nonces: bool,
+}
+
+/// Check validity of the message through the default `ValidationPipeline`, for callers
+/// without a `ValidationContext`. Duplicates are not tracked and each call checks the nonce
+/// on its own
+#[allow(clippy::too_many_arguments)]
 pub async fn check_message_validity<
     T: Message
         + ethers::types::transaction::eip712::Eip712
@@ -443,24 +629,29 @@ pub async fn check_message_validity<
         + 'static
         + async_graphql::OutputType,
 >(
-    graphcast_message: GraphcastMessage<T>,
+    graphcast_message: ReceivedMessage<T>,
     nonces: &Arc<Mutex<NoncesMap>>,
     callbook: CallBook,
     local_sender_id: String,
     id_validation: IdentityValidation,
-) -> Result<GraphcastMessage<T>, BuildMessageError> {
-    graphcast_message
-        .valid_sender(
-            callbook.graphcast_registry(),
-            callbook.graph_network(),
-            local_sender_id,
-            id_validation,
-        )
-        .await?
-        .valid_time()?
-        .valid_hash(callbook.graph_node_status())
-        .await?
-        .valid_nonce(nonces)
+    replay_window: Duration,
+    max_clock_skew: Duration,
+    accept_legacy_signatures: bool,
+) -> Result<ReceivedMessage<T>, BuildMessageError> {
+    let context = ValidationContext {
+        nonces: nonces.clone(),
+        callbook,
+        local_sender_id,
+        id_validation,
+        replay_window,
+        max_clock_skew,
+        accept_legacy_signatures,
+        seen_messages: Arc::default(),
+        nonce_verdicts: Arc::default(),
+        check_nonces: true,
+    };
+    ValidationPipeline::default()
+        .validate(&graphcast_message, &context)
         .await?;
 
     trace!(
@@ -481,7 +672,7 @@ pub enum BuildMessageError {
     #[error("Could not decode message")]
     Decoding,
     #[error("Could not pass message validity checks: {0}")]
-    InvalidFields(anyhow::Error),
+    Validation(#[from] ValidationError),
     #[error("Could not build message with Network and BlockPointer: {0}")]
     Network(NetworkBlockError),
     #[error("Could not derive fields from the existing message: {0}")]
@@ -490,6 +681,66 @@ pub enum BuildMessageError {
     TypeCast(String),
 }
 
+/// Reason a message failed the validity checks, one variant per check
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+    #[error("Message {message_id} was already received or sent, drop message")]
+    Duplicate { message_id: String },
+    #[error("Message is from self ({sender}), drop message")]
+    FromSelf { sender: String },
+    #[error("Could not recover the sender from the message signature: {reason}")]
+    InvalidSignature { reason: String },
+    #[error("Message for {identifier} only signs the payload and legacy signatures are not accepted, drop message")]
+    LegacySignature { identifier: String },
+    #[error("Legacy message for {identifier} has no payload to verify the signature against, drop message")]
+    MissingPayload { identifier: String },
+    #[error("Unsupported signature version {version}, drop message")]
+    UnsupportedSignatureVersion { version: u32 },
+    #[error("Failed to match signature with a Graph account by `{id_validation:?}` validation mechanism, drop message. Verified account: {verified}, account claimed by message: {claimed}")]
+    AccountMismatch {
+        sender: String,
+        claimed: String,
+        verified: String,
+        id_validation: IdentityValidation,
+    },
+    #[error("Sender stake of {account} is less than the minimum requirement, drop message")]
+    InsufficientStake { account: String },
+    #[error("Message is {age:?} old, outside the replay window of {window:?}, drop message")]
+    Expired { age: Duration, window: Duration },
+    #[error("Message timestamp is {ahead:?} in the future, beyond the clock skew tolerance of {tolerance:?}, drop message")]
+    FutureTimestamp {
+        ahead: Duration,
+        tolerance: Duration,
+    },
+    #[error("Message hash ({actual}) for {network} block {block_number} differ from trusted provider response ({expected}), drop message")]
+    BlockHashMismatch {
+        identifier: String,
+        network: String,
+        block_number: u64,
+        expected: String,
+        actual: String,
+    },
+    #[error("Invalid nonce for subgraph {identifier} and address {sender}! Received nonce - {nonce} is smaller than currently saved one - {saved_nonce}, skipping message...")]
+    NonceRegression {
+        identifier: String,
+        sender: String,
+        nonce: i64,
+        saved_nonce: i64,
+    },
+    #[error("No saved nonce for address {sender} on topic {identifier}, saving this one and skipping message...")]
+    FirstSeenSender {
+        identifier: String,
+        sender: String,
+        nonce: i64,
+    },
+    #[error("First time receiving message for subgraph {identifier}. Saving sender and nonce, skipping message...")]
+    FirstSeenTopic {
+        identifier: String,
+        sender: String,
+        nonce: i64,
+    },
+}
+
 /// Identity validation for a Graphcast Message
 #[derive(Clone, Debug, Eq, PartialEq, Default, clap::ValueEnum, Serialize, Deserialize)]
 pub enum IdentityValidation {
@@ -511,6 +762,9 @@ pub enum IdentityValidation {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::graphcast_agent::{
+        waku_handling::build_content_topics, DEFAULT_MAX_CLOCK_SKEW, DEFAULT_REPLAY_WINDOW,
+    };
     use ethers_contract::EthAbiType;
     use ethers_core::rand::thread_rng;
     use ethers_core::types::transaction::eip712::Eip712;
@@ -546,6 +800,11 @@ mod tests {
         }
     }
 
+    /// Content topic of the test messages
+    fn topic() -> WakuContentTopic {
+        build_content_topics("ping-pong", 0, &[String::from("ping-pong-content-topic")]).remove(0)
+    }
+
     /// Create a random wallet
     fn dummy_wallet() -> Wallet<SigningKey> {
         Wallet::new(&mut thread_rng())
@@ -563,7 +822,8 @@ mod tests {
             block_number: 9221945,
             block_hash: String::from("a8ad1057882ae2bce4e49f811e651ccacd317f3c11918d3724d7e7a551c5fc39"),
             graph_account: String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
-            signature: String::from("2cd3fa305efd9c362bc71adee6e5a85c357a951af84c80667b8ddae23ac81c3821dac7d9c167e2776a9a56d8726b472312f40d9cc7461d1a6950d00e52d6e8521b")
+            signature: String::from("2cd3fa305efd9c362bc71adee6e5a85c357a951af84c80667b8ddae23ac81c3821dac7d9c167e2776a9a56d8726b472312f40d9cc7461d1a6950d00e52d6e8521b"),
+            signature_version: LEGACY_SIGNATURE_VERSION,
         }
     }
 
@@ -579,7 +839,8 @@ mod tests {
             block_number: 9249797,
             block_hash: String::from("af04663a968f48a0bd554e5f4842b4f3546868f5d87221ae194e01d36f640cd0"),
             graph_account: String::from("0x6121d1036d7016b125f019268b0406a4c15bb99d"),
-            signature: String::from("8006bd09f7ca6582ff1bbb9fd5bf657611625cd5a99f9d92088d9098c3391cd373454554bac8b76e13eb39b63be6d985761e76761c607bd2a87078259ab8928d1c")
+            signature: String::from("8006bd09f7ca6582ff1bbb9fd5bf657611625cd5a99f9d92088d9098c3391cd373454554bac8b76e13eb39b63be6d985761e76761c607bd2a87078259ab8928d1c"),
+            signature_version: LEGACY_SIGNATURE_VERSION,
         }
     }
 
@@ -595,7 +856,8 @@ mod tests {
             block_number: 9222109,
             block_hash: String::from("f1523bcac92c7e7d38142b089ec122d1607bc9a3b1b5d55df7cc11cbe10a3c48"),
             graph_account: String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
-            signature: String::from("52dcdd23418fa9c660be6c50f2c828c5b702ac46a452c21747260adc822a79663a3b7eddaa5139a0f5cd1206c8663faf272757d46f87bbb2bb6feedd1389601d1b")
+            signature: String::from("52dcdd23418fa9c660be6c50f2c828c5b702ac46a452c21747260adc822a79663a3b7eddaa5139a0f5cd1206c8663faf272757d46f87bbb2bb6feedd1389601d1b"),
+            signature_version: LEGACY_SIGNATURE_VERSION,
         }
     }
 
@@ -605,6 +867,11 @@ mod tests {
             "https://api.thegraph.com/subgraphs/name/hopeyen/gossip-registry-test";
         let network_subgraph =
             "https://api.thegraph.com/subgraphs/name/graphprotocol/graph-network-goerli";
+        let callbook = CallBook::new(
+            String::new(),
+            registry_subgraph.to_string(),
+            network_subgraph.to_string(),
+        );
         let network = NetworkName::from_string("goerli");
 
         let hash: String = "Qmtest".to_string();
@@ -616,6 +883,7 @@ mod tests {
         let wallet = dummy_wallet();
         let msg = GraphcastMessage::build(
             &wallet,
+            &topic(),
             hash,
             Some(payload),
             network,
@@ -629,14 +897,16 @@ mod tests {
         assert_eq!(msg.block_number, 0);
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::RegisteredIndexer
             )
             .await
             .is_err());
-        assert!(msg.valid_time().is_ok());
+        assert!(msg
+            .valid_time(DEFAULT_REPLAY_WINDOW, DEFAULT_MAX_CLOCK_SKEW)
+            .is_ok());
         // TODO: set up test with mocked graph node responses
         // assert!(msg.valid_hash("weeelp".to_string()).is_err());
         assert_eq!(
@@ -647,28 +917,125 @@ mod tests {
             content
         );
         assert_eq!(
-            msg.recover_sender_address()
+            msg.recover_sender_address(&topic())
                 .expect("Could not recover sender address"),
             format!("{:#x}", wallet.address())
         );
     }
 
+    #[test]
+    fn test_valid_time() {
+        let mut msg = graph_account_message();
+        let window = Duration::from_secs(3600);
+        let skew = Duration::from_secs(30);
+        assert!(matches!(
+            msg.valid_time(window, skew),
+            Err(BuildMessageError::Validation(ValidationError::Expired { window: w, .. })) if w == window
+        ));
+
+        let now = Utc::now().timestamp();
+        msg.nonce = now - 60;
+        assert!(msg.valid_time(window, skew).is_ok());
+        // Slightly fast clocks are tolerated
+        msg.nonce = now + 10;
+        assert!(msg.valid_time(window, skew).is_ok());
+        msg.nonce = now + 120;
+        assert!(matches!(
+            msg.valid_time(window, skew),
+            Err(BuildMessageError::Validation(ValidationError::FutureTimestamp { tolerance, .. })) if tolerance == skew
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_envelope_signature() {
+        let wallet = dummy_wallet();
+        let msg = GraphcastMessage::build(
+            &wallet,
+            &topic(),
+            "Qmtest".to_string(),
+            Some(RadioPayloadMessage::new(
+                "Qmtest".to_string(),
+                "0x0000".to_string(),
+            )),
+            NetworkName::from_string("goerli"),
+            0,
+            "0xblahh".to_string(),
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(msg.signature_version, ENVELOPE_SIGNATURE_VERSION);
+        let sender = format!("{:#x}", wallet.address());
+        assert_eq!(msg.recover_sender_address(&topic()).unwrap(), sender);
+        assert!(msg.valid_signature(false).is_ok());
+
+        // Rewriting any envelope field changes the recovered signer
+        let mut rewritten = msg.clone();
+        rewritten.block_hash = "0xother".to_string();
+        assert_ne!(rewritten.recover_sender_address(&topic()).unwrap(), sender);
+        let mut replayed = msg.clone();
+        replayed.nonce += 60;
+        assert_ne!(replayed.recover_sender_address(&topic()).unwrap(), sender);
+        // So does relaying the message on another radio version or radio
+        let other_version = WakuContentTopic {
+            version: 1,
+            ..topic()
+        };
+        assert_ne!(msg.recover_sender_address(&other_version).unwrap(), sender);
+        let other_radio = WakuContentTopic {
+            application_name: "other-radio".into(),
+            ..topic()
+        };
+        assert_ne!(msg.recover_sender_address(&other_radio).unwrap(), sender);
+
+        let mut unknown = msg;
+        unknown.signature_version = 7;
+        assert!(matches!(
+            unknown.recover_sender_address(&topic()),
+            Err(BuildMessageError::Validation(
+                ValidationError::UnsupportedSignatureVersion { version: 7 }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_signature() {
+        let msg = graph_account_message();
+        assert_eq!(msg.signature_version, LEGACY_SIGNATURE_VERSION);
+        assert!(msg.valid_signature(true).is_ok());
+        assert!(matches!(
+            msg.valid_signature(false),
+            Err(BuildMessageError::Validation(
+                ValidationError::LegacySignature { .. }
+            ))
+        ));
+        assert_eq!(
+            msg.recover_sender_address(&topic()).unwrap(),
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f")
+        );
+    }
+
     #[tokio::test]
     async fn test_validate_graph_network() {
         let registry_subgraph =
             "https://api.thegraph.com/subgraphs/name/hopeyen/gossip-registry-test";
         let network_subgraph =
             "https://api.thegraph.com/subgraphs/name/graphprotocol/graph-network-goerli";
+        let callbook = CallBook::new(
+            String::new(),
+            registry_subgraph.to_string(),
+            network_subgraph.to_string(),
+        );
         // graph_account_message is by a valid eth address that is not registered as a graphcast_id but is a graph account and valid indexer
         let msg = graph_account_message();
         assert_eq!(
-            msg.recover_sender_address().unwrap(),
+            msg.recover_sender_address(&topic()).unwrap(),
             String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f")
         );
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::NoCheck
             )
@@ -676,8 +1043,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::ValidAddress
             )
@@ -685,8 +1052,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::GraphNetworkAccount
             )
@@ -694,8 +1061,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::Indexer
             )
@@ -705,8 +1072,8 @@ mod tests {
         // Message should fail to validate if registry is required
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::GraphcastRegistered
             )
@@ -714,8 +1081,8 @@ mod tests {
             .is_err());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::RegisteredIndexer
             )
@@ -729,16 +1096,21 @@ mod tests {
             "https://thegraph.com/hosted-service/subgraph/hopeyen/graphcast-registry-goerli";
         let network_subgraph =
             "https://api.thegraph.com/subgraphs/name/graphprotocol/graph-network-goerli";
+        let callbook = CallBook::new(
+            String::new(),
+            registry_subgraph.to_string(),
+            network_subgraph.to_string(),
+        );
         // graph_account_message is by a valid eth address that is not registered as a graphcast_id but is a graph account and valid indexer
         let msg = indexer_message();
         assert_eq!(
-            msg.recover_sender_address().unwrap(),
+            msg.recover_sender_address(&topic()).unwrap(),
             String::from("0x6121d1036d7016b125f019268b0406a4c15bb99d")
         );
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::NoCheck
             )
@@ -746,8 +1118,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::ValidAddress
             )
@@ -755,8 +1127,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::GraphNetworkAccount
             )
@@ -764,8 +1136,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::Indexer
             )
@@ -775,8 +1147,8 @@ mod tests {
         // Message should fail to validate if registry is required
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::GraphcastRegistered
             )
@@ -784,8 +1156,8 @@ mod tests {
             .is_err());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::RegisteredIndexer
             )
@@ -799,12 +1171,17 @@ mod tests {
             "https://api.thegraph.com/subgraphs/name/hopeyen/gossip-registry-test";
         let network_subgraph =
             "https://api.thegraph.com/subgraphs/name/graphprotocol/graph-network-goerli";
+        let callbook = CallBook::new(
+            String::new(),
+            registry_subgraph.to_string(),
+            network_subgraph.to_string(),
+        );
         // graph_account_message is by a valid eth address that is not registered as a graphcast_id but is a graph account and valid indexer
         let msg = graphcast_id_message();
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::NoCheck
             )
@@ -812,8 +1189,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::ValidAddress
             )
@@ -822,8 +1199,8 @@ mod tests {
 
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::Indexer
             )
@@ -833,8 +1210,8 @@ mod tests {
         // Message should fail to validate if only Graph network account is checked
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::GraphNetworkAccount
             )
@@ -844,8 +1221,8 @@ mod tests {
         // Should success for checks at Graphcast registry
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::GraphcastRegistered
             )
@@ -853,8 +1230,8 @@ mod tests {
             .is_ok());
         assert!(msg
             .valid_sender(
-                registry_subgraph,
-                network_subgraph,
+                &topic(),
+                &callbook,
                 "".to_string(),
                 IdentityValidation::RegisteredIndexer
             )
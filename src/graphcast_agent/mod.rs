@@ -9,49 +9,118 @@
 //! Graphcast agent shall be able to construct, send, receive, validate, and attest
 //! Graphcast messages regardless of specific radio use cases
 //!
-use self::message_typing::{BuildMessageError, GraphcastMessage, IdentityValidation};
+pub use self::config::{ConfigLayer, GraphcastAgentConfigBuilder, StartupPolicy};
+use self::heartbeat::{heartbeat_topic, Heartbeat, PeerRoster, RosterPeer, HEARTBEAT_TOPIC};
+use self::history::{StoreConfig, TimeRange};
+use self::message_typing::{
+    BuildMessageError, GraphcastMessage, IdentityValidation, ReceivedMessage, ValidationContext,
+    ValidationError,
+};
+use self::node::{NodeInfo, NodeRole, NodeServices, WakuOptions};
+use self::nonce_store::{
+    flush_nonces, flush_nonces_periodically, prune_nonces, NonceStore, NonceStoreConfig,
+    NonceStoreError,
+};
+use self::peer_manager::{PeerActions, PeerManager, PeerManagerConfig, PeerStatus};
+use self::seen_messages::{SeenMessages, SeenMessagesMetrics};
+use self::subscription::{
+    MessageDispatcher, Route, SubscriptionMetrics, DEFAULT_SUBSCRIPTION_BUFFER,
+};
+use self::topic_sync::{allocated_subtopics, TopicChange, TopicSyncConfig, TOPIC_EVENTS_BUFFER};
+use self::transport::{GraphcastTransport, SendReport, TransportMessage};
+use self::validation::{NonceVerdicts, ValidationPipeline};
 use self::waku_handling::{
-    build_content_topics, filter_peer_subscriptions, handle_signal, network_check, pubsub_topic,
-    setup_node_handle, WakuHandlingError,
+    build_content_topics, handle_message, pubsub_topic, setup_node_handle,
+    versioned_content_topics, WakuHandlingError, WakuTransport,
 };
 use ethers::signers::WalletError;
+use futures::{pin_mut, stream, Stream, StreamExt};
 use prost::Message;
-use std::collections::{HashMap, HashSet};
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use tokio::sync::Mutex as AsyncMutex;
-use tracing::{debug, error, info, trace};
+use tokio::sync::{broadcast, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace, warn};
 use url::ParseError;
-use waku::{
-    waku_set_event_callback, Multiaddr, Running, Signal, WakuContentTopic, WakuNodeHandle,
-    WakuPubSubTopic,
-};
+use waku::{Multiaddr, WakuContentTopic, WakuPubSubTopic};
 
 use crate::Account;
 use crate::{
     build_wallet,
-    callbook::CallBook,
-    graphcast_agent::waku_handling::relay_subscribe,
-    graphql::{client_graph_node::get_indexing_statuses, QueryError},
+    callbook::{cache::CacheConfig, CallBook},
+    graphql::{client::HttpConfig, QueryError},
     networks::NetworkName,
     wallet_address, GraphcastIdentity, NoncesMap,
 };
 
+pub mod config;
+pub mod heartbeat;
+pub mod history;
+pub mod loopback;
 pub mod message_typing;
+pub mod node;
+pub mod nonce_store;
+pub mod peer_manager;
+pub mod seen_messages;
+pub mod subscription;
+pub mod topic_sync;
+pub mod transport;
+pub mod validation;
 pub mod waku_handling;
 
+#[cfg(test)]
+mod test_support;
+
 /// A constant defining a message expiration limit.
+#[deprecated(
+    note = "compared against seconds, so it allowed 41 days; use `DEFAULT_REPLAY_WINDOW` and `GraphcastAgentConfig::replay_window`"
+)]
 pub const MSG_REPLAY_LIMIT: i64 = 3_600_000;
+/// Default age after which a message is considered a replay and dropped
+pub const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(3600);
+/// Default tolerance for message timestamps ahead of the local clock
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+/// Initial delay between background set up checks under the deferred startup policy
+pub const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Maximum delay between background set up checks, the delay doubles after each failure
+pub const STARTUP_RETRY_MAX_INTERVAL: Duration = Duration::from_secs(300);
 
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
     #[error("Validate the input: {0}")]
     ValidateInput(String),
+    #[error("Invalid configurations: {}", display_errors(.0))]
+    Invalid(Vec<ConfigError>),
+    #[error(transparent)]
+    NonceStore(#[from] NonceStoreError),
     #[error("Unknown error: {0}")]
     Other(anyhow::Error),
 }
 
+impl ConfigError {
+    /// Combine the collected validation failures into a single error, if there is any
+    pub fn collect(mut errors: Vec<ConfigError>) -> Result<(), ConfigError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ConfigError::Invalid(errors)),
+        }
+    }
+}
+
+fn display_errors(errors: &[ConfigError]) -> String {
+    errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
 #[derive(Clone)]
 pub struct GraphcastAgentConfig {
     pub wallet_key: String,
@@ -71,6 +140,42 @@ pub struct GraphcastAgentConfig {
     pub discv5_enrs: Vec<String>,
     pub discv5_port: Option<u16>,
     pub id_validation: Option<IdentityValidation>,
+    pub startup_policy: StartupPolicy,
+    pub node_role: NodeRole,
+    pub node_services: NodeServices,
+    pub waku_options: WakuOptions,
+    pub cache_config: CacheConfig,
+    pub http_config: HttpConfig,
+    pub nonce_store: NonceStoreConfig,
+    pub nonce_flush_interval: Duration,
+    pub nonce_max_age: Duration,
+    pub seen_messages_capacity: usize,
+    pub seen_messages_ttl: Duration,
+    pub replay_window: Duration,
+    pub max_clock_skew: Duration,
+    pub accept_legacy_signatures: bool,
+    pub peer_roster_ttl: Duration,
+    pub radio_version: u32,
+    pub accepted_radio_versions: Vec<u32>,
+    pub store: StoreConfig,
+}
+
+/// Remote set up checks that have passed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AgentHealth {
+    /// Graphcast ID resolved to a Graph Account through the registry or network subgraph
+    pub identity: bool,
+    /// Graph Account is an indexer on the network subgraph
+    pub indexer: bool,
+    /// Graph node endpoint serves the indexing statuses query
+    pub graph_node: bool,
+}
+
+impl AgentHealth {
+    /// Whether the checks required by the strict startup policy have passed
+    pub fn is_healthy(&self) -> bool {
+        self.identity && self.graph_node
+    }
 }
 
 impl GraphcastAgentConfig {
@@ -94,38 +199,48 @@ impl GraphcastAgentConfig {
         discv5_port: Option<u16>,
         id_validation: Option<IdentityValidation>,
     ) -> Result<Self, GraphcastAgentError> {
-        let boot_node_addresses = convert_to_multiaddrs(&boot_node_addresses.unwrap_or(vec![]))
-            .map_err(|_| GraphcastAgentError::ConvertMultiaddrError)?;
-
-        let config = GraphcastAgentConfig {
-            wallet_key,
-            graph_account,
-            radio_name,
-            registry_subgraph,
-            network_subgraph,
-            graph_node_endpoint,
+        let mut builder = GraphcastAgentConfig::builder().layer(ConfigLayer {
+            wallet_key: Some(wallet_key),
+            graph_account: Some(graph_account),
+            radio_name: Some(radio_name),
+            registry_subgraph: Some(registry_subgraph),
+            network_subgraph: Some(network_subgraph),
+            graph_node_endpoint: Some(graph_node_endpoint),
             boot_node_addresses,
             graphcast_namespace,
-            subtopics: subtopics.unwrap_or(vec![]),
+            subtopics,
             waku_node_key,
             waku_host,
             waku_port,
             waku_addr,
-            // Extra handling here to make sure the default behavior is filter protocol enabled
-            filter_protocol: Some(filter_protocol.unwrap_or(true)),
-            discv5_enrs: discv5_enrs.unwrap_or_default(),
+            filter_protocol,
+            discv5_enrs,
             discv5_port,
-            id_validation,
-        };
-
-        if let Err(e) = config.validate_set_up().await {
-            panic!("Could not validate the supplied configurations: {e}")
+            ..Default::default()
+        });
+        if let Some(id_validation) = id_validation {
+            builder = builder.id_validation(id_validation);
         }
+        Ok(builder.build()?)
+    }
 
-        Ok(config)
+    /// CallBook querying the configured endpoints
+    pub fn callbook(&self) -> CallBook {
+        CallBook::new(
+            self.graph_node_endpoint.clone(),
+            self.registry_subgraph.clone(),
+            self.network_subgraph.clone(),
+        )
+        .with_cache_config(self.cache_config)
+        .with_http_config(self.http_config.clone())
     }
 
     pub async fn validate_set_up(&self) -> Result<(), ConfigError> {
+        self.check_set_up(&mut AgentHealth::default()).await
+    }
+
+    /// Run the remote set up checks, recording every check that passed in `health`
+    pub async fn check_set_up(&self, health: &mut AgentHealth) -> Result<(), ConfigError> {
         let wallet = build_wallet(&self.wallet_key).map_err(|e| {
             ConfigError::ValidateInput(format!(
                 "Invalid key to wallet, use private key or mnemonic: {e}"
@@ -133,7 +248,8 @@ impl GraphcastAgentConfig {
         })?;
         let graphcast_id = wallet_address(&wallet);
         let account = Account::new(graphcast_id, self.graph_account.clone());
-        let verified_account = match account.account_from_registry(&self.registry_subgraph).await {
+        let callbook = self.callbook();
+        let verified_account = match account.account_from_registry(&callbook).await {
             Ok(a) => a,
             Err(e) => {
                 debug!(
@@ -141,21 +257,44 @@ impl GraphcastAgentConfig {
                     "Signer is not registered at Graphcast Registry. Check Graph Network"
                 );
                 account
-                    .account_from_network(&self.network_subgraph)
+                    .account_from_network(&callbook)
                     .await
                     .map_err(|e| ConfigError::ValidateInput(e.to_string()))?
             }
         };
-        let _ = verified_account.valid_indexer(&self.network_subgraph).await;
-        let _ = get_indexing_statuses(self.graph_node_endpoint.to_string())
-            .await
-            .map_err(|e| {
-                ConfigError::ValidateInput(format!(
-                    "Graph node endpoint must be able to serve indexing statuses query: {e}"
-                ))
-            })?;
+        health.identity = true;
+        health.indexer = verified_account.valid_indexer(&callbook).await.is_ok();
+        let _ = callbook.indexing_statuses().await.map_err(|e| {
+            ConfigError::ValidateInput(format!(
+                "Graph node endpoint must be able to serve indexing statuses query: {e}"
+            ))
+        })?;
+        health.graph_node = true;
         Ok(())
     }
+
+    /// Retry the set up checks with exponential backoff until they all pass
+    async fn revalidate(self, health: Arc<RwLock<AgentHealth>>) {
+        let mut interval = STARTUP_RETRY_INTERVAL;
+        loop {
+            let mut checked = AgentHealth::default();
+            let result = self.check_set_up(&mut checked).await;
+            *health.write().unwrap() = checked;
+            match result {
+                Ok(()) => {
+                    info!("Deferred set up checks passed");
+                    return;
+                }
+                Err(e) => warn!(
+                    err = tracing::field::display(&e),
+                    retry_in = tracing::field::debug(&interval),
+                    "Deferred set up checks failed"
+                ),
+            }
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(STARTUP_RETRY_MAX_INTERVAL);
+        }
+    }
 }
 
 fn convert_to_multiaddrs(addresses: &[String]) -> Result<Vec<Multiaddr>, ConfigError> {
@@ -178,13 +317,18 @@ fn convert_to_multiaddrs(addresses: &[String]) -> Result<Vec<Multiaddr>, ConfigE
     Ok(multiaddrs)
 }
 
-/// A Graphcast agent representation
-pub struct GraphcastAgent {
+/// A Graphcast agent representation, generic over the transport carrying its messages
+pub struct GraphcastAgent<N: GraphcastTransport = WakuTransport> {
     /// GraphcastID's wallet, used to sign messages
     pub graphcast_identity: GraphcastIdentity,
-    pub node_handle: WakuNodeHandle<Running>,
+    /// Transport used to publish and receive messages, a Waku node by default
+    pub transport: N,
     /// Graphcast agent waku instance's radio application
     pub radio_name: String,
+    /// Version of the radio's content topics that messages are published on
+    pub radio_version: u32,
+    /// Other versions of the radio's content topics also subscribed to
+    pub accepted_radio_versions: Vec<u32>,
     /// Graphcast agent waku instance's pubsub topic
     pub pubsub_topic: WakuPubSubTopic,
     /// Graphcast agent waku instance's content topics
@@ -193,14 +337,59 @@ pub struct GraphcastAgent {
     pub nonces: Arc<AsyncMutex<NoncesMap>>,
     /// Callbook that make query requests
     pub callbook: CallBook,
-    /// TODO: remove after confirming that gossippub seen_ttl works
-    /// A set of message ids sent from the agent
-    pub old_message_ids: Arc<AsyncMutex<HashSet<String>>>,
+    /// Bounded cache of message ids sent or received by the agent
+    pub seen_messages: Arc<SeenMessages>,
     /// Sender identity validation mechanism used by the Graphcast agent
     pub id_validation: IdentityValidation,
+    /// Age after which messages are dropped as replays
+    pub replay_window: Duration,
+    /// Tolerance for message timestamps ahead of the local clock
+    pub max_clock_skew: Duration,
+    /// Accept legacy messages whose signature only covers the payload
+    pub accept_legacy_signatures: bool,
+    /// Peers that sent a heartbeat recently
+    roster: Arc<PeerRoster>,
+    /// Fan-out of inbound messages to the agent's subscribers
+    dispatcher: MessageDispatcher,
+    /// Nonce check outcomes shared by the subscribers receiving the same message
+    nonce_verdicts: Arc<NonceVerdicts>,
+    /// Validation pipelines set by the radio, keyed by the `TypeId` of the message payload
+    validation_pipelines: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+    /// Remote set up checks that have passed
+    health: Arc<RwLock<AgentHealth>>,
+    /// Background set up checks under the deferred startup policy
+    startup_checks: Option<JoinHandle<()>>,
+    /// Persistence of the nonces map
+    nonce_store: Arc<dyn NonceStore>,
+    /// Age after which a sender's nonce is pruned
+    nonce_max_age: Duration,
+    /// Periodic flush of the nonces to a persistent store
+    nonce_flush: Option<JoinHandle<()>>,
+    /// Content topics of the radios joined through `join_radio`
+    joined_topics: std::sync::Mutex<Vec<WakuContentTopic>>,
+    /// Content topics whose subscription failed, retried on the next update and by the
+    /// retry task
+    pending_topics: AsyncMutex<Vec<WakuContentTopic>>,
+    /// Publisher of the content topic changes
+    topic_events: broadcast::Sender<TopicChange>,
+    /// Background sync of the content topics with the indexer's allocations
+    topic_sync: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Background retry of the pending content topic subscriptions
+    topic_retry: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Connection history and publish outcomes of the transport's peers
+    peer_manager: PeerManager,
+    /// Background reconnection and peer limits enforcement
+    peer_maintenance: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Role and served protocols the Waku node was configured with
+    node_role: NodeRole,
+    node_services: NodeServices,
+    /// Address announced to peers
+    advertised_address: Option<Multiaddr>,
+    /// Set once the agent has released its resources
+    shut_down: bool,
 }
 
-impl GraphcastAgent {
+impl GraphcastAgent<WakuTransport> {
     /// Constructs a new Graphcast agent with the provided configuration.
     ///
     /// The `GraphcastAgentConfig` struct contains the following fields used to construct
@@ -223,10 +412,18 @@ impl GraphcastAgent {
     /// * `discv5_enrs:`: ENR records to bootstrap peer discovery through Discv5 mechanism
     /// * `discv5_port:`: The port for the Waku node to be discoverable by peers through Discv5.
     /// * `id_validation:`: Sender identity validation mechanism utilized for incoming messages.
+    /// * `radio_version`: Version of the radio's content topics that messages are published on.
+    /// * `accepted_radio_versions`: Other content topic versions subscribed to during a rollout.
+    /// * `node_role`: Light, relay or boot node, which decides the protocols the Waku node runs.
+    /// * `node_services`: Store and filter protocols served to other nodes.
+    /// * `waku_options`: Log level, keep alive, publishing and gossipsub settings of the Waku node.
+    /// * `store`: Store database and retention of the Waku node and the store peer queried for history.
     ///
     /// If the `waku_host`, `waku_port`, or `waku_addr` fields are not provided, the Waku node will
     /// use default values. Similarly, if the `graphcast_namespace` field is not provided, the agent
-    /// will default to using the IPFS hashes of the subgraphs that the indexer is allocating to.
+    /// uses the "testnet" namespace. Content topics are built from `subtopics`, radios can keep
+    /// them on the IPFS hashes of the subgraphs that the indexer is allocating to with
+    /// `start_topic_sync`.
     ///
     /// # Examples
     ///
@@ -247,33 +444,45 @@ impl GraphcastAgent {
     ///     discv5_enrs: vec![String::from("enr:-JK4QBcfVXu2YDeSKdjF2xE5EDM5f5E_1Akpkv_yw_byn1adESxDXVLVjapjDvS_ujx6MgWDu9hqO_Az_CbKLJ8azbMBgmlkgnY0gmlwhAVOUWOJc2VjcDI1NmsxoQOUZIqKLk5xkiH0RAFaMGrziGeGxypJ03kOod1-7Pum3oN0Y3CCfJyDdWRwgiMohXdha3UyDQ")],
     ///     discv5_port: Some(String::from("60000")),
     ///     id_validation: Some(IdentityValidation::NoCheck),
+    ///     startup_policy: StartupPolicy::Deferred,
+    ///     node_role: NodeRole::Light,
+    ///     node_services: NodeServices::default(),
+    ///     waku_options: WakuOptions::light(),
+    ///     cache_config: CacheConfig::default(),
+    ///     http_config: HttpConfig::default(),
+    ///     nonce_store: NonceStoreConfig::JsonFile(PathBuf::from("nonces.json")),
+    ///     nonce_flush_interval: DEFAULT_NONCE_FLUSH_INTERVAL,
+    ///     nonce_max_age: DEFAULT_NONCE_MAX_AGE,
+    ///     seen_messages_capacity: DEFAULT_SEEN_MESSAGES_CAPACITY,
+    ///     seen_messages_ttl: DEFAULT_SEEN_MESSAGES_TTL,
+    ///     replay_window: DEFAULT_REPLAY_WINDOW,
+    ///     max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+    ///     accept_legacy_signatures: false,
+    ///     peer_roster_ttl: DEFAULT_PEER_ROSTER_TTL,
+    ///     radio_version: 1,
+    ///     accepted_radio_versions: vec![0],
+    ///     store: StoreConfig::default(),
     /// };
     ///
     /// let agent = GraphcastAgent::new(config).await?;
     /// ```
 
-    pub async fn new(
-        GraphcastAgentConfig {
-            wallet_key,
-            graph_account,
-            radio_name,
-            registry_subgraph,
-            network_subgraph,
-            graph_node_endpoint,
+    pub async fn new(config: GraphcastAgentConfig) -> Result<Self, GraphcastAgentError> {
+        let GraphcastAgentConfig {
             boot_node_addresses,
             graphcast_namespace,
-            subtopics,
             waku_node_key,
             waku_host,
             waku_port,
             waku_addr,
-            filter_protocol,
             discv5_enrs,
             discv5_port,
-            id_validation,
-        }: GraphcastAgentConfig,
-    ) -> Result<GraphcastAgent, GraphcastAgentError> {
-        let graphcast_identity = GraphcastIdentity::new(wallet_key, graph_account.clone()).await?;
+            node_role,
+            node_services,
+            waku_options,
+            store,
+            ..
+        } = config.clone();
         let pubsub_topic: WakuPubSubTopic = pubsub_topic(graphcast_namespace.as_deref());
 
         let host = waku_host.as_deref();
@@ -290,42 +499,383 @@ impl GraphcastAgent {
             port,
             advertised_addr,
             node_key,
-            filter_protocol,
+            node_role,
+            &node_services,
             discv5_enrs,
             discv5_port,
+            &store,
+            &waku_options,
         )
         .map_err(GraphcastAgentError::WakuNodeError)?;
 
-        // Filter subscriptions only if provided subtopic
-        let content_topics = build_content_topics(&radio_name, 0, &subtopics);
-        if filter_protocol.is_some() && !filter_protocol.unwrap() {
-            debug!("Filter protocol disabled, subscribe to pubsub topic on the relay protocol");
-            relay_subscribe(&node_handle, &pubsub_topic)
-                .expect("Could not subscribe to the pubsub topic");
-        } else {
-            debug!("Filter protocol enabled, filter subscriptions with peers");
-            let _ = filter_peer_subscriptions(&node_handle, &pubsub_topic, &content_topics)
-                .expect("Could not connect and subscribe to the subtopics");
+        let mut transport = WakuTransport::new(node_handle, Some(!node_role.relay()));
+        if let Some(store_peer) = store.store_peer {
+            transport = transport.with_store_peer(store_peer);
+        }
+        let agent = GraphcastAgent::with_transport(config, transport).await?;
+        if let Ok(node_info) = agent.node_info() {
+            info!(
+                node = tracing::field::debug(&node_info),
+                "Started Waku node"
+            );
+        }
+        Ok(agent)
+    }
+}
+
+impl<N: GraphcastTransport> GraphcastAgent<N> {
+    /// Constructs a Graphcast agent on top of an already running transport.
+    ///
+    /// Waku node specific fields of the `GraphcastAgentConfig` are ignored, the transport
+    /// is expected to be set up and connected to its peers.
+    ///
+    /// Remote set up checks run according to the config's `startup_policy`: `Strict` fails
+    /// when a check fails, `Deferred` retries the checks in the background and `Offline`
+    /// skips them. Passed checks are reported by `health`.
+    ///
+    /// Fails when `seen_messages_ttl` is shorter than `replay_window`, as a replayed message
+    /// could then pass the timestamp check after its id was forgotten.
+    pub async fn with_transport(
+        config: GraphcastAgentConfig,
+        transport: N,
+    ) -> Result<GraphcastAgent<N>, GraphcastAgentError> {
+        if config.seen_messages_ttl < config.replay_window {
+            return Err(ConfigError::ValidateInput(format!(
+                "Seen messages TTL {:?} is shorter than the replay window {:?}",
+                config.seen_messages_ttl, config.replay_window
+            ))
+            .into());
+        }
+        let health = Arc::new(RwLock::new(AgentHealth::default()));
+        let mut startup_checks = None;
+        match config.startup_policy {
+            StartupPolicy::Strict => {
+                let mut checked = AgentHealth::default();
+                config.check_set_up(&mut checked).await?;
+                *health.write().unwrap() = checked;
+            }
+            StartupPolicy::Deferred => {
+                startup_checks = Some(tokio::spawn(config.clone().revalidate(health.clone())));
+            }
+            StartupPolicy::Offline => {
+                warn!("Offline startup policy, skip set up checks against remote endpoints");
+            }
+        }
+
+        let callbook = config.callbook();
+        let nonce_store = config.nonce_store.open()?;
+        let mut saved_nonces = nonce_store.load()?;
+        prune_nonces(&mut saved_nonces, config.nonce_max_age);
+        let nonces = Arc::new(AsyncMutex::new(saved_nonces));
+        let nonce_flush = config.nonce_store.is_persistent().then(|| {
+            tokio::spawn(flush_nonces_periodically(
+                nonce_store.clone(),
+                nonces.clone(),
+                config.nonce_flush_interval,
+                config.nonce_max_age,
+            ))
+        });
+        let nonce_max_age = config.nonce_max_age;
+        let seen_messages = Arc::new(SeenMessages::new(
+            config.seen_messages_capacity,
+            config.seen_messages_ttl,
+        ));
+
+        let GraphcastAgentConfig {
+            wallet_key,
+            graph_account,
+            radio_name,
+            graphcast_namespace,
+            subtopics,
+            id_validation,
+            replay_window,
+            max_clock_skew,
+            accept_legacy_signatures,
+            peer_roster_ttl,
+            radio_version,
+            mut accepted_radio_versions,
+            waku_addr,
+            node_role,
+            node_services,
+            ..
+        } = config;
+        if accept_legacy_signatures {
+            // Opt in during migrations only, see `accept_legacy_signatures`
+            warn!(
+                "Accepting legacy payload-only signatures, the nonce, block and network of legacy messages are not authenticated"
+            );
         }
+        accepted_radio_versions.retain(|&version| version != radio_version);
+        accepted_radio_versions.sort_unstable();
+        accepted_radio_versions.dedup();
+        let radio_versions: Vec<u32> = std::iter::once(radio_version)
+            .chain(accepted_radio_versions.iter().copied())
+            .collect();
+        let graphcast_identity = GraphcastIdentity::new(wallet_key, graph_account.clone()).await?;
+        let pubsub_topic: WakuPubSubTopic = pubsub_topic(graphcast_namespace.as_deref());
 
-        let callbook = CallBook::new(graph_node_endpoint, registry_subgraph, network_subgraph);
+        // Filter subscriptions only if provided subtopic, on every accepted radio version. A
+        // light node can start before any filter peer accepts them, the subscriptions are then
+        // kept pending and retried like failed content topic updates
+        let content_topics = build_content_topics(&radio_name, radio_version as usize, &subtopics);
+        let mut startup_topics = content_topics.clone();
+        startup_topics.push(heartbeat_topic(&radio_name, radio_version));
+        let pending_topics = match transport.subscribe(
+            &pubsub_topic,
+            &versioned_content_topics(&startup_topics, &radio_versions),
+        ) {
+            Ok(()) => vec![],
+            Err(e) => {
+                warn!(
+                    err = tracing::field::display(&e),
+                    "Could not subscribe to content topics, keep them pending"
+                );
+                startup_topics
+            }
+        };
+
+        // Drop messages already received or sent by self once, before the fan-out. Record
+        // heartbeats in the roster, and route every other inbound message through the
+        // dispatcher so multiple subscribers can consume them
+        let dispatcher = MessageDispatcher::default();
+        let roster = Arc::new(PeerRoster::new(peer_roster_ttl));
+        let inbound = dispatcher.clone();
+        let heartbeats = roster.clone();
+        let seen = seen_messages.clone();
+        let local_sender_id = graphcast_identity.graphcast_id.clone();
+        transport.set_message_handler(Box::new(move |message: TransportMessage| {
+            if seen.check_and_insert(&message.message_id) {
+                trace!(id = message.message_id, "Drop duplicate message");
+                return;
+            }
+            if message.content_topic().content_topic_name != HEARTBEAT_TOPIC {
+                return inbound.dispatch(message);
+            }
+            match Heartbeat::decode(message.waku_message.payload())
+                .map_err(|_| BuildMessageError::Decoding)
+                .and_then(|heartbeat| {
+                    heartbeats.record(&heartbeat, &local_sender_id, replay_window, max_clock_skew)
+                }) {
+                Ok(peer) => trace!(peer = tracing::field::debug(&peer), "Received heartbeat"),
+                Err(e) => trace!(err = tracing::field::display(&e), "Dropped heartbeat"),
+            }
+        }));
 
         Ok(GraphcastAgent {
             graphcast_identity,
             radio_name,
+            radio_version,
+            accepted_radio_versions,
             pubsub_topic,
             content_topics: Arc::new(AsyncMutex::new(content_topics)),
-            node_handle,
-            nonces: Arc::new(AsyncMutex::new(HashMap::new())),
+            transport,
+            nonces,
             callbook,
-            old_message_ids: Arc::new(AsyncMutex::new(HashSet::new())),
+            seen_messages,
             id_validation: id_validation.unwrap_or_default(),
+            replay_window,
+            max_clock_skew,
+            accept_legacy_signatures,
+            roster,
+            dispatcher,
+            nonce_verdicts: Arc::default(),
+            validation_pipelines: HashMap::new(),
+            health,
+            startup_checks,
+            nonce_store,
+            nonce_max_age,
+            nonce_flush,
+            joined_topics: std::sync::Mutex::new(vec![]),
+            pending_topics: AsyncMutex::new(pending_topics),
+            topic_events: broadcast::channel(TOPIC_EVENTS_BUFFER).0,
+            topic_sync: std::sync::Mutex::new(None),
+            topic_retry: std::sync::Mutex::new(None),
+            peer_manager: PeerManager::default(),
+            peer_maintenance: std::sync::Mutex::new(None),
+            node_role,
+            node_services,
+            advertised_address: waku_addr.and_then(|addr| Multiaddr::from_str(&addr).ok()),
+            shut_down: false,
         })
     }
 
+    /// Peer id, role, served protocols and addresses of the local node
+    pub fn node_info(&self) -> Result<NodeInfo, GraphcastAgentError> {
+        let node_info = || {
+            Ok(NodeInfo {
+                peer_id: self.transport.local_peer_id()?,
+                role: self.node_role,
+                services: self.node_services,
+                lightpush: self.node_role.lightpush(),
+                listen_addresses: self.transport.listen_addresses()?,
+                advertised_address: self.advertised_address.clone(),
+                enr: self.transport.enr()?,
+            })
+        };
+        node_info().map_err(GraphcastAgentError::WakuNodeError)
+    }
+
+    /// Peers that sent a heartbeat within the roster TTL
+    pub fn roster(&self) -> Vec<RosterPeer> {
+        self.roster.peers()
+    }
+
+    /// Number of live peers running each version of the radio, to decide when a rollout
+    /// can drop the previous version
+    pub fn peer_radio_versions(&self) -> BTreeMap<u32, usize> {
+        self.roster
+            .peers()
+            .iter()
+            .filter(|peer| peer.radio_name == self.radio_name)
+            .fold(BTreeMap::new(), |mut versions, peer| {
+                *versions.entry(peer.radio_version).or_default() += 1;
+                versions
+            })
+    }
+
+    /// The published radio version followed by the other accepted versions
+    fn radio_versions(&self) -> Vec<u32> {
+        std::iter::once(self.radio_version)
+            .chain(self.accepted_radio_versions.iter().copied())
+            .collect()
+    }
+
+    /// Announce liveness, radio version and subscribed topics to the radio's peers
+    pub async fn send_heartbeat(&self) -> Result<String, GraphcastAgentError> {
+        let subtopics = self
+            .content_topics
+            .lock()
+            .await
+            .iter()
+            .map(|topic| topic.content_topic_name.to_string())
+            .collect();
+        let id = Heartbeat::build(
+            &self.graphcast_identity.wallet,
+            self.radio_name.clone(),
+            self.radio_version,
+            subtopics,
+            self.graphcast_identity.graph_account.clone(),
+        )
+        .await
+        .map_err(GraphcastAgentError::MessageError)?
+        .send(
+            &self.transport,
+            &self.pubsub_topic,
+            heartbeat_topic(&self.radio_name, self.radio_version),
+        )
+        .map_err(GraphcastAgentError::WakuNodeError)?;
+        self.seen_messages.insert(&id);
+        trace!(id = id, "Sent heartbeat");
+        Ok(id)
+    }
+
+    /// Hit and miss counters of the seen-message cache
+    pub fn seen_messages_metrics(&self) -> SeenMessagesMetrics {
+        self.seen_messages.metrics()
+    }
+
+    /// Prune stale senders and save the nonces to the configured store
+    pub async fn flush_nonces(&self) -> Result<(), NonceStoreError> {
+        flush_nonces(&self.nonce_store, &self.nonces, self.nonce_max_age).await
+    }
+
+    /// Remote set up checks that have passed so far
+    pub fn health(&self) -> AgentHealth {
+        *self.health.read().unwrap()
+    }
+
+    /// Connection history, publish outcomes and score of the transport's peers, highest
+    /// scored first
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        match self.transport.peers() {
+            Ok(peers) => self.peer_manager.observe(&peers, Instant::now()),
+            Err(e) => trace!(
+                err = tracing::field::display(&e),
+                "Could not list the transport's peers"
+            ),
+        }
+        self.peer_manager.snapshot()
+    }
+
+    /// Opt in to managing the peer connections every `config.interval`: disconnected peers
+    /// are reconnected with exponential backoff and the lowest scored peers are dropped above
+    /// `config.max_peers`. Replaces a running peer manager and stops reconnecting every
+    /// disconnected peer before sending a message. The task only holds a weak reference and
+    /// ends once the agent is dropped or shut down
+    pub fn start_peer_manager(self: &Arc<Self>, config: PeerManagerConfig) {
+        self.peer_manager.set_config(config);
+        let agent: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config.interval);
+            loop {
+                interval.tick().await;
+                let agent = match agent.upgrade() {
+                    Some(agent) => agent,
+                    None => return,
+                };
+                if let Err(e) = agent.maintain_peers() {
+                    warn!(
+                        err = tracing::field::display(&e),
+                        "Could not maintain peer connections"
+                    );
+                }
+            }
+        });
+        if let Some(previous) = self.peer_maintenance.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    /// Run a round of peer management, returns the connections that were changed
+    pub fn maintain_peers(&self) -> Result<PeerActions, GraphcastAgentError> {
+        let now = Instant::now();
+        let peers = self
+            .transport
+            .peers()
+            .map_err(GraphcastAgentError::WakuNodeError)?;
+        self.peer_manager.observe(&peers, now);
+        let mut actions = self.peer_manager.plan(now);
+        actions
+            .disconnect
+            .retain(|peer_id| match self.transport.disconnect_peer(peer_id) {
+                Ok(()) => {
+                    self.peer_manager.record_disconnect(peer_id, now);
+                    true
+                }
+                Err(e) => {
+                    debug!(
+                        peer = peer_id,
+                        err = tracing::field::display(&e),
+                        "Could not disconnect from peer"
+                    );
+                    false
+                }
+            });
+        actions.reconnect.retain(|peer_id| {
+            let connected = match self.transport.connect_peer(peer_id) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!(
+                        peer = peer_id,
+                        err = tracing::field::display(&e),
+                        "Could not reconnect to peer"
+                    );
+                    false
+                }
+            };
+            self.peer_manager.record_reconnect(peer_id, connected, now);
+            connected
+        });
+        trace!(
+            actions = tracing::field::debug(&actions),
+            "Maintained peer connections"
+        );
+        Ok(actions)
+    }
+
     /// Get the number of peers excluding self
     pub fn number_of_peers(&self) -> usize {
-        self.node_handle.peer_count().unwrap_or({
+        self.transport.peer_count().unwrap_or({
             trace!("Could not count the number of peers");
             0
         })
@@ -371,9 +921,242 @@ impl GraphcastAgent {
         }
     }
 
-    /// Establish custom handler for incoming Waku messages
+    /// State needed to validate inbound messages outside of the agent
+    pub fn validation_context(&self) -> ValidationContext {
+        ValidationContext {
+            nonces: self.nonces.clone(),
+            callbook: self.callbook.clone(),
+            local_sender_id: self.graphcast_identity.graphcast_id.clone(),
+            id_validation: self.id_validation.clone(),
+            replay_window: self.replay_window,
+            max_clock_skew: self.max_clock_skew,
+            accept_legacy_signatures: self.accept_legacy_signatures,
+            seen_messages: self.seen_messages.clone(),
+            nonce_verdicts: self.nonce_verdicts.clone(),
+            check_nonces: true,
+        }
+    }
+
+    /// Validate messages with payload `T` through `pipeline` instead of the default checks.
+    /// Only applies to subscriptions and handlers created afterwards
+    pub fn set_validation_pipeline<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &mut self,
+        pipeline: ValidationPipeline<T>,
+    ) {
+        self.validation_pipelines
+            .insert(TypeId::of::<T>(), Arc::new(pipeline));
+    }
+
+    /// Validation pipeline for messages with payload `T`, the default checks unless one was set
+    pub fn validation_pipeline<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+    ) -> Arc<ValidationPipeline<T>> {
+        self.validation_pipelines
+            .get(&TypeId::of::<T>())
+            .and_then(|pipeline| pipeline.clone().downcast::<ValidationPipeline<T>>().ok())
+            .unwrap_or_default()
+    }
+
+    /// Stream of decoded and validated incoming messages of the agent's radio.
+    ///
+    /// The stream is fed from a bounded channel of `DEFAULT_SUBSCRIPTION_BUFFER` messages
+    /// and can be polled from any runtime. Messages arriving while the buffer is full are
+    /// dropped and counted in `subscription_metrics`.
+    pub fn subscribe<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+    ) -> impl Stream<Item = Result<ReceivedMessage<T>, WakuHandlingError>> + Send + 'static {
+        self.subscribe_with_capacity(DEFAULT_SUBSCRIPTION_BUFFER)
+    }
+
+    /// Stream of decoded and validated incoming messages of the agent's radio buffering up
+    /// to `capacity` messages
+    pub fn subscribe_with_capacity<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+        capacity: usize,
+    ) -> impl Stream<Item = Result<ReceivedMessage<T>, WakuHandlingError>> + Send + 'static {
+        self.subscribe_route_with_capacity(Route::Radio(self.radio_name.clone()), capacity)
+    }
+
+    /// Stream of incoming messages on `route`, decoded as `T` and validated through the
+    /// pipeline set for `T`. Radios sharing the agent subscribe to their own route with
+    /// their own payload type
+    pub fn subscribe_route<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+        route: Route,
+    ) -> impl Stream<Item = Result<ReceivedMessage<T>, WakuHandlingError>> + Send + 'static {
+        self.subscribe_route_with_capacity(route, DEFAULT_SUBSCRIPTION_BUFFER)
+    }
+
+    /// Stream of incoming messages on `route` buffering up to `capacity` messages
+    pub fn subscribe_route_with_capacity<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+        route: Route,
+        capacity: usize,
+    ) -> impl Stream<Item = Result<ReceivedMessage<T>, WakuHandlingError>> + Send + 'static {
+        let receiver = self.dispatcher.subscribe_route(route, capacity);
+        let context = self.validation_context();
+        let pipeline = self.validation_pipeline::<T>();
+        stream::unfold(
+            (receiver, context, pipeline),
+            |(mut receiver, context, pipeline)| async move {
+                let message = receiver.recv().await?;
+                let msg = handle_message::<T>(message, &context, &pipeline).await;
+                Some((msg, (receiver, context, pipeline)))
+            },
+        )
+    }
+
+    /// Messages published on `content_topics` within `time_range`, fetched from the transport's
+    /// message store, oldest first. History goes through the same decoding and validation
+    /// pipeline as live messages, apart from the live state: messages already received live
+    /// are returned again, sender nonces are neither checked nor recorded, and the replay
+    /// window covers the queried range. Duplicates within the history are reported as errors
+    pub async fn query_history<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+        content_topics: &[WakuContentTopic],
+        time_range: TimeRange,
+    ) -> Result<Vec<Result<ReceivedMessage<T>, WakuHandlingError>>, GraphcastAgentError> {
+        let history = self
+            .transport
+            .query_history(&self.pubsub_topic, content_topics, time_range)
+            .map_err(GraphcastAgentError::WakuNodeError)?;
+        debug!(
+            messages = history.len(),
+            time_range = tracing::field::debug(&time_range),
+            "Queried message history"
+        );
+        // History is validated apart from the live messages: messages already received live
+        // are not duplicates here, and ordering checks against the live nonces would reject
+        // every message older than the sender's latest one
+        let live = self.validation_context();
+        let context = ValidationContext {
+            seen_messages: Arc::new(SeenMessages::new(history.len(), Duration::MAX)),
+            nonce_verdicts: Arc::default(),
+            check_nonces: false,
+            replay_window: time_range
+                .start_age()
+                .map_or(Duration::MAX, |age| age.max(live.replay_window)),
+            ..live
+        };
+        let pipeline = self.validation_pipeline::<T>();
+        let mut messages = Vec::with_capacity(history.len());
+        for message in history {
+            if context.seen_messages.check_and_insert(&message.message_id) {
+                messages.push(Err(ValidationError::Duplicate {
+                    message_id: message.message_id,
+                }
+                .into()));
+                continue;
+            }
+            messages.push(handle_message::<T>(message, &context, &pipeline).await);
+        }
+        Ok(messages)
+    }
+
+    /// Subscribe the transport to the content topics of another radio, so the radio can
+    /// run on this agent through `subscribe_route` and `register_route_handler`
+    pub fn join_radio(
+        &self,
+        radio_name: &str,
+        radio_version: u32,
+        subtopics: &[String],
+    ) -> Result<Vec<WakuContentTopic>, GraphcastAgentError> {
+        let content_topics = build_content_topics(radio_name, radio_version as usize, subtopics);
+        self.transport
+            .subscribe(&self.pubsub_topic, &content_topics)
+            .map_err(GraphcastAgentError::WakuNodeError)?;
+        self.joined_topics
+            .lock()
+            .unwrap()
+            .extend(content_topics.iter().cloned());
+        debug!(
+            radio_name,
+            content_topics = tracing::field::debug(&content_topics),
+            "Joined radio"
+        );
+        Ok(content_topics)
+    }
+
+    /// Backpressure metrics of the message subscriptions
+    pub fn subscription_metrics(&self) -> SubscriptionMetrics {
+        self.dispatcher.metrics()
+    }
+
+    /// Establish custom handler for incoming messages of the agent's radio.
+    ///
+    /// The handler runs on a dedicated thread consuming a message subscription,
+    /// prefer `subscribe` to consume messages from the radio's own runtime.
     pub fn register_handler<
-        F: FnMut(Result<GraphcastMessage<T>, WakuHandlingError>)
+        F: FnMut(Result<ReceivedMessage<T>, WakuHandlingError>)
+            + std::marker::Sync
+            + std::marker::Send
+            + 'static,
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+        radio_handler_mutex: Arc<AsyncMutex<F>>,
+    ) -> Result<(), GraphcastAgentError> {
+        self.register_route_handler(Route::Radio(self.radio_name.clone()), radio_handler_mutex)
+    }
+
+    /// Establish custom handler for incoming messages on `route`, decoded as `T`.
+    /// Handlers on different routes run side by side
+    pub fn register_route_handler<
+        F: FnMut(Result<ReceivedMessage<T>, WakuHandlingError>)
             + std::marker::Sync
             + std::marker::Send
             + 'static,
@@ -384,23 +1167,25 @@ impl GraphcastAgent {
             + 'static
             + async_graphql::OutputType,
     >(
-        &'static self,
+        &self,
+        route: Route,
         radio_handler_mutex: Arc<AsyncMutex<F>>,
     ) -> Result<(), GraphcastAgentError> {
-        let handle_async = move |signal: Signal| {
-            let rt = Runtime::new().expect("Could not create Tokio runtime");
+        let messages = self.subscribe_route::<T>(route);
+        let rt = Runtime::new().map_err(|e| GraphcastAgentError::Other(e.into()))?;
+        thread::spawn(move || {
             rt.block_on(async {
-                let msg = handle_signal(signal, self).await;
-                let mut radio_handler = radio_handler_mutex.lock().await;
-                radio_handler(msg);
+                pin_mut!(messages);
+                while let Some(msg) = messages.next().await {
+                    let mut radio_handler = radio_handler_mutex.lock().await;
+                    radio_handler(msg);
+                }
             });
-        };
-        waku_set_event_callback(handle_async);
+        });
         Ok(())
     }
 
     /// For each topic, construct with custom write function and send
-    #[allow(unused_must_use)]
     pub async fn send_message<
         T: Message
             + ethers::types::transaction::eip712::Eip712
@@ -415,6 +1200,28 @@ impl GraphcastAgent {
         block_number: u64,
         payload: Option<T>,
     ) -> Result<String, GraphcastAgentError> {
+        self.send_message_with_report(identifier, network, block_number, payload)
+            .await?
+            .into_result()
+            .map_err(GraphcastAgentError::WakuNodeError)
+    }
+
+    /// Construct, sign and send a message, reporting the peers that accepted or failed it.
+    /// The report is returned even if no peer accepted the message
+    pub async fn send_message_with_report<
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    >(
+        &self,
+        identifier: String,
+        network: NetworkName,
+        block_number: u64,
+        payload: Option<T>,
+    ) -> Result<SendReport, GraphcastAgentError> {
         let content_topic = self.match_content_topic(identifier.clone()).await?;
         trace!(
             topic = tracing::field::debug(&content_topic),
@@ -426,12 +1233,15 @@ impl GraphcastAgent {
             .block_hash(network.to_string(), block_number)
             .await?;
 
-        // Check network before sending a message
-        network_check(&self.node_handle).map_err(GraphcastAgentError::WakuNodeError)?;
-        let mut ids = self.old_message_ids.lock().await;
-
-        GraphcastMessage::build(
+        // Check network before sending a message, unless the peer manager maintains it
+        if self.peer_maintenance.lock().unwrap().is_none() {
+            self.transport
+                .network_check()
+                .map_err(GraphcastAgentError::WakuNodeError)?;
+        }
+        let report = GraphcastMessage::build(
             &self.graphcast_identity.wallet,
+            &content_topic,
             identifier,
             payload,
             network,
@@ -441,43 +1251,301 @@ impl GraphcastAgent {
         )
         .await
         .map_err(GraphcastAgentError::MessageError)?
-        .send_to_waku(&self.node_handle, self.pubsub_topic.clone(), content_topic)
-        .map_err(GraphcastAgentError::WakuNodeError)
-        .map(|id| {
-            ids.insert(id.clone());
-            trace!(id = id, "Sent message");
-            id
-        })
+        .send_with_report(&self.transport, self.pubsub_topic.clone(), content_topic)
+        .map_err(GraphcastAgentError::WakuNodeError)?;
+        if let Ok(peers) = self.transport.peers() {
+            self.peer_manager.observe(&peers, Instant::now());
+        }
+        self.peer_manager.record_send(&report);
+        match &report.message_id {
+            Some(id) => {
+                self.seen_messages.insert(id);
+                trace!(
+                    id = id,
+                    accepted = tracing::field::debug(&report.accepted),
+                    failed = report.failed.len(),
+                    "Sent message"
+                );
+            }
+            None => warn!(
+                failed = tracing::field::debug(&report.failed),
+                "Message was not accepted by any peer"
+            ),
+        }
+        Ok(report)
+    }
+
+    /// Receive the changes of the agent's content topics, from `update_content_topics` or the
+    /// topic sync
+    pub fn topic_events(&self) -> broadcast::Receiver<TopicChange> {
+        self.topic_events.subscribe()
+    }
+
+    /// Set the content topics to the deployments the indexer allocates to, restricted to the
+    /// ones indexed by graph node if `require_indexing`. Returns the applied change
+    pub async fn sync_content_topics(
+        &self,
+        require_indexing: bool,
+    ) -> Result<TopicChange, GraphcastAgentError> {
+        let subtopics = allocated_subtopics(
+            &self.callbook,
+            &self.graphcast_identity.graph_account,
+            require_indexing,
+        )
+        .await?;
+        self.update_content_topics(subtopics).await
+    }
+
+    /// Opt in to keeping the content topics in sync with the indexer's allocations, polled
+    /// every `config.interval`, and to retrying failed subscriptions every
+    /// `config.retry_interval`. Replaces a running sync. The task only holds a weak reference
+    /// and ends once the agent is dropped or shut down
+    pub fn start_topic_sync(self: &Arc<Self>, config: TopicSyncConfig) {
+        self.start_topic_retry(config.retry_interval);
+        let agent: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config.interval);
+            loop {
+                interval.tick().await;
+                let agent = match agent.upgrade() {
+                    Some(agent) => agent,
+                    None => return,
+                };
+                if let Err(e) = agent.sync_content_topics(config.require_indexing).await {
+                    warn!(
+                        err = tracing::field::display(&e),
+                        "Could not sync content topics with allocations"
+                    );
+                }
+            }
+        });
+        if let Some(previous) = self.topic_sync.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    /// Opt in to retrying the pending content topic subscriptions every `interval` rather
+    /// than on the next update only, started by `start_topic_sync`. Replaces a running retry
+    /// task. The task only holds a weak reference and ends once the agent is dropped or shut
+    /// down
+    pub fn start_topic_retry(self: &Arc<Self>, interval: Duration) {
+        let agent: Weak<Self> = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let agent = match agent.upgrade() {
+                    Some(agent) => agent,
+                    None => return,
+                };
+                if let Err(e) = agent.retry_pending_topics().await {
+                    trace!(
+                        err = tracing::field::display(&e),
+                        "Could not subscribe to pending content topics"
+                    );
+                }
+            }
+        });
+        if let Some(previous) = self.topic_retry.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    /// Content topics whose subscription failed, see `retry_pending_topics`
+    pub async fn pending_content_topics(&self) -> Vec<WakuContentTopic> {
+        self.pending_topics.lock().await.clone()
+    }
+
+    /// Subscribe to the pending content topics again, returns the number of content topics
+    /// that left the pending list
+    pub async fn retry_pending_topics(&self) -> Result<usize, GraphcastAgentError> {
+        let mut pending = self.pending_topics.lock().await;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        self.transport
+            .subscribe(
+                &self.pubsub_topic,
+                &versioned_content_topics(&pending, &self.radio_versions()),
+            )
+            .map_err(GraphcastAgentError::WakuNodeError)?;
+        debug!(
+            topics = tracing::field::debug(&*pending),
+            "Subscribed to pending content topics"
+        );
+        Ok(std::mem::take(&mut *pending).len())
     }
 
-    // TODO: Could register the query function at intialization and call it within this fn
-    pub async fn update_content_topics(&self, subtopics: Vec<String>) {
-        // build content topics
-        let new_topics = build_content_topics(&self.radio_name, 0, &subtopics);
+    /// Replace the radio's subtopics, subscribing to the added content topics and
+    /// unsubscribing from the removed ones. Returns the change and publishes it to the
+    /// topic event subscribers.
+    ///
+    /// The content topics are updated even when the transport fails: added topics whose
+    /// subscription failed are kept pending and retried on the next update, including an
+    /// update with the same subtopics, and by `start_topic_retry`. The error is returned.
+    pub async fn update_content_topics(
+        &self,
+        subtopics: Vec<String>,
+    ) -> Result<TopicChange, GraphcastAgentError> {
+        let new_topics =
+            build_content_topics(&self.radio_name, self.radio_version as usize, &subtopics);
         let mut cur_topics = self.content_topics.lock().await;
+        let mut pending = self.pending_topics.lock().await;
 
-        // Check if an update to the content topic is necessary
-        if *cur_topics != new_topics {
+        let added: Vec<WakuContentTopic> = new_topics
+            .iter()
+            .filter(|&topic| !cur_topics.contains(topic))
+            .cloned()
+            .collect();
+        let removed: Vec<WakuContentTopic> = cur_topics
+            .iter()
+            .filter(|&topic| !new_topics.contains(topic))
+            .cloned()
+            .collect();
+        pending.retain(|topic| {
+            topic.content_topic_name == HEARTBEAT_TOPIC || new_topics.contains(topic)
+        });
+        let mut to_subscribe = pending.clone();
+        to_subscribe.extend(added.iter().cloned());
+        let change = TopicChange {
+            added: added
+                .iter()
+                .map(|topic| topic.content_topic_name.to_string())
+                .collect(),
+            removed: removed
+                .iter()
+                .map(|topic| topic.content_topic_name.to_string())
+                .collect(),
+        };
+        if !change.is_empty() {
             debug!(
-                new_topics = tracing::field::debug(&new_topics),
-                current_topics = tracing::field::debug(&*cur_topics),
-                "Updating to new set of content topics"
+                added = tracing::field::debug(&change.added),
+                removed = tracing::field::debug(&change.removed),
+                pending = pending.len(),
+                "Updating content topics"
+            );
+        }
+
+        let subscribed = if to_subscribe.is_empty() {
+            Ok(())
+        } else {
+            self.transport.subscribe(
+                &self.pubsub_topic,
+                &versioned_content_topics(&to_subscribe, &self.radio_versions()),
+            )
+        };
+        *pending = match &subscribed {
+            Ok(()) => vec![],
+            Err(e) => {
+                warn!(
+                    err = tracing::field::display(e),
+                    topics = tracing::field::debug(&to_subscribe),
+                    "Could not subscribe to content topics, keep them pending"
+                );
+                to_subscribe
+            }
+        };
+        let unsubscribed = if removed.is_empty() {
+            Ok(())
+        } else {
+            self.transport.unsubscribe(
+                &self.pubsub_topic,
+                &versioned_content_topics(&removed, &self.radio_versions()),
+            )
+        };
+        *cur_topics = new_topics;
+        drop(pending);
+        drop(cur_topics);
+
+        if !change.is_empty() {
+            // No receiver is not an error, radios opt in to the events
+            let _ = self.topic_events.send(change.clone());
+        }
+        subscribed
+            .and(unsubscribed)
+            .map_err(GraphcastAgentError::WakuNodeError)?;
+        Ok(change)
+    }
+
+    /// Stop the agent: unsubscribe from its content topics, flush the nonces, end the
+    /// message subscriptions and handlers, and stop the transport.
+    ///
+    /// Messages are published synchronously by `send_message`, so no send is pending once
+    /// `shutdown` holds the agent. Calling `shutdown` again is a no-op. Dropping the agent
+    /// without calling `shutdown` releases the same resources on a best effort basis, but
+    /// does not unsubscribe the content topics and may skip the final nonce flush. The agent is
+    /// released even when the final nonce flush fails, the flush error is returned afterwards.
+    pub async fn shutdown(&mut self) -> Result<(), GraphcastAgentError> {
+        if self.shut_down {
+            return Ok(());
+        }
+        // Stop receiving messages before tearing down the state they are validated against
+        let mut topics = self.content_topics.lock().await.clone();
+        topics.push(heartbeat_topic(&self.radio_name, self.radio_version));
+        let mut topics = versioned_content_topics(&topics, &self.radio_versions());
+        topics.extend(self.joined_topics.lock().unwrap().drain(..));
+        if let Err(e) = self.transport.unsubscribe(&self.pubsub_topic, &topics) {
+            warn!(
+                err = tracing::field::display(&e),
+                "Could not unsubscribe from content topics"
             );
+        }
 
-            // TODO: Uncomment after a release that contains this issue
-            // https://github.com/waku-org/go-waku/pull/536/files
-            // // Unsubscribe to the old content topics
-            // if !cur_topics.is_empty() {
-            //     unsubscribe_peer(&self.node_handle, &self.pubsub_topic, &cur_topics)
-            //         .expect("Connect and unsubscribe to subtopics");
-            // }
+        let flushed = match self.nonce_flush.take() {
+            Some(nonce_flush) => {
+                nonce_flush.abort();
+                self.flush_nonces().await
+            }
+            None => Ok(()),
+        };
+        self.release();
+        info!(radio_name = self.radio_name, "Graphcast agent shut down");
+        flushed.map_err(GraphcastAgentError::NonceStore)
+    }
 
-            // Subscribe to the new content topics
-            filter_peer_subscriptions(&self.node_handle, &self.pubsub_topic, &new_topics)
-                .expect("Connect and subscribe to subtopics");
-            *cur_topics = new_topics;
+    /// Synchronous part of the teardown, shared by `shutdown` and `Drop`
+    fn release(&mut self) {
+        self.shut_down = true;
+        if let Some(startup_checks) = self.startup_checks.take() {
+            startup_checks.abort();
+        }
+        if let Some(topic_sync) = self.topic_sync.lock().unwrap().take() {
+            topic_sync.abort();
+        }
+        if let Some(topic_retry) = self.topic_retry.lock().unwrap().take() {
+            topic_retry.abort();
+        }
+        if let Some(peer_maintenance) = self.peer_maintenance.lock().unwrap().take() {
+            peer_maintenance.abort();
+        }
+        self.transport.clear_message_handler();
+        // Ends the subscription streams and the handler threads consuming them
+        self.dispatcher.close();
+        if let Err(e) = self.transport.stop() {
+            warn!(
+                err = tracing::field::display(&e),
+                "Could not stop the transport"
+            );
         }
-        drop(cur_topics);
+    }
+}
+
+impl<N: GraphcastTransport> Drop for GraphcastAgent<N> {
+    fn drop(&mut self) {
+        if self.shut_down {
+            return;
+        }
+        if let Some(nonce_flush) = self.nonce_flush.take() {
+            nonce_flush.abort();
+            // Best effort final flush, skipped if a validation still holds the nonces
+            if let Ok(mut nonces) = self.nonces.try_lock() {
+                prune_nonces(&mut nonces, self.nonce_max_age);
+                if let Err(e) = self.nonce_store.save(&nonces) {
+                    warn!(err = tracing::field::display(&e), "Could not flush nonces");
+                }
+            }
+        }
+        self.release();
     }
 }
 
@@ -499,6 +1567,161 @@ pub enum GraphcastAgentError {
     WakuPortError,
     #[error("Failed to convert Multiaddr from String")]
     ConvertMultiaddrError,
+    #[error(transparent)]
+    NonceStore(#[from] NonceStoreError),
     #[error("Unknown error: {0}")]
     Other(anyhow::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loopback::LoopbackHub;
+    use test_support::{next, send, test_config, wallet_key, LoopbackPayload};
+    use validation::{SignatureValidator, TimeValidator};
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let peer = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let mut agent = GraphcastAgent::with_transport(
+            test_config(&wallet_key(2), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let messages = agent.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+        assert_eq!(hub.member_count(), 2);
+
+        agent.shutdown().await.unwrap();
+        agent.shutdown().await.unwrap();
+        assert_eq!(hub.member_count(), 1);
+        // Subscriptions end and the stopped transport cannot publish anymore
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), messages.next())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            agent.send_heartbeat().await,
+            Err(GraphcastAgentError::WakuNodeError(_))
+        ));
+        assert!(peer.send_heartbeat().await.is_err());
+        drop(agent);
+
+        // Agents can be created and torn down repeatedly, dropped agents release their transport
+        for i in 0..5 {
+            let agent = GraphcastAgent::with_transport(
+                test_config(&wallet_key(3 + i), subtopics.clone()),
+                hub.transport(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(hub.member_count(), 2);
+            drop(agent);
+        }
+        assert_eq!(hub.member_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_startup_policy() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+
+        let mut config = test_config(&wallet_key(1), subtopics.clone());
+        config.startup_policy = StartupPolicy::Strict;
+        assert!(GraphcastAgent::with_transport(config, hub.transport())
+            .await
+            .is_err());
+
+        let mut config = test_config(&wallet_key(1), subtopics.clone());
+        config.startup_policy = StartupPolicy::Deferred;
+        let agent = GraphcastAgent::with_transport(config, hub.transport())
+            .await
+            .unwrap();
+        assert!(!agent.health().is_healthy());
+
+        let agent =
+            GraphcastAgent::with_transport(test_config(&wallet_key(2), subtopics), hub.transport())
+                .await
+                .unwrap();
+        assert_eq!(agent.health(), AgentHealth::default());
+    }
+
+    #[tokio::test]
+    async fn test_seen_messages_ttl_shorter_than_replay_window() {
+        let hub = LoopbackHub::new();
+        let mut config = test_config(&wallet_key(1), vec![]);
+        config.seen_messages_ttl = config.replay_window / 2;
+        assert!(matches!(
+            GraphcastAgent::with_transport(config, hub.transport()).await,
+            Err(GraphcastAgentError::ConfigValidation(
+                ConfigError::ValidateInput(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_radio_version_rollout() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let versioned_config = |i: u8, radio_version: u32, accepted: Vec<u32>| {
+            let mut config = test_config(&wallet_key(i), subtopics.clone());
+            config.radio_version = radio_version;
+            config.accepted_radio_versions = accepted;
+            config
+        };
+        let mut agent =
+            GraphcastAgent::with_transport(versioned_config(1, 1, vec![0]), hub.transport())
+                .await
+                .unwrap();
+        agent.set_validation_pipeline::<LoopbackPayload>(
+            ValidationPipeline::empty()
+                .with(SignatureValidator)
+                .with(TimeValidator),
+        );
+        let messages = agent.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+        let old_peer =
+            GraphcastAgent::with_transport(versioned_config(2, 0, vec![]), hub.transport())
+                .await
+                .unwrap();
+        let new_peer =
+            GraphcastAgent::with_transport(versioned_config(3, 1, vec![]), hub.transport())
+                .await
+                .unwrap();
+
+        for (peer, content) in [(&old_peer, "Old"), (&new_peer, "New")] {
+            peer.send_heartbeat().await.unwrap();
+            send(peer, "Qmloopback", LoopbackPayload::new(content)).await;
+        }
+
+        // Messages of both versions are received, tagged with the version they arrived on
+        let old = next(&mut messages).await.unwrap();
+        assert_eq!(old.radio_version(), 0);
+        assert_eq!(old.message.payload.unwrap().content, "Old");
+        let new = next(&mut messages).await.unwrap();
+        assert_eq!(new.radio_version(), 1);
+        assert_eq!(new.message.payload.unwrap().content, "New");
+
+        let mut versions = agent.peer_radio_versions();
+        for _ in 0..100 {
+            if versions.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            versions = agent.peer_radio_versions();
+        }
+        assert_eq!(versions, BTreeMap::from([(0, 1), (1, 1)]));
+        // Peers on a single version only hear their own version
+        assert!(old_peer.peer_radio_versions().is_empty());
+    }
+}
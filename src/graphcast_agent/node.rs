@@ -0,0 +1,393 @@
+//! Roles of the agent's Waku node.
+//!
+//! Radios run light nodes by default: relay is disabled, messages are received through
+//! filter subscriptions and published through lightpush. Relay nodes gossip every message
+//! of the pubsub topic. Boot nodes are relay nodes run by network operators, that other
+//! nodes bootstrap from and that serve the store, filter and lightpush protocols to light
+//! nodes.
+//!
+//! `WakuOptions` holds the remaining Waku node settings, with presets for each role.
+//!
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use waku::{GossipSubParams, Multiaddr, WakuLogLevel, WakuPubSubTopic};
+
+use super::ConfigError;
+
+/// Part the agent's Waku node plays in the Graphcast network
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default, clap::ValueEnum, Serialize, Deserialize)]
+pub enum NodeRole {
+    // receive through filter subscriptions and publish through lightpush
+    #[default]
+    Light,
+    // gossip every message on the pubsub topic
+    Relay,
+    // relay node that peers bootstrap from and that serves light nodes
+    Boot,
+}
+
+impl NodeRole {
+    /// Role of configurations that only set the legacy `filter_protocol`
+    pub fn from_filter_protocol(filter_protocol: Option<bool>) -> Self {
+        match filter_protocol {
+            Some(false) => NodeRole::Relay,
+            _ => NodeRole::Light,
+        }
+    }
+
+    pub fn relay(&self) -> bool {
+        !matches!(self, NodeRole::Light)
+    }
+
+    /// Services served unless configured otherwise, boot nodes serve them all
+    pub fn default_services(&self) -> NodeServices {
+        match self {
+            NodeRole::Light | NodeRole::Relay => NodeServices::default(),
+            NodeRole::Boot => NodeServices {
+                store: true,
+                filter: true,
+            },
+        }
+    }
+
+    /// Whether the node relays the messages lightpushed by light nodes. The Waku bindings
+    /// serve lightpush on every relay node, so it follows the role and is not configurable
+    pub fn lightpush(&self) -> bool {
+        self.relay()
+    }
+}
+
+/// Protocols the node serves to other nodes, only relay and boot nodes can serve them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeServices {
+    /// Persist relayed messages and answer history queries, see `StoreConfig`
+    pub store: bool,
+    /// Push the messages matching light nodes' filter subscriptions
+    pub filter: bool,
+}
+
+impl NodeServices {
+    pub fn any(&self) -> bool {
+        self.store || self.filter
+    }
+
+    /// Services named in a config layer, such as `["store", "filter"]`
+    pub fn from_names(names: &[String]) -> Result<Self, ConfigError> {
+        let mut services = NodeServices::default();
+        for name in names {
+            match name.to_lowercase().as_str() {
+                "store" => services.store = true,
+                "filter" => services.filter = true,
+                _ => {
+                    return Err(ConfigError::ValidateInput(format!(
+                        "Unknown node service {name}, expected store or filter"
+                    )))
+                }
+            }
+        }
+        Ok(services)
+    }
+
+    /// Services require the relay protocol to receive the messages they serve
+    pub fn validate(&self, role: NodeRole) -> Result<(), ConfigError> {
+        if self.any() && !role.relay() {
+            return Err(ConfigError::ValidateInput(format!(
+                "Light nodes cannot serve the store or filter protocols: {self:?}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Log level of the Waku node
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default, clap::ValueEnum, Serialize, Deserialize)]
+pub enum NodeLogLevel {
+    Debug,
+    Info,
+    Warn,
+    #[default]
+    Error,
+    Fatal,
+    Panic,
+}
+
+impl From<NodeLogLevel> for WakuLogLevel {
+    fn from(level: NodeLogLevel) -> Self {
+        match level {
+            NodeLogLevel::Debug => WakuLogLevel::Debug,
+            NodeLogLevel::Info => WakuLogLevel::Info,
+            NodeLogLevel::Warn => WakuLogLevel::Warn,
+            NodeLogLevel::Error => WakuLogLevel::Error,
+            NodeLogLevel::Fatal => WakuLogLevel::Fatal,
+            NodeLogLevel::Panic => WakuLogLevel::Panic,
+        }
+    }
+}
+
+/// Waku node settings that are not covered by the other agent configurations
+#[derive(Clone, Debug)]
+pub struct WakuOptions {
+    pub log_level: NodeLogLevel,
+    /// Interval of the pings keeping peer connections alive, the Waku default when unset
+    pub keep_alive_interval: Option<Duration>,
+    /// Number of relay peers required before publishing a message
+    pub min_peers_to_publish: usize,
+    /// Pubsub topics relayed in addition to the Graphcast pubsub topic
+    pub relay_topics: Vec<WakuPubSubTopic>,
+    /// Enable Discv5 discovery, by default only when no bootstrap ENR is known
+    pub discv5: Option<bool>,
+    /// Gossipsub parameters of relay nodes
+    pub gossipsub: GossipSubParams,
+}
+
+impl Default for WakuOptions {
+    fn default() -> Self {
+        WakuOptions::light()
+    }
+}
+
+impl WakuOptions {
+    /// Light clients do not run gossipsub, the Waku defaults apply
+    pub fn light() -> Self {
+        WakuOptions {
+            log_level: NodeLogLevel::default(),
+            keep_alive_interval: None,
+            min_peers_to_publish: 0,
+            relay_topics: vec![],
+            discv5: None,
+            gossipsub: GossipSubParams::default(),
+        }
+    }
+
+    /// Relay nodes remember messages for half an hour and keep a long gossip history, so
+    /// peers reconnecting after a short outage catch up through gossip
+    pub fn relay() -> Self {
+        WakuOptions {
+            gossipsub: GossipSubParams {
+                seen_messages_ttl_seconds: Some(1800),
+                history_length: Some(100_000),
+                ..Default::default()
+            },
+            ..WakuOptions::light()
+        }
+    }
+
+    pub fn for_role(role: NodeRole) -> Self {
+        if role.relay() {
+            WakuOptions::relay()
+        } else {
+            WakuOptions::light()
+        }
+    }
+
+    /// Check the options against the role and the gossipsub mesh constraints
+    pub fn validate(&self, role: NodeRole) -> Result<(), ConfigError> {
+        let mut errors = vec![];
+        let mut invalid = |message: String| errors.push(ConfigError::ValidateInput(message));
+        if self
+            .keep_alive_interval
+            .map_or(false, |interval| interval.as_secs() == 0)
+        {
+            invalid(String::from(
+                "Keep alive interval must be at least a second",
+            ));
+        }
+        if !role.relay() && !self.relay_topics.is_empty() {
+            invalid(String::from(
+                "Light nodes cannot relay additional pubsub topics",
+            ));
+        }
+
+        let params = &self.gossipsub;
+        if let (Some(d_low), Some(d)) = (params.d_low, params.d) {
+            if d_low > d {
+                invalid(format!("Gossipsub d_low {d_low} is above d {d}"));
+            }
+        }
+        if let (Some(d), Some(d_high)) = (params.d, params.d_high) {
+            if d > d_high {
+                invalid(format!("Gossipsub d {d} is above d_high {d_high}"));
+            }
+        }
+        if let (Some(d_out), Some(d_low)) = (params.d_out, params.d_low) {
+            if d_out >= d_low {
+                invalid(format!(
+                    "Gossipsub d_out {d_out} must be below d_low {d_low}"
+                ));
+            }
+        }
+        if let (Some(history_gossip), Some(history_length)) =
+            (params.history_gossip, params.history_length)
+        {
+            if history_gossip > history_length {
+                invalid(format!(
+                    "Gossipsub history_gossip {history_gossip} is above history_length {history_length}"
+                ));
+            }
+        }
+        if params.history_length.map_or(false, |length| length <= 0) {
+            invalid(String::from("Gossipsub history_length must be positive"));
+        }
+        if params
+            .seen_messages_ttl_seconds
+            .map_or(false, |ttl| ttl <= 0)
+        {
+            invalid(String::from(
+                "Gossipsub seen_messages_ttl_seconds must be positive",
+            ));
+        }
+        ConfigError::collect(errors)
+    }
+}
+
+/// Summary of the running node, such as for operators to register boot nodes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub peer_id: String,
+    pub role: NodeRole,
+    pub services: NodeServices,
+    /// Relays the messages lightpushed by light nodes, see `NodeRole::lightpush`
+    pub lightpush: bool,
+    /// Addresses the node listens on, including its peer id
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Address announced to peers, when configured
+    pub advertised_address: Option<Multiaddr>,
+    /// Discv5 ENR of the node, when the transport exposes it
+    pub enr: Option<String>,
+}
+
+impl NodeInfo {
+    /// Addresses peers should dial, the advertised address first
+    pub fn dial_addresses(&self) -> Vec<Multiaddr> {
+        self.advertised_address
+            .iter()
+            .chain(self.listen_addresses.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        test_support::{test_config, wallet_key, FlakyTransport},
+        transport::GraphcastTransport,
+        GraphcastAgent,
+    };
+    use std::str::FromStr;
+
+    #[test]
+    fn test_node_role() {
+        assert_eq!(NodeRole::from_filter_protocol(None), NodeRole::Light);
+        assert_eq!(NodeRole::from_filter_protocol(Some(true)), NodeRole::Light);
+        assert_eq!(NodeRole::from_filter_protocol(Some(false)), NodeRole::Relay);
+        assert!(!NodeRole::Light.relay());
+        assert!(NodeRole::Boot.relay());
+        assert!(NodeRole::Boot.default_services().store);
+        assert!(!NodeRole::Relay.default_services().store);
+        assert!(!NodeRole::Light.default_services().any());
+        assert!(NodeRole::Relay.lightpush());
+        assert!(!NodeRole::Light.lightpush());
+    }
+
+    #[test]
+    fn test_services_require_relay() {
+        let services = NodeServices {
+            filter: true,
+            ..Default::default()
+        };
+        assert!(services.validate(NodeRole::Light).is_err());
+        assert!(services.validate(NodeRole::Relay).is_ok());
+        assert!(NodeServices::default().validate(NodeRole::Light).is_ok());
+        assert!(NodeServices::default().validate(NodeRole::Boot).is_ok());
+    }
+
+    #[test]
+    fn test_services_from_names() {
+        let names = vec![String::from("Store"), String::from("filter")];
+        assert_eq!(
+            NodeServices::from_names(&names).unwrap(),
+            NodeRole::Boot.default_services()
+        );
+        assert!(!NodeServices::from_names(&[]).unwrap().any());
+        assert!(NodeServices::from_names(&[String::from("lightpush")]).is_err());
+    }
+
+    #[test]
+    fn test_waku_options() {
+        assert!(WakuOptions::light().gossipsub.history_length.is_none());
+        assert_eq!(
+            WakuOptions::for_role(NodeRole::Boot)
+                .gossipsub
+                .seen_messages_ttl_seconds,
+            Some(1800)
+        );
+        assert!(WakuOptions::relay().validate(NodeRole::Relay).is_ok());
+
+        let mut options = WakuOptions::relay();
+        options.keep_alive_interval = Some(Duration::ZERO);
+        options.gossipsub.d = Some(4);
+        options.gossipsub.d_low = Some(6);
+        options.gossipsub.history_gossip = Some(200_000);
+        match options.validate(NodeRole::Relay) {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors.len(), 3),
+            _ => panic!("Expected invalid options"),
+        }
+
+        let options = WakuOptions {
+            relay_topics: vec![crate::graphcast_agent::waku_handling::pubsub_topic(None)],
+            ..WakuOptions::light()
+        };
+        assert!(options.validate(NodeRole::Light).is_err());
+        assert!(options.validate(NodeRole::Relay).is_ok());
+    }
+
+    #[test]
+    fn test_dial_addresses() {
+        let listen = Multiaddr::from_str("/ip4/0.0.0.0/tcp/60000").unwrap();
+        let advertised = Multiaddr::from_str("/ip4/1.2.3.4/tcp/60000").unwrap();
+        let info = NodeInfo {
+            peer_id: String::from("16Uiu2"),
+            role: NodeRole::Boot,
+            services: NodeRole::Boot.default_services(),
+            listen_addresses: vec![listen.clone()],
+            advertised_address: Some(advertised.clone()),
+            enr: None,
+        };
+        assert_eq!(info.dial_addresses(), vec![advertised, listen]);
+    }
+
+    #[tokio::test]
+    async fn test_node_info() {
+        let hub = LoopbackHub::new();
+        let mut config = test_config(&wallet_key(1), vec![]);
+        config.node_role = NodeRole::Boot;
+        config.node_services = NodeRole::Boot.default_services();
+        config.waku_addr = Some(String::from("/ip4/1.2.3.4/tcp/60000"));
+        let enr = String::from("enr:-JK4QBcfVXu2YDeSKdjF2xE5EDM5f5E_1Akpkv_yw_byn1adESxDXVLVjapjDvS_ujx6MgWDu9hqO_Az_CbKLJ8azbMBgmlkgnY0gmlwhAVOUWOJc2VjcDI1NmsxoQOUZIqKLk5xkiH0RAFaMGrziGeGxypJ03kOod1-7Pum3oN0Y3CCfJyDdWRwgiMohXdha3UyDQ");
+        let agent = GraphcastAgent::with_transport(
+            config,
+            FlakyTransport {
+                enr: Some(enr.clone()),
+                ..FlakyTransport::new(hub.transport())
+            },
+        )
+        .await
+        .unwrap();
+
+        let info = agent.node_info().unwrap();
+        assert_eq!(info.peer_id, agent.transport.local_peer_id().unwrap());
+        assert_eq!(info.role, NodeRole::Boot);
+        assert!(info.services.store);
+        assert!(info.lightpush);
+        assert!(info.listen_addresses.is_empty());
+        assert_eq!(
+            info.dial_addresses(),
+            vec!["/ip4/1.2.3.4/tcp/60000".parse().unwrap()]
+        );
+        assert_eq!(info.enr, Some(enr));
+        assert!(hub.transport().enr().unwrap().is_none());
+    }
+}
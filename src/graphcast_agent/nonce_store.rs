@@ -0,0 +1,402 @@
+//! Persistence of the sender nonces used for message sequencing.
+//!
+//! Without persisted nonces, `valid_nonce` treats every sender as new after a restart
+//! and drops its first message on every topic. A `NonceStore` loads the nonces when the
+//! agent starts, and the agent saves them periodically and when it is dropped.
+//!
+//! Nonces are message timestamps in seconds, so senders whose latest nonce is older
+//! than the configured max age are pruned before saving.
+//!
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{trace, warn};
+
+use crate::NoncesMap;
+
+/// Default interval between nonce flushes to the store
+pub const DEFAULT_NONCE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Default age after which a sender's nonce is pruned
+pub const DEFAULT_NONCE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Debug, thiserror::Error)]
+pub enum NonceStoreError {
+    #[error("Could not access the nonce store: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not (de)serialize nonces: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite nonce store error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Storage backend for sender nonces
+pub trait NonceStore: Send + Sync + 'static {
+    /// Load the persisted nonces, empty if nothing was saved yet
+    fn load(&self) -> Result<NoncesMap, NonceStoreError>;
+
+    /// Persist the nonces, replacing the previously saved ones
+    fn save(&self, nonces: &NoncesMap) -> Result<(), NonceStoreError>;
+}
+
+/// Prune the shared nonces and save a snapshot without blocking the async runtime
+pub async fn flush_nonces(
+    store: &Arc<dyn NonceStore>,
+    nonces: &Arc<AsyncMutex<NoncesMap>>,
+    max_age: Duration,
+) -> Result<(), NonceStoreError> {
+    let snapshot = {
+        let mut nonces = nonces.lock().await;
+        prune_nonces(&mut nonces, max_age);
+        nonces.clone()
+    };
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || store.save(&snapshot))
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+}
+
+/// Flush the nonces to the store every `interval`
+pub(crate) async fn flush_nonces_periodically(
+    store: Arc<dyn NonceStore>,
+    nonces: Arc<AsyncMutex<NoncesMap>>,
+    interval: Duration,
+    max_age: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes right away
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = flush_nonces(&store, &nonces, max_age).await {
+            warn!(err = tracing::field::display(&e), "Could not flush nonces");
+        }
+    }
+}
+
+/// Remove senders whose latest nonce is older than `max_age`, and topics left without senders
+pub fn prune_nonces(nonces: &mut NoncesMap, max_age: Duration) {
+    let oldest = Utc::now().timestamp() - max_age.as_secs() as i64;
+    nonces.retain(|_, senders| {
+        senders.retain(|_, nonce| *nonce >= oldest);
+        !senders.is_empty()
+    });
+}
+
+/// Keeps nonces in memory only, they are lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryNonceStore {
+    nonces: Mutex<NoncesMap>,
+}
+
+impl NonceStore for MemoryNonceStore {
+    fn load(&self) -> Result<NoncesMap, NonceStoreError> {
+        Ok(self.nonces.lock().unwrap().clone())
+    }
+
+    fn save(&self, nonces: &NoncesMap) -> Result<(), NonceStoreError> {
+        *self.nonces.lock().unwrap() = nonces.clone();
+        Ok(())
+    }
+}
+
+/// Saves nonces as a JSON document
+#[derive(Debug, Clone)]
+pub struct JsonFileNonceStore {
+    path: PathBuf,
+}
+
+impl JsonFileNonceStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        JsonFileNonceStore { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl NonceStore for JsonFileNonceStore {
+    fn load(&self) -> Result<NoncesMap, NonceStoreError> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(NoncesMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, nonces: &NoncesMap) -> Result<(), NonceStoreError> {
+        if let Some(dir) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so a crash never leaves a truncated store
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(nonces)?)?;
+        fs::rename(&tmp, &self.path)?;
+        trace!(path = tracing::field::debug(&self.path), "Saved nonces");
+        Ok(())
+    }
+}
+
+/// Saves nonces in a SQLite database
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteNonceStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteNonceStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, NonceStoreError> {
+        SqliteNonceStore::with_connection(rusqlite::Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> Result<Self, NonceStoreError> {
+        SqliteNonceStore::with_connection(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: rusqlite::Connection) -> Result<Self, NonceStoreError> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS nonces (
+                topic TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                nonce INTEGER NOT NULL,
+                PRIMARY KEY (topic, sender)
+            )",
+            [],
+        )?;
+        Ok(SqliteNonceStore {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl NonceStore for SqliteNonceStore {
+    fn load(&self) -> Result<NoncesMap, NonceStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT topic, sender, nonce FROM nonces")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        let mut nonces = NoncesMap::new();
+        for row in rows {
+            let (topic, sender, nonce) = row?;
+            nonces.entry(topic).or_default().insert(sender, nonce);
+        }
+        Ok(nonces)
+    }
+
+    fn save(&self, nonces: &NoncesMap) -> Result<(), NonceStoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM nonces", [])?;
+        {
+            let mut statement = transaction
+                .prepare("INSERT INTO nonces (topic, sender, nonce) VALUES (?1, ?2, ?3)")?;
+            for (topic, senders) in nonces {
+                for (sender, nonce) in senders {
+                    statement.execute(rusqlite::params![topic, sender, nonce])?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// Nonce store backend of the agent
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NonceStoreConfig {
+    /// Keep nonces in memory only
+    #[default]
+    Memory,
+    /// JSON document at the path
+    JsonFile(PathBuf),
+    /// SQLite database at the path
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+}
+
+impl NonceStoreConfig {
+    /// Open the configured store
+    pub fn open(&self) -> Result<Arc<dyn NonceStore>, NonceStoreError> {
+        Ok(match self {
+            NonceStoreConfig::Memory => Arc::new(MemoryNonceStore::default()),
+            NonceStoreConfig::JsonFile(path) => Arc::new(JsonFileNonceStore::new(path)),
+            #[cfg(feature = "sqlite")]
+            NonceStoreConfig::Sqlite(path) => Arc::new(SqliteNonceStore::open(path)?),
+        })
+    }
+
+    /// Whether nonces outlive the agent
+    pub fn is_persistent(&self) -> bool {
+        !matches!(self, NonceStoreConfig::Memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        test_support::{test_config, wallet_key, LoopbackPayload},
+        GraphcastAgent,
+    };
+    use futures::{pin_mut, StreamExt};
+    use std::collections::HashMap;
+
+    fn nonces() -> NoncesMap {
+        let now = Utc::now().timestamp();
+        let mut nonces = NoncesMap::new();
+        nonces.insert(
+            String::from("Qma"),
+            HashMap::from([
+                (String::from("0xfresh"), now),
+                (String::from("0xstale"), now - 7200),
+            ]),
+        );
+        nonces.insert(
+            String::from("Qmb"),
+            HashMap::from([(String::from("0xstale"), now - 7200)]),
+        );
+        nonces
+    }
+
+    fn round_trip(store: &dyn NonceStore) {
+        assert!(store.load().unwrap().is_empty());
+        let nonces = nonces();
+        store.save(&nonces).unwrap();
+        assert_eq!(store.load().unwrap(), nonces);
+        store.save(&NoncesMap::new()).unwrap();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_stale_senders() {
+        let mut nonces = nonces();
+        prune_nonces(&mut nonces, Duration::from_secs(3600));
+        assert_eq!(nonces.len(), 1);
+        assert_eq!(
+            nonces.get("Qma").unwrap().keys().collect::<Vec<_>>(),
+            vec!["0xfresh"]
+        );
+    }
+
+    #[test]
+    fn test_memory_store() {
+        round_trip(&MemoryNonceStore::default());
+    }
+
+    #[test]
+    fn test_json_file_store() {
+        let path = std::env::temp_dir()
+            .join(format!("graphcast-nonces-{}", std::process::id()))
+            .join("nonces.json");
+        let _ = fs::remove_file(&path);
+        round_trip(&JsonFileNonceStore::new(&path));
+
+        // A new store on the same file sees the saved nonces
+        let nonces = nonces();
+        NonceStoreConfig::JsonFile(path.clone())
+            .open()
+            .unwrap()
+            .save(&nonces)
+            .unwrap();
+        assert_eq!(JsonFileNonceStore::new(&path).load().unwrap(), nonces);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_flush_prunes_and_saves() {
+        let store: Arc<dyn NonceStore> = Arc::new(MemoryNonceStore::default());
+        let shared = Arc::new(AsyncMutex::new(nonces()));
+        flush_nonces(&store, &shared, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(store.load().unwrap(), *shared.lock().await);
+        assert_eq!(store.load().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store() {
+        round_trip(&SqliteNonceStore::in_memory().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_nonces_survive_restart() {
+        let hub = LoopbackHub::new();
+        let path = std::env::temp_dir().join(format!(
+            "graphcast-loopback-nonces-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let config = || {
+            let mut config = test_config(&wallet_key(1), vec!["Qmloopback".to_string()]);
+            config.nonce_store = NonceStoreConfig::JsonFile(path.clone());
+            config
+        };
+
+        let agent = GraphcastAgent::with_transport(config(), hub.transport())
+            .await
+            .unwrap();
+        let nonce = chrono::Utc::now().timestamp();
+        agent.nonces.lock().await.insert(
+            String::from("Qmloopback"),
+            HashMap::from([(String::from("0xsender"), nonce)]),
+        );
+        drop(agent);
+
+        let agent = GraphcastAgent::with_transport(config(), hub.transport())
+            .await
+            .unwrap();
+        assert_eq!(
+            agent.nonces.lock().await["Qmloopback"].get("0xsender"),
+            Some(&nonce)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flush_error() {
+        let hub = LoopbackHub::new();
+        let dir = std::env::temp_dir().join(format!(
+            "graphcast-loopback-shutdown-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_file(&dir);
+        let mut config = test_config(&wallet_key(1), vec!["Qmloopback".to_string()]);
+        config.nonce_store = NonceStoreConfig::JsonFile(dir.join("nonces.json"));
+        let mut agent = GraphcastAgent::with_transport(config, hub.transport())
+            .await
+            .unwrap();
+        let messages = agent.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+
+        // The store directory cannot be created, so the final flush fails
+        std::fs::write(&dir, b"").unwrap();
+        assert!(agent.shutdown().await.is_err());
+        // The agent is released all the same
+        assert_eq!(hub.member_count(), 0);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), messages.next())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(agent.shutdown().await.is_ok());
+        let _ = std::fs::remove_file(&dir);
+    }
+}
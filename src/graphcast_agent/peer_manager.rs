@@ -0,0 +1,570 @@
+//! Peer connection management.
+//!
+//! The peer manager keeps the connection history and publish outcomes of every peer the
+//! transport reported. A maintenance round, run in the background once the agent starts
+//! the peer manager, reconnects disconnected peers with exponential backoff and drops the
+//! lowest scored peers above the maximum peer count. Peers are scored on the share of
+//! messages they accepted. Peers that left the network, no longer listed by the transport
+//! and failing to reconnect, are forgotten.
+//!
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use waku::Multiaddr;
+
+use super::transport::{PeerInfo, SendReport};
+
+/// Default time between two maintenance rounds
+pub const DEFAULT_PEER_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Default number of connected peers under which the reconnection delay stops growing
+pub const DEFAULT_MIN_PEERS: usize = 1;
+/// Default number of connected peers above which the lowest scored peers are disconnected
+pub const DEFAULT_MAX_PEERS: usize = 50;
+/// Default delay before retrying a peer after its first failed reconnection
+pub const DEFAULT_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+/// Default upper bound of the reconnection delay, the delay doubles after each failure
+pub const DEFAULT_MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(600);
+/// Default number of consecutive failed reconnections after which a peer that is no longer
+/// listed by the transport is forgotten
+pub const DEFAULT_MAX_RECONNECT_FAILURES: u32 = 5;
+
+/// Settings of the peer manager
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerManagerConfig {
+    /// Time between two maintenance rounds
+    pub interval: Duration,
+    /// Below this number of connected peers, failed reconnections are retried after the
+    /// initial backoff
+    pub min_peers: usize,
+    /// Above this number of connected peers, the lowest scored peers are disconnected
+    pub max_peers: usize,
+    /// Delay before retrying a peer after its first failed reconnection
+    pub reconnect_backoff: Duration,
+    /// Upper bound of the reconnection delay
+    pub max_reconnect_backoff: Duration,
+    /// Peers no longer listed by the transport are forgotten after this many consecutive
+    /// failed reconnections, once disconnected for longer than the maximum backoff
+    pub max_reconnect_failures: u32,
+}
+
+impl Default for PeerManagerConfig {
+    fn default() -> Self {
+        PeerManagerConfig {
+            interval: DEFAULT_PEER_MAINTENANCE_INTERVAL,
+            min_peers: DEFAULT_MIN_PEERS,
+            max_peers: DEFAULT_MAX_PEERS,
+            reconnect_backoff: DEFAULT_RECONNECT_BACKOFF,
+            max_reconnect_backoff: DEFAULT_MAX_RECONNECT_BACKOFF,
+            max_reconnect_failures: DEFAULT_MAX_RECONNECT_FAILURES,
+        }
+    }
+}
+
+impl PeerManagerConfig {
+    /// Delay before the next reconnection after `failures` consecutive failures
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.reconnect_backoff
+            .saturating_mul(factor)
+            .min(self.max_reconnect_backoff)
+    }
+}
+
+/// Connection history and publish outcomes of a peer
+#[derive(Clone, Debug, Default)]
+struct PeerRecord {
+    addresses: Vec<Multiaddr>,
+    connected: bool,
+    connections: u32,
+    disconnections: u32,
+    last_connected: Option<Instant>,
+    last_disconnected: Option<Instant>,
+    reconnect_failures: u32,
+    next_reconnect: Option<Instant>,
+    accepted: u64,
+    failed: u64,
+}
+
+impl PeerRecord {
+    /// Share of accepted messages, starting from an even prior so new peers rank between
+    /// reliable and failing peers
+    fn score(&self) -> f64 {
+        (self.accepted as f64 + 1.0) / ((self.accepted + self.failed) as f64 + 2.0)
+    }
+
+    fn connect(&mut self, now: Instant) {
+        if !self.connected {
+            self.connections += 1;
+            self.last_connected = Some(now);
+        }
+        self.connected = true;
+        self.reconnect_failures = 0;
+        self.next_reconnect = None;
+    }
+
+    fn disconnect(&mut self, now: Instant) {
+        if self.connected {
+            self.disconnections += 1;
+            self.last_disconnected = Some(now);
+        }
+        self.connected = false;
+    }
+
+    fn reconnect_due(&self, now: Instant) -> bool {
+        !self.connected && self.next_reconnect.map_or(true, |next| next <= now)
+    }
+
+    /// Whether a peer the transport no longer lists has given up on reconnections
+    fn gone(&self, config: &PeerManagerConfig, now: Instant) -> bool {
+        !self.connected
+            && self.reconnect_failures >= config.max_reconnect_failures
+            && self.last_disconnected.map_or(true, |disconnected| {
+                now.saturating_duration_since(disconnected) >= config.max_reconnect_backoff
+            })
+    }
+}
+
+/// Snapshot of a peer tracked by the peer manager
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStatus {
+    pub peer_id: String,
+    pub addresses: Vec<Multiaddr>,
+    pub connected: bool,
+    /// Number of times the peer was seen connecting
+    pub connections: u32,
+    /// Number of times the peer was seen disconnecting
+    pub disconnections: u32,
+    pub last_connected: Option<Instant>,
+    pub last_disconnected: Option<Instant>,
+    /// Consecutive failed reconnections
+    pub reconnect_failures: u32,
+    /// Earliest time of the next reconnection, none if the peer can be retried right away
+    pub next_reconnect: Option<Instant>,
+    /// Messages the peer accepted
+    pub accepted: u64,
+    /// Messages the peer failed to accept
+    pub failed: u64,
+    /// Share of accepted messages with an even prior, between 0 and 1
+    pub score: f64,
+}
+
+impl PeerStatus {
+    /// Share of accepted messages, none if no message was published through the peer
+    pub fn success_rate(&self) -> Option<f64> {
+        let total = self.accepted + self.failed;
+        (total > 0).then(|| self.accepted as f64 / total as f64)
+    }
+}
+
+/// Connections to change in a maintenance round
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerActions {
+    /// Disconnected peers to reconnect, highest scored first
+    pub reconnect: Vec<String>,
+    /// Connected peers to drop, lowest scored first
+    pub disconnect: Vec<String>,
+}
+
+/// Tracks the peers of an agent's transport
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    config: RwLock<PeerManagerConfig>,
+    peers: Mutex<HashMap<String, PeerRecord>>,
+}
+
+impl PeerManager {
+    pub fn new(config: PeerManagerConfig) -> Self {
+        PeerManager {
+            config: RwLock::new(config),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> PeerManagerConfig {
+        *self.config.read().unwrap()
+    }
+
+    pub fn set_config(&self, config: PeerManagerConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Update the connection history with the peers listed by the transport. Peers that are
+    /// no longer listed are kept as disconnected until they fail to reconnect
+    /// `max_reconnect_failures` times in a row and stayed disconnected for the maximum backoff
+    pub fn observe(&self, peers: &[PeerInfo], now: Instant) {
+        let config = self.config();
+        let mut records = self.peers.lock().unwrap();
+        for peer in peers {
+            let record = records.entry(peer.peer_id.clone()).or_default();
+            if !peer.addresses.is_empty() {
+                record.addresses = peer.addresses.clone();
+            }
+            if peer.connected {
+                record.connect(now);
+            } else {
+                record.disconnect(now);
+            }
+        }
+        records.retain(|id, record| {
+            if peers.iter().any(|peer| &peer.peer_id == id) {
+                return true;
+            }
+            record.disconnect(now);
+            if record.gone(&config, now) {
+                debug!(
+                    peer = id,
+                    failures = record.reconnect_failures,
+                    "Forget peer that left"
+                );
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Count the peers that accepted or failed a published message
+    pub fn record_send(&self, report: &SendReport) {
+        let mut records = self.peers.lock().unwrap();
+        for peer_id in &report.accepted {
+            if let Some(record) = records.get_mut(peer_id) {
+                record.accepted += 1;
+            }
+        }
+        for failure in &report.failed {
+            if let Some(record) = records.get_mut(&failure.peer_id) {
+                record.failed += 1;
+            }
+        }
+    }
+
+    /// Record the outcome of a reconnection. Failures push the next attempt back
+    /// exponentially, unless the agent is short of peers
+    pub fn record_reconnect(&self, peer_id: &str, connected: bool, now: Instant) {
+        let config = self.config();
+        let mut records = self.peers.lock().unwrap();
+        let short_of_peers =
+            records.values().filter(|record| record.connected).count() < config.min_peers;
+        let record = match records.get_mut(peer_id) {
+            Some(record) => record,
+            None => return,
+        };
+        if connected {
+            record.connect(now);
+        } else {
+            record.reconnect_failures += 1;
+            let backoff = if short_of_peers {
+                config.reconnect_backoff
+            } else {
+                config.backoff(record.reconnect_failures)
+            };
+            record.next_reconnect = Some(now + backoff);
+            debug!(
+                peer = peer_id,
+                failures = record.reconnect_failures,
+                backoff = tracing::field::debug(backoff),
+                "Peer reconnection failed"
+            );
+        }
+    }
+
+    /// Record a peer dropped by the manager, it is not reconnected before the maximum backoff
+    pub fn record_disconnect(&self, peer_id: &str, now: Instant) {
+        let config = self.config();
+        if let Some(record) = self.peers.lock().unwrap().get_mut(peer_id) {
+            record.disconnect(now);
+            record.next_reconnect = Some(now + config.max_reconnect_backoff);
+        }
+    }
+
+    /// Peers to reconnect or drop to stay within the peer limits
+    pub fn plan(&self, now: Instant) -> PeerActions {
+        let config = self.config();
+        let records = self.peers.lock().unwrap();
+        let by_score = |a: &(&String, &PeerRecord), b: &(&String, &PeerRecord)| {
+            a.1.score()
+                .partial_cmp(&b.1.score())
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.0.cmp(b.0))
+        };
+
+        let mut connected: Vec<(&String, &PeerRecord)> = records
+            .iter()
+            .filter(|(_, record)| record.connected)
+            .collect();
+        if connected.len() < config.min_peers {
+            warn!(
+                connected = connected.len(),
+                min_peers = config.min_peers,
+                "Connected to fewer peers than the minimum"
+            );
+        }
+        connected.sort_by(by_score);
+        let disconnect = connected
+            .iter()
+            .take(connected.len().saturating_sub(config.max_peers))
+            .map(|(id, _)| id.to_string())
+            .collect();
+
+        let mut due: Vec<(&String, &PeerRecord)> = records
+            .iter()
+            .filter(|(_, record)| record.reconnect_due(now))
+            .collect();
+        due.sort_by(|a, b| by_score(b, a));
+        let reconnect = due
+            .iter()
+            .take(config.max_peers.saturating_sub(connected.len()))
+            .map(|(id, _)| id.to_string())
+            .collect();
+
+        PeerActions {
+            reconnect,
+            disconnect,
+        }
+    }
+
+    /// Tracked peers, highest scored first
+    pub fn snapshot(&self) -> Vec<PeerStatus> {
+        let mut peers: Vec<PeerStatus> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer_id, record)| PeerStatus {
+                peer_id: peer_id.clone(),
+                addresses: record.addresses.clone(),
+                connected: record.connected,
+                connections: record.connections,
+                disconnections: record.disconnections,
+                last_connected: record.last_connected,
+                last_disconnected: record.last_disconnected,
+                reconnect_failures: record.reconnect_failures,
+                next_reconnect: record.next_reconnect,
+                accepted: record.accepted,
+                failed: record.failed,
+                score: record.score(),
+            })
+            .collect();
+        peers.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.peer_id.cmp(&b.peer_id))
+        });
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        test_support::{test_config, wallet_key},
+        transport::GraphcastTransport,
+        GraphcastAgent,
+    };
+    use std::sync::Arc;
+
+    fn peer(id: &str, connected: bool) -> PeerInfo {
+        PeerInfo {
+            peer_id: id.to_string(),
+            addresses: vec![],
+            connected,
+        }
+    }
+
+    fn manager(min_peers: usize, max_peers: usize) -> PeerManager {
+        PeerManager::new(PeerManagerConfig {
+            min_peers,
+            max_peers,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = PeerManagerConfig {
+            reconnect_backoff: Duration::from_secs(5),
+            max_reconnect_backoff: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(config.backoff(1), Duration::from_secs(5));
+        assert_eq!(config.backoff(2), Duration::from_secs(10));
+        assert_eq!(config.backoff(4), Duration::from_secs(40));
+        assert_eq!(config.backoff(5), Duration::from_secs(60));
+        assert_eq!(config.backoff(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_connection_history() {
+        let manager = manager(0, 10);
+        let now = Instant::now();
+        manager.observe(&[peer("a", true), peer("b", false)], now);
+        manager.observe(&[peer("a", false), peer("b", true)], now);
+        manager.observe(&[peer("b", true)], now);
+        manager.observe(&[peer("a", true), peer("b", true)], now);
+
+        let peers = manager.snapshot();
+        let a = peers.iter().find(|p| p.peer_id == "a").unwrap();
+        assert_eq!((a.connections, a.disconnections), (2, 1));
+        let b = peers.iter().find(|p| p.peer_id == "b").unwrap();
+        assert_eq!((b.connections, b.disconnections), (1, 0));
+        assert!(b.connected);
+    }
+
+    #[test]
+    fn test_publish_scores() {
+        let manager = manager(0, 10);
+        manager.observe(&[peer("good", true), peer("bad", true)], Instant::now());
+        let mut report = SendReport::default();
+        report.fail(String::from("bad"), "timeout");
+        report.accept(String::from("good"), String::from("0xid"));
+        // Untracked peers, such as the local relay node, are ignored
+        report.accept(String::from("local"), String::from("0xid"));
+        manager.record_send(&report);
+
+        let peers = manager.snapshot();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].peer_id, "good");
+        assert_eq!(peers[0].success_rate(), Some(1.0));
+        assert_eq!(peers[1].peer_id, "bad");
+        assert_eq!(peers[1].success_rate(), Some(0.0));
+        assert!(peers[0].score > 0.5 && peers[1].score < 0.5);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let manager = manager(1, 10);
+        let now = Instant::now();
+        manager.observe(&[peer("a", true), peer("b", false)], now);
+        assert_eq!(manager.plan(now).reconnect, vec!["b"]);
+
+        manager.record_reconnect("b", false, now);
+        assert!(manager.plan(now).reconnect.is_empty());
+        let backoff = manager.config().reconnect_backoff;
+        assert_eq!(manager.plan(now + backoff).reconnect, vec!["b"]);
+
+        // Second failure doubles the delay
+        manager.record_reconnect("b", false, now);
+        assert!(manager.plan(now + backoff).reconnect.is_empty());
+        assert_eq!(manager.plan(now + backoff * 2).reconnect, vec!["b"]);
+
+        manager.record_reconnect("b", true, now);
+        let b = manager.snapshot().into_iter().find(|p| p.peer_id == "b");
+        assert_eq!(b.unwrap().reconnect_failures, 0);
+    }
+
+    #[test]
+    fn test_short_of_peers_skips_backoff_growth() {
+        let manager = manager(2, 10);
+        let now = Instant::now();
+        manager.observe(&[peer("a", false)], now);
+        let backoff = manager.config().reconnect_backoff;
+        for _ in 0..3 {
+            manager.record_reconnect("a", false, now);
+        }
+        assert_eq!(manager.plan(now + backoff).reconnect, vec!["a"]);
+    }
+
+    #[test]
+    fn test_forget_peers_that_left() {
+        let manager = manager(0, 10);
+        let config = manager.config();
+        let now = Instant::now();
+        manager.observe(&[peer("a", true), peer("b", true)], now);
+        manager.observe(&[peer("a", true)], now);
+        for _ in 0..config.max_reconnect_failures {
+            manager.record_reconnect("b", false, now);
+        }
+        // Kept until disconnected for the maximum backoff
+        manager.observe(&[peer("a", true)], now);
+        assert_eq!(manager.snapshot().len(), 2);
+        let later = now + config.max_reconnect_backoff;
+        manager.observe(&[peer("a", true)], later);
+        let peers = manager.snapshot();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, "a");
+        assert!(manager.plan(later).reconnect.is_empty());
+
+        // Peers still listed by the transport are kept whatever their failures
+        manager.observe(&[peer("a", true), peer("c", false)], later);
+        for _ in 0..config.max_reconnect_failures {
+            manager.record_reconnect("c", false, later);
+        }
+        let latest = later + config.max_reconnect_backoff;
+        manager.observe(&[peer("a", true), peer("c", false)], latest);
+        assert_eq!(manager.snapshot().len(), 2);
+    }
+
+    #[test]
+    fn test_peer_limits() {
+        let manager = manager(0, 2);
+        let now = Instant::now();
+        manager.observe(&[peer("a", true), peer("b", true), peer("c", true)], now);
+        let mut report = SendReport::default();
+        report.accept(String::from("a"), String::from("0xid"));
+        report.accept(String::from("b"), String::from("0xid"));
+        report.fail(String::from("c"), "timeout");
+        manager.record_send(&report);
+
+        let actions = manager.plan(now);
+        assert_eq!(actions.disconnect, vec!["c"]);
+        assert!(actions.reconnect.is_empty());
+
+        manager.record_disconnect("c", now);
+        let actions = manager.plan(now);
+        assert!(actions.disconnect.is_empty());
+        // Dropped peers wait for the maximum backoff and the limit leaves no room anyway
+        assert!(actions.reconnect.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peer_manager() {
+        let hub = LoopbackHub::new();
+        let agent = Arc::new(
+            GraphcastAgent::with_transport(test_config(&wallet_key(1), vec![]), hub.transport())
+                .await
+                .unwrap(),
+        );
+        let _peers = [hub.transport(), hub.transport(), hub.transport()];
+        assert!(agent.peers().iter().all(|peer| peer.connected));
+        assert_eq!(agent.peers().len(), 3);
+
+        // The first round runs right away, the next ones are left to `maintain_peers`
+        agent.start_peer_manager(PeerManagerConfig {
+            interval: Duration::from_secs(3600),
+            min_peers: 0,
+            max_peers: 2,
+            ..Default::default()
+        });
+        let connected = || agent.peers().iter().filter(|peer| peer.connected).count();
+        for _ in 0..100 {
+            if connected() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(connected(), 2);
+        let dropped = agent
+            .peers()
+            .into_iter()
+            .find(|peer| !peer.connected)
+            .unwrap();
+        assert_eq!(dropped.disconnections, 1);
+        assert!(dropped.next_reconnect.is_some());
+
+        // A peer dropped by the transport is reconnected on the next round
+        let peer_id = agent
+            .peers()
+            .into_iter()
+            .find(|peer| peer.connected)
+            .unwrap()
+            .peer_id;
+        agent.transport.disconnect_peer(&peer_id).unwrap();
+        let actions = agent.maintain_peers().unwrap();
+        assert_eq!(actions.reconnect, vec![peer_id]);
+        assert_eq!(connected(), 2);
+    }
+}
@@ -0,0 +1,218 @@
+//! Bounded cache of message ids already sent or received by the agent.
+//!
+//! Ids expire once they have not been seen for the TTL, and the least recently seen
+//! ids are evicted when the cache is full. The TTL defaults to the replay window and must
+//! not be shorter: older messages are rejected by the timestamp check anyway, while a
+//! message replayed within the window after its id expired would be accepted again.
+//!
+use std::collections::{HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant};
+
+use super::DEFAULT_REPLAY_WINDOW;
+
+/// Default number of message ids kept in the cache
+pub const DEFAULT_SEEN_MESSAGES_CAPACITY: usize = 100_000;
+/// Default time a message id is remembered after it was last seen
+pub const DEFAULT_SEEN_MESSAGES_TTL: Duration = DEFAULT_REPLAY_WINDOW;
+
+/// Point in time view of the seen-message cache counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeenMessagesMetrics {
+    /// Lookups of ids already in the cache, i.e. duplicate messages
+    pub hits: u64,
+    /// Lookups of new ids
+    pub misses: u64,
+    /// Ids evicted because the cache was full
+    pub evicted: u64,
+    /// Ids currently in the cache
+    pub entries: usize,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Id to (last seen, generation)
+    seen: HashMap<String, (Instant, u64)>,
+    /// Ids in the order they were last seen, with the generation they were recorded at.
+    /// Records with an outdated generation were refreshed later and are skipped.
+    order: VecDeque<(String, u64)>,
+    generation: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, id: &str, now: Instant) {
+        self.generation += 1;
+        self.seen.insert(id.to_string(), (now, self.generation));
+        self.order.push_back((id.to_string(), self.generation));
+        // Refreshed ids leave outdated records behind, drop them once they pile up
+        if self.order.len() > 2 * self.seen.len() + 16 {
+            let seen = &self.seen;
+            self.order
+                .retain(|(id, generation)| seen.get(id).map_or(false, |&(_, g)| g == *generation));
+        }
+    }
+
+    /// Drop the least recently seen id, returns when it was last seen
+    fn pop_oldest(&mut self) -> Option<Instant> {
+        while let Some((id, generation)) = self.order.pop_front() {
+            if let Some(&(seen_at, current)) = self.seen.get(&id) {
+                if current == generation {
+                    self.seen.remove(&id);
+                    return Some(seen_at);
+                }
+            }
+        }
+        None
+    }
+
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        while let Some((id, generation)) = self.order.front() {
+            match self.seen.get(id) {
+                Some(&(seen_at, current)) if current == *generation => {
+                    if now.duration_since(seen_at) < ttl {
+                        break;
+                    }
+                    self.seen.remove(id);
+                }
+                // Refreshed or removed since this record
+                _ => (),
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+/// LRU and TTL bounded set of message ids
+pub struct SeenMessages {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl Default for SeenMessages {
+    fn default() -> Self {
+        SeenMessages::new(DEFAULT_SEEN_MESSAGES_CAPACITY, DEFAULT_SEEN_MESSAGES_TTL)
+    }
+}
+
+impl SeenMessages {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        SeenMessages {
+            capacity: capacity.max(1),
+            ttl,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the id as seen, returns true if it was already seen within the TTL
+    pub fn check_and_insert(&self, id: &str) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.expire(now, self.ttl);
+        let seen = entries.seen.contains_key(id);
+        if seen {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            if entries.seen.len() >= self.capacity && entries.pop_oldest().is_some() {
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        entries.touch(id, now);
+        seen
+    }
+
+    /// Record the id as seen without counting a lookup, used for messages sent by the agent
+    pub fn insert(&self, id: &str) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.expire(now, self.ttl);
+        if !entries.seen.contains_key(id)
+            && entries.seen.len() >= self.capacity
+            && entries.pop_oldest().is_some()
+        {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.touch(id, now);
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.expire(Instant::now(), self.ttl);
+        entries.seen.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn metrics(&self) -> SeenMessagesMetrics {
+        SeenMessagesMetrics {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            entries: self.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_and_counters() {
+        let seen = SeenMessages::new(10, Duration::from_secs(60));
+        assert!(!seen.check_and_insert("a"));
+        assert!(seen.check_and_insert("a"));
+        seen.insert("b");
+        assert!(seen.check_and_insert("b"));
+        assert_eq!(
+            seen.metrics(),
+            SeenMessagesMetrics {
+                hits: 2,
+                misses: 1,
+                evicted: 0,
+                entries: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_least_recently_seen_evicted() {
+        let seen = SeenMessages::new(2, Duration::from_secs(60));
+        seen.check_and_insert("a");
+        seen.check_and_insert("b");
+        // Seeing "a" again makes "b" the least recently seen
+        seen.check_and_insert("a");
+        seen.check_and_insert("c");
+        assert!(seen.contains("a"));
+        assert!(!seen.contains("b"));
+        assert!(seen.contains("c"));
+        assert_eq!(seen.metrics().evicted, 1);
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn test_ids_expire() {
+        let seen = SeenMessages::new(10, Duration::from_millis(20));
+        seen.check_and_insert("a");
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!seen.contains("a"));
+        assert!(!seen.check_and_insert("a"));
+        assert_eq!(seen.len(), 1);
+    }
+}
@@ -0,0 +1,364 @@
+//! Fan-out of inbound transport messages to the agent's subscribers.
+//!
+//! Every subscriber owns a bounded channel and a `Route` selecting the messages it receives,
+//! so radios sharing one transport only get the messages published on their content topics.
+//! Duplicates are dropped before the fan-out, every subscriber on a message's route gets a
+//! copy of it.
+//! When a subscriber falls behind and its channel is full, new messages for it are dropped
+//! instead of blocking the transport callback, and the drop is recorded in the subscription
+//! metrics.
+//!
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{trace, warn};
+use waku::WakuContentTopic;
+
+use super::transport::TransportMessage;
+
+/// Default number of messages buffered for each subscriber
+pub const DEFAULT_SUBSCRIPTION_BUFFER: usize = 1024;
+
+/// Selection of the inbound messages delivered to a subscriber
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    /// Every inbound message
+    All,
+    /// Messages on any content topic of the radio
+    Radio(String),
+    /// Messages on a single content topic
+    ContentTopic(WakuContentTopic),
+}
+
+impl Route {
+    pub fn matches(&self, content_topic: &WakuContentTopic) -> bool {
+        match self {
+            Route::All => true,
+            Route::Radio(radio_name) => content_topic.application_name == radio_name.as_str(),
+            Route::ContentTopic(topic) => topic == content_topic,
+        }
+    }
+}
+
+/// Counters tracking message delivery to subscribers
+#[derive(Debug, Default)]
+struct SubscriptionCounters {
+    received: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    unrouted: AtomicU64,
+}
+
+/// Point in time view of the subscription backpressure metrics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubscriptionMetrics {
+    /// Messages received from the transport
+    pub received: u64,
+    /// Messages queued to a subscriber
+    pub delivered: u64,
+    /// Messages dropped because a subscriber's buffer was full
+    pub dropped: u64,
+    /// Messages that matched the route of no subscriber
+    pub unrouted: u64,
+    /// Number of active subscribers
+    pub subscribers: usize,
+    /// Messages waiting in subscriber buffers
+    pub queued: usize,
+}
+
+/// Distributes transport messages to the active subscribers on their route
+#[derive(Clone, Default)]
+pub struct MessageDispatcher {
+    subscribers: Arc<Mutex<Vec<(Route, mpsc::Sender<TransportMessage>)>>>,
+    counters: Arc<SubscriptionCounters>,
+}
+
+impl MessageDispatcher {
+    /// Register a new subscriber to every message with a buffer of `capacity` messages
+    pub fn subscribe(&self, capacity: usize) -> mpsc::Receiver<TransportMessage> {
+        self.subscribe_route(Route::All, capacity)
+    }
+
+    /// Register a new subscriber to the messages on `route` with a buffer of `capacity` messages
+    pub fn subscribe_route(
+        &self,
+        route: Route,
+        capacity: usize,
+    ) -> mpsc::Receiver<TransportMessage> {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        self.subscribers.lock().unwrap().push((route, sender));
+        receiver
+    }
+
+    /// Queue the message for every subscriber on its route, never blocks the caller
+    pub fn dispatch(&self, message: TransportMessage) {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        let mut routed = false;
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(route, sender)| {
+            if !route.matches(message.content_topic()) {
+                return !sender.is_closed();
+            }
+            match sender.try_send(message.clone()) {
+                Ok(()) => {
+                    routed = true;
+                    self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    routed = true;
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        id = message.message_id,
+                        "Subscriber buffer is full, drop message"
+                    );
+                    true
+                }
+                Err(TrySendError::Closed(_)) => {
+                    trace!("Remove closed subscriber");
+                    false
+                }
+            }
+        });
+        if !routed {
+            self.counters.unrouted.fetch_add(1, Ordering::Relaxed);
+            trace!(
+                id = message.message_id,
+                topic = tracing::field::display(message.content_topic()),
+                "No subscriber on the message route"
+            );
+        }
+    }
+
+    /// Remove every subscriber, ending their streams once the buffered messages are consumed
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+
+    pub fn metrics(&self) -> SubscriptionMetrics {
+        let subscribers = self.subscribers.lock().unwrap();
+        SubscriptionMetrics {
+            received: self.counters.received.load(Ordering::Relaxed),
+            delivered: self.counters.delivered.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            unrouted: self.counters.unrouted.load(Ordering::Relaxed),
+            subscribers: subscribers.iter().filter(|(_, s)| !s.is_closed()).count(),
+            queued: subscribers
+                .iter()
+                .map(|(_, s)| s.max_capacity() - s.capacity())
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        message_typing::ValidationError,
+        test_support::{next, send, test_config, wallet_key, LoopbackPayload, UpgradePayload},
+        validation::{NonceValidator, SignatureValidator, TimeValidator, ValidationPipeline},
+        waku_handling::{build_content_topics, pubsub_topic, WakuHandlingError},
+        GraphcastAgent,
+    };
+    use futures::{pin_mut, StreamExt};
+    use std::time::Duration;
+    use waku::WakuMessage;
+
+    fn message(id: &str) -> TransportMessage {
+        radio_message(id, "radio", "Qm")
+    }
+
+    fn radio_message(id: &str, radio_name: &str, subtopic: &str) -> TransportMessage {
+        let content_topic = build_content_topics(radio_name, 0, &[subtopic.to_string()])
+            .pop()
+            .unwrap();
+        TransportMessage::new(
+            id.to_string(),
+            pubsub_topic(None),
+            WakuMessage::new(vec![], content_topic, 2, 0, vec![], true),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_drops_when_full() {
+        let dispatcher = MessageDispatcher::default();
+        let mut slow = dispatcher.subscribe(1);
+        let mut fast = dispatcher.subscribe(10);
+
+        dispatcher.dispatch(message("1"));
+        dispatcher.dispatch(message("2"));
+
+        let metrics = dispatcher.metrics();
+        assert_eq!(metrics.received, 2);
+        assert_eq!(metrics.delivered, 3);
+        assert_eq!(metrics.dropped, 1);
+        assert_eq!(metrics.subscribers, 2);
+        assert_eq!(metrics.queued, 3);
+
+        assert_eq!(slow.recv().await.unwrap().message_id, "1");
+        assert_eq!(fast.recv().await.unwrap().message_id, "1");
+        assert_eq!(fast.recv().await.unwrap().message_id, "2");
+    }
+
+    #[tokio::test]
+    async fn test_closed_subscribers_removed() {
+        let dispatcher = MessageDispatcher::default();
+        let receiver = dispatcher.subscribe(1);
+        drop(receiver);
+        dispatcher.dispatch(message("1"));
+        assert_eq!(dispatcher.metrics().subscribers, 0);
+        assert_eq!(dispatcher.metrics().delivered, 0);
+
+        let mut receiver = dispatcher.subscribe(1);
+        dispatcher.close();
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let dispatcher = MessageDispatcher::default();
+        let mut poi = dispatcher.subscribe_route(Route::Radio("poi-radio".to_string()), 10);
+        let upgrade_topic = build_content_topics("upgrade-radio", 0, &["Qmone".to_string()])
+            .pop()
+            .unwrap();
+        let mut upgrade = dispatcher.subscribe_route(Route::ContentTopic(upgrade_topic), 10);
+        let mut all = dispatcher.subscribe(10);
+
+        dispatcher.dispatch(radio_message("1", "poi-radio", "Qmone"));
+        dispatcher.dispatch(radio_message("2", "upgrade-radio", "Qmone"));
+        dispatcher.dispatch(radio_message("3", "upgrade-radio", "Qmtwo"));
+        drop(all);
+        dispatcher.dispatch(radio_message("4", "other-radio", "Qmone"));
+
+        assert_eq!(poi.recv().await.unwrap().message_id, "1");
+        assert_eq!(upgrade.recv().await.unwrap().message_id, "2");
+        assert!(poi.try_recv().is_err());
+        assert!(upgrade.try_recv().is_err());
+        let metrics = dispatcher.metrics();
+        assert_eq!(metrics.received, 4);
+        assert_eq!(metrics.delivered, 5);
+        assert_eq!(metrics.unrouted, 1);
+        assert_eq!(metrics.subscribers, 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_share_messages() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let sender = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let mut receiver =
+            GraphcastAgent::with_transport(test_config(&wallet_key(2), subtopics), hub.transport())
+                .await
+                .unwrap();
+        // The block hash of the loopback messages cannot be checked against a graph node
+        receiver.set_validation_pipeline::<LoopbackPayload>(
+            ValidationPipeline::empty()
+                .with(SignatureValidator)
+                .with(TimeValidator)
+                .with(NonceValidator),
+        );
+        let radio = Route::Radio(String::from("loopback-radio"));
+        let first = receiver.subscribe_route::<LoopbackPayload>(radio.clone());
+        let second = receiver.subscribe_route::<LoopbackPayload>(radio);
+        let all = receiver.subscribe_route::<LoopbackPayload>(Route::All);
+        pin_mut!(first);
+        pin_mut!(second);
+        pin_mut!(all);
+
+        for content in ["First", "Second"] {
+            send(&sender, "Qmloopback", LoopbackPayload::new(content)).await;
+        }
+
+        // Every subscriber gets the same outcome for each message, however far behind it is
+        for messages in [&mut first, &mut second, &mut all] {
+            assert!(matches!(
+                next(messages).await,
+                Err(WakuHandlingError::Validation(
+                    ValidationError::FirstSeenTopic { .. }
+                ))
+            ));
+            let valid = next(messages).await.unwrap();
+            assert_eq!(valid.message.payload.unwrap().content, "Second");
+        }
+        assert_eq!(receiver.subscription_metrics().delivered, 6);
+    }
+
+    #[tokio::test]
+    async fn test_multi_radio_routing() {
+        let hub = LoopbackHub::new();
+        let mut agent = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), vec!["Qmloopback".to_string()]),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        agent
+            .join_radio("upgrade-radio", 0, &["Qmupgrade".to_string()])
+            .unwrap();
+        // The block hash and first nonces of the loopback messages cannot be checked
+        agent.set_validation_pipeline::<LoopbackPayload>(
+            ValidationPipeline::empty()
+                .with(SignatureValidator)
+                .with(TimeValidator),
+        );
+        agent.set_validation_pipeline::<UpgradePayload>(
+            ValidationPipeline::empty()
+                .with(SignatureValidator)
+                .with(TimeValidator),
+        );
+        let poi_messages = agent.subscribe::<LoopbackPayload>();
+        let upgrade_messages =
+            agent.subscribe_route::<UpgradePayload>(Route::Radio("upgrade-radio".to_string()));
+        pin_mut!(poi_messages);
+        pin_mut!(upgrade_messages);
+
+        let poi_peer = GraphcastAgent::with_transport(
+            test_config(&wallet_key(2), vec!["Qmloopback".to_string()]),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let mut upgrade_config = test_config(&wallet_key(3), vec!["Qmupgrade".to_string()]);
+        upgrade_config.radio_name = String::from("upgrade-radio");
+        let upgrade_peer = GraphcastAgent::with_transport(upgrade_config, hub.transport())
+            .await
+            .unwrap();
+
+        send(
+            &upgrade_peer,
+            "Qmupgrade",
+            UpgradePayload {
+                subgraph_id: String::from("Qmupgrade"),
+                new_version: 2,
+            },
+        )
+        .await;
+        send(&poi_peer, "Qmloopback", LoopbackPayload::new("Ping")).await;
+
+        let upgrade = next(&mut upgrade_messages).await.unwrap();
+        assert_eq!(upgrade.message.payload.unwrap().new_version, 2);
+        let poi = next(&mut poi_messages).await.unwrap();
+        assert_eq!(poi.message.payload.unwrap().content, "Ping");
+        // Each radio only receives the messages on its own content topics
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), upgrade_messages.next())
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), poi_messages.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(agent.subscription_metrics().unrouted, 0);
+    }
+}
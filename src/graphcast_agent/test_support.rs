@@ -0,0 +1,251 @@
+//! Fixtures shared by the agent tests.
+//!
+//! Agents run on a `LoopbackHub` with a config that skips the remote set up checks, and
+//! exchange `LoopbackPayload` messages signed and sent through `send`.
+//!
+use async_graphql::SimpleObject;
+use ethers_contract::EthAbiType;
+use ethers_core::types::transaction::eip712::Eip712;
+use ethers_derive_eip712::*;
+use futures::{Stream, StreamExt};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use waku::{WakuContentTopic, WakuMessage, WakuPubSubTopic};
+
+use super::{
+    heartbeat::DEFAULT_PEER_ROSTER_TTL,
+    history::StoreConfig,
+    loopback::LoopbackTransport,
+    message_typing::{GraphcastMessage, IdentityValidation, ENVELOPE_SIGNATURE_VERSION},
+    node::{NodeRole, NodeServices, WakuOptions},
+    nonce_store::{NonceStoreConfig, DEFAULT_NONCE_FLUSH_INTERVAL, DEFAULT_NONCE_MAX_AGE},
+    seen_messages::{DEFAULT_SEEN_MESSAGES_CAPACITY, DEFAULT_SEEN_MESSAGES_TTL},
+    transport::{GraphcastTransport, MessageHandler, PeerInfo},
+    waku_handling::WakuHandlingError,
+    GraphcastAgent, GraphcastAgentConfig, StartupPolicy, DEFAULT_MAX_CLOCK_SKEW,
+    DEFAULT_REPLAY_WINDOW,
+};
+use crate::callbook::cache::CacheConfig;
+use crate::graphql::client::HttpConfig;
+use crate::networks::NetworkName;
+
+#[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, SimpleObject)]
+#[eip712(
+    name = "Graphcast Loopback Radio",
+    version = "0",
+    chain_id = 1,
+    verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+)]
+pub struct LoopbackPayload {
+    #[prost(string, tag = "1")]
+    pub content: String,
+}
+
+impl LoopbackPayload {
+    pub fn new(content: &str) -> Self {
+        LoopbackPayload {
+            content: content.to_string(),
+        }
+    }
+}
+
+#[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, SimpleObject)]
+#[eip712(
+    name = "Graphcast Upgrade Radio",
+    version = "0",
+    chain_id = 1,
+    verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+)]
+pub struct UpgradePayload {
+    #[prost(string, tag = "1")]
+    pub subgraph_id: String,
+    #[prost(uint64, tag = "2")]
+    pub new_version: u64,
+}
+
+/// Config of a "loopback-radio" agent without remote endpoints or identity checks
+pub fn test_config(wallet_key: &str, subtopics: Vec<String>) -> GraphcastAgentConfig {
+    GraphcastAgentConfig {
+        wallet_key: wallet_key.to_string(),
+        graph_account: String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        radio_name: String::from("loopback-radio"),
+        registry_subgraph: String::from("http://127.0.0.1:1/registry"),
+        network_subgraph: String::from("http://127.0.0.1:1/network"),
+        graph_node_endpoint: String::from("http://127.0.0.1:1/graphql"),
+        boot_node_addresses: vec![],
+        graphcast_namespace: Some(String::from("testnet")),
+        subtopics,
+        waku_node_key: None,
+        waku_host: None,
+        waku_port: None,
+        waku_addr: None,
+        filter_protocol: Some(true),
+        discv5_enrs: vec![],
+        discv5_port: None,
+        id_validation: Some(IdentityValidation::NoCheck),
+        startup_policy: StartupPolicy::Offline,
+        node_role: NodeRole::Light,
+        node_services: NodeServices::default(),
+        waku_options: WakuOptions::light(),
+        cache_config: CacheConfig::default(),
+        http_config: HttpConfig::default(),
+        nonce_store: NonceStoreConfig::Memory,
+        nonce_flush_interval: DEFAULT_NONCE_FLUSH_INTERVAL,
+        nonce_max_age: DEFAULT_NONCE_MAX_AGE,
+        seen_messages_capacity: DEFAULT_SEEN_MESSAGES_CAPACITY,
+        seen_messages_ttl: DEFAULT_SEEN_MESSAGES_TTL,
+        replay_window: DEFAULT_REPLAY_WINDOW,
+        max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+        accept_legacy_signatures: false,
+        peer_roster_ttl: DEFAULT_PEER_ROSTER_TTL,
+        radio_version: 0,
+        accepted_radio_versions: vec![],
+        store: StoreConfig::default(),
+    }
+}
+
+pub fn wallet_key(i: u8) -> String {
+    format!("{:0>64}", i)
+}
+
+/// Canned message with an invalid signature, for tests that only check routing
+pub fn test_message(identifier: &str) -> GraphcastMessage<LoopbackPayload> {
+    GraphcastMessage {
+        identifier: identifier.to_string(),
+        payload: Some(LoopbackPayload::new("Ping")),
+        nonce: 1687448729,
+        network: NetworkName::Goerli.to_string(),
+        block_number: 0,
+        block_hash: String::from("0xblahh"),
+        graph_account: String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        signature: String::from("0x"),
+        signature_version: ENVELOPE_SIGNATURE_VERSION,
+    }
+}
+
+/// Sign `payload` as `agent` and send it on the agent's content topic of `identifier`,
+/// returns the message id
+pub async fn send<N, T>(agent: &GraphcastAgent<N>, identifier: &str, payload: T) -> String
+where
+    N: GraphcastTransport,
+    T: Message + Eip712 + Default + Clone + 'static + async_graphql::OutputType,
+{
+    let content_topic = agent
+        .match_content_topic(identifier.to_string())
+        .await
+        .unwrap();
+    GraphcastMessage::build(
+        &agent.graphcast_identity.wallet,
+        &content_topic,
+        identifier.to_string(),
+        Some(payload),
+        NetworkName::Goerli,
+        0,
+        String::from("0xblahh"),
+        agent.graphcast_identity.graph_account.clone(),
+    )
+    .await
+    .unwrap()
+    .send_to_waku(&agent.transport, agent.pubsub_topic.clone(), content_topic)
+    .unwrap()
+}
+
+/// Next item of a message stream, failing the test after a second
+pub async fn next<S: Stream + Unpin>(messages: &mut S) -> S::Item {
+    tokio::time::timeout(Duration::from_secs(1), messages.next())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+/// Answer the block hash check of the messages built by `send` from the CallBook cache
+pub async fn cache_block_hash<N: GraphcastTransport>(agent: &GraphcastAgent<N>) {
+    agent
+        .callbook
+        .cache()
+        .block_hashes
+        .get_or_fetch((NetworkName::Goerli.to_string(), 0), || async {
+            Ok(String::from("0xblahh"))
+        })
+        .await
+        .unwrap();
+}
+
+/// Loopback transport whose subscriptions can be made to fail, with the ENR of a Discv5
+/// enabled node
+pub struct FlakyTransport {
+    pub inner: LoopbackTransport,
+    pub fail_subscribe: AtomicBool,
+    pub enr: Option<String>,
+}
+
+impl FlakyTransport {
+    pub fn new(inner: LoopbackTransport) -> Self {
+        FlakyTransport {
+            inner,
+            fail_subscribe: AtomicBool::new(false),
+            enr: None,
+        }
+    }
+
+    pub fn fail_subscribe(&self, fail: bool) {
+        self.fail_subscribe.store(fail, Ordering::SeqCst);
+    }
+}
+
+impl GraphcastTransport for FlakyTransport {
+    fn local_peer_id(&self) -> Result<String, WakuHandlingError> {
+        self.inner.local_peer_id()
+    }
+
+    fn enr(&self) -> Result<Option<String>, WakuHandlingError> {
+        Ok(self.enr.clone())
+    }
+
+    fn peers(&self) -> Result<Vec<PeerInfo>, WakuHandlingError> {
+        self.inner.peers()
+    }
+
+    fn publish(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<String, WakuHandlingError> {
+        self.inner.publish(pubsub_topic, message)
+    }
+
+    fn subscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError> {
+        if self.fail_subscribe.load(Ordering::SeqCst) {
+            return Err(WakuHandlingError::ContentTopicsError(
+                "No filter peer".to_string(),
+            ));
+        }
+        self.inner.subscribe(pubsub_topic, content_topics)
+    }
+
+    fn unsubscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError> {
+        self.inner.unsubscribe(pubsub_topic, content_topics)
+    }
+
+    fn set_message_handler(&self, handler: MessageHandler) {
+        self.inner.set_message_handler(handler)
+    }
+
+    fn clear_message_handler(&self) {
+        self.inner.clear_message_handler()
+    }
+
+    fn stop(&self) -> Result<(), WakuHandlingError> {
+        self.inner.stop()
+    }
+}
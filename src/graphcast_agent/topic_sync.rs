@@ -0,0 +1,294 @@
+//! Content topics following the indexer's allocations.
+//!
+//! Radios commonly use the IPFS hashes of the deployments their indexer allocates to as
+//! subtopics. The topic sync task polls the network subgraph for the indexer's active
+//! allocations, optionally keeps the deployments the local graph node indexes, and updates
+//! the agent's content topics when the set changes. Every change is published as a
+//! `TopicChange` to the agent's topic event subscribers.
+//!
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::trace;
+
+use crate::{callbook::CallBook, graphql::QueryError};
+
+/// Default time between two polls of the indexer's allocations
+pub const DEFAULT_TOPIC_SYNC_INTERVAL: Duration = Duration::from_secs(300);
+/// Default time between two retries of the pending content topic subscriptions
+pub const DEFAULT_TOPIC_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Number of topic changes buffered for each topic event subscriber
+pub const TOPIC_EVENTS_BUFFER: usize = 16;
+
+/// Settings of the topic sync task
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopicSyncConfig {
+    /// Time between two polls of the indexer's allocations
+    pub interval: Duration,
+    /// Only keep allocated deployments that are reported by graph node `indexingStatuses`
+    pub require_indexing: bool,
+    /// Time between two retries of the content topic subscriptions that failed
+    pub retry_interval: Duration,
+}
+
+impl Default for TopicSyncConfig {
+    fn default() -> Self {
+        TopicSyncConfig {
+            interval: DEFAULT_TOPIC_SYNC_INTERVAL,
+            require_indexing: false,
+            retry_interval: DEFAULT_TOPIC_RETRY_INTERVAL,
+        }
+    }
+}
+
+/// Subtopics added to and removed from the agent's content topics
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopicChange {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl TopicChange {
+    /// Change from the `current` subtopics to the `next` ones, preserving their order
+    pub fn between(current: &[String], next: &[String]) -> Self {
+        TopicChange {
+            added: next
+                .iter()
+                .filter(|topic| !current.contains(topic))
+                .cloned()
+                .collect(),
+            removed: current
+                .iter()
+                .filter(|topic| !next.contains(topic))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Deduplicated allocations, restricted to the `indexed` deployments when provided
+pub fn select_subtopics(allocations: Vec<String>, indexed: Option<&[String]>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    allocations
+        .into_iter()
+        .filter(|deployment| indexed.map_or(true, |indexed| indexed.contains(deployment)))
+        .filter(|deployment| seen.insert(deployment.clone()))
+        .collect()
+}
+
+/// Subtopics of the deployments the indexer allocates to. The allocations are queried past
+/// the CallBook cache, as its stake TTL can be longer than the sync interval
+pub async fn allocated_subtopics(
+    callbook: &CallBook,
+    indexer_address: &str,
+    require_indexing: bool,
+) -> Result<Vec<String>, QueryError> {
+    let allocations = callbook
+        .refresh_network_subgraph(indexer_address.to_string())
+        .await?
+        .indexer_allocations();
+    let indexed = if require_indexing {
+        Some(
+            callbook
+                .indexing_statuses()
+                .await?
+                .into_iter()
+                .map(|status| status.subgraph)
+                .collect::<Vec<String>>(),
+        )
+    } else {
+        None
+    };
+    let subtopics = select_subtopics(allocations, indexed.as_deref());
+    trace!(
+        indexer = indexer_address,
+        subtopics = tracing::field::debug(&subtopics),
+        "Allocated subtopics"
+    );
+    Ok(subtopics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        test_support::{
+            next, test_config, test_message, wallet_key, FlakyTransport, LoopbackPayload,
+        },
+        validation::ValidationPipeline,
+        waku_handling::build_content_topics,
+        GraphcastAgent,
+    };
+    use futures::pin_mut;
+    use std::sync::Arc;
+
+    fn topics(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_topic_change() {
+        let change = TopicChange::between(&topics(&["Qma", "Qmb"]), &topics(&["Qmb", "Qmc"]));
+        assert_eq!(change.added, topics(&["Qmc"]));
+        assert_eq!(change.removed, topics(&["Qma"]));
+        assert!(TopicChange::between(&topics(&["Qma"]), &topics(&["Qma"])).is_empty());
+    }
+
+    #[test]
+    fn test_select_subtopics() {
+        let allocations = topics(&["Qma", "Qmb", "Qma", "Qmc"]);
+        assert_eq!(
+            select_subtopics(allocations.clone(), None),
+            topics(&["Qma", "Qmb", "Qmc"])
+        );
+        assert_eq!(
+            select_subtopics(allocations, Some(&topics(&["Qmc", "Qma", "Qmz"]))),
+            topics(&["Qma", "Qmc"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_content_topics() {
+        let hub = LoopbackHub::new();
+        let sender = hub.transport();
+        let mut agent = GraphcastAgent::with_transport(
+            test_config(
+                &wallet_key(1),
+                vec!["Qmone".to_string(), "Qmtwo".to_string()],
+            ),
+            FlakyTransport::new(hub.transport()),
+        )
+        .await
+        .unwrap();
+        // Only the routing of the canned messages is checked
+        agent.set_validation_pipeline::<LoopbackPayload>(ValidationPipeline::empty());
+        let messages = agent.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+        let mut events = agent.topic_events();
+        let publish = |subtopic: &str| {
+            test_message(subtopic)
+                .send_to_waku(
+                    &sender,
+                    agent.pubsub_topic.clone(),
+                    build_content_topics("loopback-radio", 0, &[subtopic.to_string()])[0].clone(),
+                )
+                .unwrap()
+        };
+
+        let change = agent
+            .update_content_topics(vec!["Qmtwo".to_string(), "Qmthree".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(change.added, vec!["Qmthree".to_string()]);
+        assert_eq!(change.removed, vec!["Qmone".to_string()]);
+        assert_eq!(events.try_recv().unwrap(), change);
+        // Removed topics are unsubscribed, added topics are delivered
+        publish("Qmone");
+        publish("Qmthree");
+        assert_eq!(
+            next(&mut messages).await.unwrap().message.identifier,
+            "Qmthree"
+        );
+
+        // Failed subscriptions are kept pending and retried on the next update
+        agent.transport.fail_subscribe(true);
+        assert!(agent
+            .update_content_topics(vec!["Qmfour".to_string()])
+            .await
+            .is_err());
+        assert_eq!(
+            agent.content_identifiers().await,
+            vec!["Qmfour".to_string()]
+        );
+        assert_eq!(agent.pending_content_topics().await.len(), 1);
+        agent.transport.fail_subscribe(false);
+        assert!(agent
+            .update_content_topics(vec!["Qmfour".to_string()])
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(agent.pending_content_topics().await.is_empty());
+        publish("Qmfour");
+        assert_eq!(
+            next(&mut messages).await.unwrap().message.identifier,
+            "Qmfour"
+        );
+        agent.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pending_topics_retry() {
+        let hub = LoopbackHub::new();
+        let sender = hub.transport();
+        let mut agent = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), vec![]),
+            FlakyTransport::new(hub.transport()),
+        )
+        .await
+        .unwrap();
+        // Only the routing of the canned message is checked
+        agent.set_validation_pipeline::<LoopbackPayload>(ValidationPipeline::empty());
+        let agent = Arc::new(agent);
+        let messages = agent.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+
+        agent.transport.fail_subscribe(true);
+        assert!(agent
+            .update_content_topics(vec!["Qmloopback".to_string()])
+            .await
+            .is_err());
+        agent.start_topic_retry(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(agent.pending_content_topics().await.len(), 1);
+
+        // Retried without another update once the transport recovers
+        agent.transport.fail_subscribe(false);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !agent.pending_content_topics().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        test_message("Qmloopback")
+            .send_to_waku(
+                &sender,
+                agent.pubsub_topic.clone(),
+                build_content_topics("loopback-radio", 0, &["Qmloopback".to_string()])[0].clone(),
+            )
+            .unwrap();
+        assert_eq!(
+            next(&mut messages).await.unwrap().message.identifier,
+            "Qmloopback"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_startup_subscriptions_pending() {
+        let hub = LoopbackHub::new();
+        let transport = FlakyTransport::new(hub.transport());
+        transport.fail_subscribe(true);
+        let agent = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), vec!["Qmloopback".to_string()]),
+            transport,
+        )
+        .await
+        .unwrap();
+        // The radio's content topic and the heartbeat topic
+        assert_eq!(agent.pending_content_topics().await.len(), 2);
+        assert!(agent.retry_pending_topics().await.is_err());
+        assert!(agent
+            .update_content_topics(vec!["Qmother".to_string()])
+            .await
+            .is_err());
+        assert_eq!(agent.pending_content_topics().await.len(), 2);
+
+        agent.transport.fail_subscribe(false);
+        assert_eq!(agent.retry_pending_topics().await.unwrap(), 2);
+        assert!(agent.pending_content_topics().await.is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+//! Transport abstraction for the Graphcast agent.
+//!
+//! A `GraphcastTransport` carries Graphcast messages between agents. The agent only
+//! relies on this trait for publishing, subscribing, peer listing and receiving
+//! messages, so radio logic can run on top of a live Waku node (`WakuTransport`)
+//! or any other backend that keeps the same pubsub/content topic semantics.
+//!
+use ethers_core::utils::keccak256;
+use waku::{Multiaddr, WakuContentTopic, WakuMessage, WakuPubSubTopic};
+
+use super::{history::TimeRange, waku_handling::WakuHandlingError};
+
+/// A message received by a transport, together with the routing information
+/// it was delivered with
+#[derive(Clone, Debug)]
+pub struct TransportMessage {
+    /// Message id assigned by the transport
+    pub message_id: String,
+    /// Pubsub topic the message was received on
+    pub pubsub_topic: WakuPubSubTopic,
+    /// Waku message carrying the encoded Graphcast message
+    pub waku_message: WakuMessage,
+}
+
+impl TransportMessage {
+    pub fn new(
+        message_id: String,
+        pubsub_topic: WakuPubSubTopic,
+        waku_message: WakuMessage,
+    ) -> Self {
+        TransportMessage {
+            message_id,
+            pubsub_topic,
+            waku_message,
+        }
+    }
+
+    /// Message with an id derived from the pubsub topic and message content like Waku
+    /// message hashes, for transports that do not assign ids themselves
+    pub fn hashed(pubsub_topic: WakuPubSubTopic, waku_message: WakuMessage) -> Self {
+        let mut data = pubsub_topic.to_string().into_bytes();
+        data.extend_from_slice(waku_message.content_topic().to_string().as_bytes());
+        data.extend_from_slice(waku_message.payload());
+        data.extend_from_slice(&waku_message.timestamp().to_be_bytes());
+        TransportMessage::new(
+            format!("0x{}", data_encoding::HEXLOWER.encode(&keccak256(data))),
+            pubsub_topic,
+            waku_message,
+        )
+    }
+
+    /// Content topic the message was published to
+    pub fn content_topic(&self) -> &WakuContentTopic {
+        self.waku_message.content_topic()
+    }
+}
+
+/// Basic information about a peer known by the transport
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub addresses: Vec<Multiaddr>,
+    pub connected: bool,
+}
+
+/// A peer that did not accept a published message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerFailure {
+    pub peer_id: String,
+    pub reason: String,
+}
+
+/// Outcome of publishing a message through a transport
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SendReport {
+    /// Message id, set once a peer accepted the message
+    pub message_id: Option<String>,
+    /// Peers that accepted the message. Relay nodes list the local node, which gossips the
+    /// message to its mesh. Transports that do not track peers leave it empty
+    pub accepted: Vec<String>,
+    /// Peers that were tried and did not accept the message
+    pub failed: Vec<PeerFailure>,
+}
+
+impl SendReport {
+    /// Record a peer that accepted the message with the returned id
+    pub fn accept(&mut self, peer_id: String, message_id: String) {
+        self.accepted.push(peer_id);
+        self.message_id.get_or_insert(message_id);
+    }
+
+    /// Record a peer that did not accept the message
+    pub fn fail(&mut self, peer_id: String, reason: impl ToString) {
+        self.failed.push(PeerFailure {
+            peer_id,
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn is_sent(&self) -> bool {
+        self.message_id.is_some()
+    }
+
+    /// Message id if any peer accepted the message, the peer failures otherwise
+    pub fn into_result(self) -> Result<String, WakuHandlingError> {
+        self.message_id.ok_or_else(|| {
+            let reasons = self
+                .failed
+                .iter()
+                .map(|failure| format!("{}: {}", failure.peer_id, failure.reason))
+                .collect::<Vec<String>>();
+            WakuHandlingError::PublishMessage(if reasons.is_empty() {
+                "Message could not be sent to any peers".to_string()
+            } else {
+                format!(
+                    "Message could not be sent to any peers: {}",
+                    reasons.join("; ")
+                )
+            })
+        })
+    }
+}
+
+/// Callback invoked by a transport for every inbound message
+pub type MessageHandler = Box<dyn Fn(TransportMessage) + Send + Sync + 'static>;
+
+/// Operations a Graphcast agent needs from the underlying p2p network
+pub trait GraphcastTransport: Send + Sync + 'static {
+    /// Peer id of the local node
+    fn local_peer_id(&self) -> Result<String, WakuHandlingError>;
+
+    /// Addresses the local node listens on, empty for transports without network addresses
+    fn listen_addresses(&self) -> Result<Vec<Multiaddr>, WakuHandlingError> {
+        Ok(vec![])
+    }
+
+    /// Discv5 ENR of the local node, if the transport exposes it
+    fn enr(&self) -> Result<Option<String>, WakuHandlingError> {
+        Ok(None)
+    }
+
+    /// List the peers known to the local node, excluding the local node itself
+    fn peers(&self) -> Result<Vec<PeerInfo>, WakuHandlingError>;
+
+    /// Number of peers excluding self
+    fn peer_count(&self) -> Result<usize, WakuHandlingError> {
+        self.peers().map(|peers| peers.len())
+    }
+
+    /// Connect to a known peer
+    fn connect_peer(&self, peer_id: &str) -> Result<(), WakuHandlingError> {
+        Err(WakuHandlingError::ConnectPeer(format!(
+            "Transport cannot connect to {peer_id}"
+        )))
+    }
+
+    /// Close the connection to a peer, it stays known to the transport
+    fn disconnect_peer(&self, peer_id: &str) -> Result<(), WakuHandlingError> {
+        Err(WakuHandlingError::ConnectPeer(format!(
+            "Transport cannot disconnect from {peer_id}"
+        )))
+    }
+
+    /// Publish a message on the pubsub topic, returns the message id
+    fn publish(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<String, WakuHandlingError>;
+
+    /// Publish a message on the pubsub topic and report the peers that accepted or failed it.
+    /// Errors are reserved to the transport itself being unusable
+    fn publish_report(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<SendReport, WakuHandlingError> {
+        let message_id = self.publish(pubsub_topic, message)?;
+        Ok(SendReport {
+            message_id: Some(message_id),
+            ..Default::default()
+        })
+    }
+
+    /// Subscribe to the content topics on the pubsub topic
+    fn subscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError>;
+
+    /// Unsubscribe from the content topics on the pubsub topic
+    fn unsubscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError>;
+
+    /// Install the callback that receives inbound messages, replacing any previous one
+    fn set_message_handler(&self, handler: MessageHandler);
+
+    /// Remove the inbound message callback, messages received afterwards are dropped
+    fn clear_message_handler(&self);
+
+    /// Messages previously published on the content topics within `time_range`, oldest first.
+    /// Transports without access to a message store return an error
+    fn query_history(
+        &self,
+        _pubsub_topic: &WakuPubSubTopic,
+        _content_topics: &[WakuContentTopic],
+        _time_range: TimeRange,
+    ) -> Result<Vec<TransportMessage>, WakuHandlingError> {
+        Err(WakuHandlingError::StoreQuery(
+            "Transport has no message history".to_string(),
+        ))
+    }
+
+    /// Check for peer connectivity and try to recover disconnected peers
+    fn network_check(&self) -> Result<(), WakuHandlingError> {
+        Ok(())
+    }
+
+    /// Stop the transport, it cannot publish or receive messages afterwards.
+    /// Stopping an already stopped transport is a no-op
+    fn stop(&self) -> Result<(), WakuHandlingError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphcast_agent::{
+        message_typing::{GraphcastMessage, ENVELOPE_SIGNATURE_VERSION},
+        waku_handling::{build_content_topics, pubsub_topic},
+    };
+    use async_graphql::SimpleObject;
+    use ethers_contract::EthAbiType;
+    use ethers_core::types::transaction::eip712::Eip712;
+    use ethers_derive_eip712::*;
+    use prost::Message;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Mutex;
+
+    #[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, SimpleObject)]
+    #[eip712(
+        name = "Graphcast Test Radio",
+        version = "0",
+        chain_id = 1,
+        verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+    )]
+    pub struct TestPayload {
+        #[prost(string, tag = "1")]
+        pub content: String,
+    }
+
+    /// Transport that records published messages instead of sending them
+    #[derive(Default)]
+    struct RecordingTransport {
+        published: Mutex<Vec<(WakuPubSubTopic, WakuMessage)>>,
+    }
+
+    impl GraphcastTransport for RecordingTransport {
+        fn local_peer_id(&self) -> Result<String, WakuHandlingError> {
+            Ok(String::from("recorder"))
+        }
+
+        fn peers(&self) -> Result<Vec<PeerInfo>, WakuHandlingError> {
+            Ok(vec![])
+        }
+
+        fn publish(
+            &self,
+            pubsub_topic: &WakuPubSubTopic,
+            message: &WakuMessage,
+        ) -> Result<String, WakuHandlingError> {
+            let mut published = self.published.lock().unwrap();
+            published.push((pubsub_topic.clone(), message.clone()));
+            Ok(published.len().to_string())
+        }
+
+        fn subscribe(
+            &self,
+            _pubsub_topic: &WakuPubSubTopic,
+            _content_topics: &[WakuContentTopic],
+        ) -> Result<(), WakuHandlingError> {
+            Ok(())
+        }
+
+        fn unsubscribe(
+            &self,
+            _pubsub_topic: &WakuPubSubTopic,
+            _content_topics: &[WakuContentTopic],
+        ) -> Result<(), WakuHandlingError> {
+            Ok(())
+        }
+
+        fn set_message_handler(&self, _handler: MessageHandler) {}
+
+        fn clear_message_handler(&self) {}
+    }
+
+    #[test]
+    fn test_send_through_transport() {
+        let transport = RecordingTransport::default();
+        let pubsub_topic = pubsub_topic(Some("testnet"));
+        let content_topic = build_content_topics("test-radio", 0, &["Qmtest".to_string()])
+            .pop()
+            .unwrap();
+        let msg = GraphcastMessage {
+            identifier: String::from("Qmtest"),
+            payload: Some(TestPayload {
+                content: String::from("Ping"),
+            }),
+            nonce: 1687448729,
+            network: String::from("goerli"),
+            block_number: 0,
+            block_hash: String::from("0xblahh"),
+            graph_account: String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+            signature: String::from("0x"),
+            signature_version: ENVELOPE_SIGNATURE_VERSION,
+        };
+
+        let id = msg
+            .send_to_waku(&transport, pubsub_topic.clone(), content_topic.clone())
+            .expect("Could not publish through transport");
+        assert_eq!(id, "1");
+
+        let published = transport.published.lock().unwrap();
+        let (topic, waku_message) = published.first().unwrap();
+        assert_eq!(topic, &pubsub_topic);
+        assert_eq!(waku_message.content_topic(), &content_topic);
+        let decoded =
+            <GraphcastMessage<TestPayload> as Message>::decode(waku_message.payload()).unwrap();
+        assert_eq!(decoded.identifier, "Qmtest");
+        assert_eq!(decoded.payload.unwrap().content, "Ping");
+    }
+
+    #[test]
+    fn test_send_report() {
+        let mut report = SendReport::default();
+        report.fail(String::from("peer-1"), "timeout");
+        assert!(!report.is_sent());
+        assert!(matches!(
+            report.clone().into_result(),
+            Err(WakuHandlingError::PublishMessage(e)) if e.contains("peer-1: timeout")
+        ));
+
+        report.accept(String::from("peer-2"), String::from("0xid"));
+        report.accept(String::from("peer-3"), String::from("0xid"));
+        assert_eq!(report.accepted, vec!["peer-2", "peer-3"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.into_result().unwrap(), "0xid");
+    }
+}
@@ -0,0 +1,634 @@
+//! Composable validation of inbound messages.
+//!
+//! A `ValidationPipeline` runs `MessageValidator` stages in order and stops at the first
+//! failure. The default pipeline runs the signature, sender, time, block hash and nonce
+//! checks. Radios can build their own pipeline to skip checks, such as the block hash for
+//! non-EVM data, reorder them, or add payload specific validators.
+//!
+//! The nonce check records sender nonces as it goes, so the pipeline keeps it after every
+//! other stage: a message rejected by any check does not advance the sender's nonce.
+//! Every subscriber on a message's route validates its own copy of the message, the nonce
+//! check outcome is recorded once per message in `NonceVerdicts` and shared by the copies.
+//!
+use async_trait::async_trait;
+use prost::Message;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::message_typing::{
+    BuildMessageError, ReceivedMessage, ValidationContext, ValidationError,
+    LEGACY_SIGNATURE_VERSION,
+};
+use super::subscription::DEFAULT_SUBSCRIPTION_BUFFER;
+use crate::NoncesMap;
+
+/// Default number of nonce check outcomes kept for subscribers lagging behind
+pub const DEFAULT_NONCE_VERDICTS_CAPACITY: usize = 4 * DEFAULT_SUBSCRIPTION_BUFFER;
+
+/// A single validity check run on decoded messages
+#[async_trait]
+pub trait MessageValidator<T>: Send + Sync
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError>;
+
+    /// Stages recording state from the messages they accept, kept after the other stages of
+    /// a pipeline so that rejected messages are not recorded
+    fn runs_last(&self) -> bool {
+        false
+    }
+}
+
+/// Check the signing scheme, rejecting legacy payload-only signatures unless accepted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignatureValidator;
+
+/// Verify the sender's identity with the context's `IdentityValidation`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SenderValidator;
+
+/// Drop replayed messages and messages too far in the future
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeValidator;
+
+/// Compare the message block hash with the local graph node
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockHashValidator;
+
+/// Ensure the ordering of messages per sender and topic, and record the sender nonce.
+/// Skipped when the context does not check nonces, such as for history
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NonceValidator;
+
+#[async_trait]
+impl<T> MessageValidator<T> for SignatureValidator
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError> {
+        let message = &received.message;
+        message.valid_signature(context.accept_legacy_signatures)?;
+        if message.signature_version == LEGACY_SIGNATURE_VERSION {
+            debug!(
+                identifier = message.identifier,
+                "Accepted legacy signature over the payload only"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T> MessageValidator<T> for SenderValidator
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError> {
+        received
+            .message
+            .valid_sender(
+                &received.content_topic,
+                &context.callbook,
+                context.local_sender_id.clone(),
+                context.id_validation.clone(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T> MessageValidator<T> for TimeValidator
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError> {
+        received
+            .message
+            .valid_time(context.replay_window, context.max_clock_skew)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T> MessageValidator<T> for BlockHashValidator
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError> {
+        received.message.valid_hash(&context.callbook).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T> MessageValidator<T> for NonceValidator
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError> {
+        if !context.check_nonces {
+            return Ok(());
+        }
+        context
+            .nonce_verdicts
+            .check(received, &context.nonces)
+            .await
+    }
+
+    fn runs_last(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Default)]
+struct Verdicts {
+    outcomes: HashMap<String, Result<(), ValidationError>>,
+    order: VecDeque<String>,
+}
+
+/// Outcomes of the nonce check by message. The first copy of a message checks and records
+/// the sender nonce, the copies delivered to other subscribers get the same outcome instead
+/// of being checked against the nonce their sibling just recorded
+pub struct NonceVerdicts {
+    capacity: usize,
+    verdicts: Mutex<Verdicts>,
+}
+
+impl Default for NonceVerdicts {
+    fn default() -> Self {
+        NonceVerdicts::new(DEFAULT_NONCE_VERDICTS_CAPACITY)
+    }
+}
+
+impl NonceVerdicts {
+    pub fn new(capacity: usize) -> Self {
+        NonceVerdicts {
+            capacity: capacity.max(1),
+            verdicts: Mutex::new(Verdicts::default()),
+        }
+    }
+
+    /// Nonce check outcome of the message, checked against `nonces` on its first copy
+    pub async fn check<T>(
+        &self,
+        received: &ReceivedMessage<T>,
+        nonces: &Arc<Mutex<NoncesMap>>,
+    ) -> Result<(), BuildMessageError>
+    where
+        T: Message
+            + ethers::types::transaction::eip712::Eip712
+            + Default
+            + Clone
+            + 'static
+            + async_graphql::OutputType,
+    {
+        // Legacy signatures do not cover the nonce, and envelope signatures recover another
+        // sender on another content topic, so both are part of the key
+        let message = &received.message;
+        let key = format!(
+            "{}/{}/{}/{}",
+            received.content_topic, message.identifier, message.nonce, message.signature
+        );
+        // Held while checking so concurrent copies wait for the first outcome
+        let mut verdicts = self.verdicts.lock().await;
+        if let Some(outcome) = verdicts.outcomes.get(&key) {
+            return outcome.clone().map_err(BuildMessageError::Validation);
+        }
+        let outcome = match message.valid_nonce(&received.content_topic, nonces).await {
+            Ok(_) => Ok(()),
+            Err(BuildMessageError::Validation(e)) => Err(e),
+            Err(e) => return Err(e),
+        };
+        if verdicts.order.len() >= self.capacity {
+            if let Some(oldest) = verdicts.order.pop_front() {
+                verdicts.outcomes.remove(&oldest);
+            }
+        }
+        verdicts.order.push_back(key.clone());
+        verdicts.outcomes.insert(key, outcome.clone());
+        outcome.map_err(BuildMessageError::Validation)
+    }
+}
+
+/// Ordered validation stages for messages with payload `T`
+pub struct ValidationPipeline<T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    stages: Vec<Arc<dyn MessageValidator<T>>>,
+}
+
+impl<T> Clone for ValidationPipeline<T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    fn clone(&self) -> Self {
+        ValidationPipeline {
+            stages: self.stages.clone(),
+        }
+    }
+}
+
+impl<T> Default for ValidationPipeline<T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    /// Signature, sender, time, block hash and nonce checks
+    fn default() -> Self {
+        ValidationPipeline::empty()
+            .with(SignatureValidator)
+            .with(SenderValidator)
+            .with(TimeValidator)
+            .with(BlockHashValidator)
+            .with(NonceValidator)
+    }
+}
+
+impl<T> ValidationPipeline<T>
+where
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+{
+    /// Pipeline without stages, accepting every decoded message
+    pub fn empty() -> Self {
+        ValidationPipeline { stages: vec![] }
+    }
+
+    /// Append a stage, run after the existing ones but before the stages that run last,
+    /// such as the nonce check
+    pub fn with(self, validator: impl MessageValidator<T> + 'static) -> Self {
+        let index = self.stages.len();
+        self.insert(index, validator)
+    }
+
+    /// Insert a stage at `index`, shifting the later stages. Stages that run last are
+    /// appended, and other stages are placed before them whatever the index
+    pub fn insert(mut self, index: usize, validator: impl MessageValidator<T> + 'static) -> Self {
+        let first_last = self
+            .stages
+            .iter()
+            .position(|stage| stage.runs_last())
+            .unwrap_or(self.stages.len());
+        let index = if validator.runs_last() {
+            self.stages.len()
+        } else {
+            index.min(first_last)
+        };
+        self.stages.insert(index, Arc::new(validator));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run the stages in order, returning the first failure
+    pub async fn validate(
+        &self,
+        received: &ReceivedMessage<T>,
+        context: &ValidationContext,
+    ) -> Result<(), BuildMessageError> {
+        for stage in &self.stages {
+            stage.validate(received, context).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callbook::CallBook;
+    use crate::graphcast_agent::{
+        loopback::LoopbackHub,
+        message_typing::{GraphcastMessage, IdentityValidation},
+        seen_messages::SeenMessages,
+        test_support::{next, send, test_config, wallet_key, LoopbackPayload},
+        waku_handling::{build_content_topics, WakuHandlingError},
+        GraphcastAgent, DEFAULT_MAX_CLOCK_SKEW, DEFAULT_REPLAY_WINDOW,
+    };
+    use crate::networks::NetworkName;
+    use async_graphql::SimpleObject;
+    use ethers::signers::Wallet;
+    use ethers_contract::EthAbiType;
+    use ethers_core::rand::thread_rng;
+    use ethers_core::types::transaction::eip712::Eip712;
+    use ethers_derive_eip712::*;
+    use futures::pin_mut;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, SimpleObject)]
+    #[eip712(
+        name = "Graphcast Test Radio",
+        version = "0",
+        chain_id = 1,
+        verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+    )]
+    pub struct TestPayload {
+        #[prost(string, tag = "1")]
+        pub content: String,
+    }
+
+    /// Records its name when run, and fails if asked to
+    struct Stage {
+        name: &'static str,
+        fail: bool,
+        runs: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl MessageValidator<TestPayload> for Stage {
+        async fn validate(
+            &self,
+            received: &ReceivedMessage<TestPayload>,
+            _context: &ValidationContext,
+        ) -> Result<(), BuildMessageError> {
+            self.runs.lock().unwrap().push(self.name);
+            if self.fail {
+                return Err(BuildMessageError::TypeCast(format!(
+                    "{} rejected {}",
+                    self.name, received.message.identifier
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    fn validation_context() -> ValidationContext {
+        ValidationContext {
+            nonces: Arc::default(),
+            callbook: CallBook::new(String::new(), String::new(), String::new()),
+            local_sender_id: String::from("0xlocal"),
+            id_validation: IdentityValidation::NoCheck,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            accept_legacy_signatures: false,
+            seen_messages: Arc::new(SeenMessages::default()),
+            nonce_verdicts: Arc::default(),
+            check_nonces: true,
+        }
+    }
+
+    async fn message(identifier: &str) -> ReceivedMessage<TestPayload> {
+        let content_topic =
+            build_content_topics("test-radio", 0, &[identifier.to_string()]).remove(0);
+        let message = GraphcastMessage::build(
+            &Wallet::new(&mut thread_rng()),
+            &content_topic,
+            identifier.to_string(),
+            Some(TestPayload {
+                content: String::from("Ping"),
+            }),
+            NetworkName::from_string("goerli"),
+            0,
+            String::from("0xblahh"),
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        )
+        .await
+        .unwrap();
+        ReceivedMessage::new(message, content_topic)
+    }
+
+    #[tokio::test]
+    async fn test_stage_order() {
+        let runs = Arc::new(std::sync::Mutex::new(vec![]));
+        let stage = |name, fail| Stage {
+            name,
+            fail,
+            runs: runs.clone(),
+        };
+        let pipeline = ValidationPipeline::empty()
+            .with(stage("second", false))
+            .with(stage("third", false))
+            .insert(0, stage("first", false))
+            .insert(10, stage("fourth", false));
+        assert_eq!(pipeline.len(), 4);
+        let context = validation_context();
+        pipeline
+            .validate(&message("Qm").await, &context)
+            .await
+            .unwrap();
+        assert_eq!(
+            *runs.lock().unwrap(),
+            vec!["first", "second", "third", "fourth"]
+        );
+
+        // Stops at the first failure
+        runs.lock().unwrap().clear();
+        let pipeline = ValidationPipeline::empty()
+            .with(stage("first", false))
+            .with(stage("second", true))
+            .with(stage("third", false));
+        assert!(matches!(
+            pipeline.validate(&message("Qm").await, &context).await,
+            Err(BuildMessageError::TypeCast(reason)) if reason == "second rejected Qm"
+        ));
+        assert_eq!(*runs.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_check_runs_last() {
+        let runs = Arc::new(std::sync::Mutex::new(vec![]));
+        let pipeline = ValidationPipeline::empty()
+            .with(NonceValidator)
+            .with(Stage {
+                name: "appended",
+                fail: true,
+                runs: runs.clone(),
+            })
+            .insert(
+                5,
+                Stage {
+                    name: "inserted",
+                    fail: false,
+                    runs: runs.clone(),
+                },
+            );
+        let context = validation_context();
+        assert!(pipeline
+            .validate(&message("Qm").await, &context)
+            .await
+            .is_err());
+        assert_eq!(*runs.lock().unwrap(), vec!["appended", "inserted"]);
+        // The rejected message did not reach the nonce check
+        assert!(context.nonces.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nonce_verdicts_shared() {
+        let verdicts = NonceVerdicts::default();
+        let nonces = Arc::default();
+        let message = message("Qm").await;
+        let first = verdicts.check(&message, &nonces).await;
+        assert!(matches!(
+            first,
+            Err(BuildMessageError::Validation(
+                ValidationError::FirstSeenTopic { .. }
+            ))
+        ));
+        // Copies of the message get the outcome of the first check
+        assert!(matches!(
+            verdicts.check(&message, &nonces).await,
+            Err(BuildMessageError::Validation(
+                ValidationError::FirstSeenTopic { .. }
+            ))
+        ));
+        assert_eq!(nonces.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_verdicts_eviction() {
+        let verdicts = NonceVerdicts::new(1);
+        let nonces = Arc::default();
+        let first = message("Qm1").await;
+        let second = message("Qm2").await;
+        assert!(verdicts.check(&first, &nonces).await.is_err());
+        assert!(verdicts.check(&second, &nonces).await.is_err());
+        // The first outcome was evicted, the message is checked again against its own nonce
+        assert!(verdicts.check(&first, &nonces).await.is_ok());
+    }
+
+    /// Radio specific check on the decoded payload
+    struct RejectContent(&'static str);
+
+    #[async_trait]
+    impl MessageValidator<LoopbackPayload> for RejectContent {
+        async fn validate(
+            &self,
+            received: &ReceivedMessage<LoopbackPayload>,
+            _context: &ValidationContext,
+        ) -> Result<(), BuildMessageError> {
+            match &received.message.payload {
+                Some(payload) if payload.content == self.0 => {
+                    Err(BuildMessageError::TypeCast(format!("Rejected {}", self.0)))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_validation_pipeline() {
+        let hub = LoopbackHub::new();
+        let subtopics = vec!["Qmloopback".to_string()];
+        let sender = GraphcastAgent::with_transport(
+            test_config(&wallet_key(1), subtopics.clone()),
+            hub.transport(),
+        )
+        .await
+        .unwrap();
+        let mut receiver =
+            GraphcastAgent::with_transport(test_config(&wallet_key(2), subtopics), hub.transport())
+                .await
+                .unwrap();
+        assert_eq!(receiver.validation_pipeline::<LoopbackPayload>().len(), 5);
+        // The block hash of the loopback messages cannot be checked against a graph node
+        receiver.set_validation_pipeline(
+            ValidationPipeline::empty()
+                .with(TimeValidator)
+                .with(RejectContent("Drop"))
+                .with(NonceValidator),
+        );
+        let messages = receiver.subscribe::<LoopbackPayload>();
+        pin_mut!(messages);
+
+        for content in ["First", "Drop", "Second"] {
+            send(&sender, "Qmloopback", LoopbackPayload::new(content)).await;
+        }
+
+        // The first message of a sender only records its nonce
+        assert!(matches!(
+            next(&mut messages).await,
+            Err(WakuHandlingError::Validation(
+                ValidationError::FirstSeenTopic { .. }
+            ))
+        ));
+        assert!(matches!(
+            next(&mut messages).await,
+            Err(WakuHandlingError::InvalidMessage(e)) if e.contains("Rejected Drop")
+        ));
+        let valid = next(&mut messages).await.unwrap();
+        assert_eq!(valid.message.payload.unwrap().content, "Second");
+    }
+}
@@ -1,23 +1,41 @@
 use prost::Message;
-use std::{borrow::Cow, env, num::ParseIntError, sync::Arc};
-use std::{collections::HashSet, time::Duration};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, RwLock,
+};
+use std::time::Duration;
+use std::{borrow::Cow, num::ParseIntError};
 use std::{net::IpAddr, str::FromStr};
-use tokio::sync::Mutex as AsyncMutex;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 use url::ParseError;
 use waku::{
-    waku_dns_discovery, waku_new, ContentFilter, DnsInfo, Encoding, FilterSubscription,
-    GossipSubParams, Multiaddr, ProtocolId, Running, SecretKey, Signal, WakuContentTopic,
-    WakuLogLevel, WakuNodeConfig, WakuNodeHandle, WakuPeerData, WakuPubSubTopic,
+    waku_dns_discovery, waku_new, waku_set_event_callback, ContentFilter, DnsInfo, Encoding,
+    FilterSubscription, Multiaddr, PagingOptions, PeerId, ProtocolId, Running, SecretKey, Signal,
+    StoreQuery, WakuContentTopic, WakuMessage, WakuNodeConfig, WakuNodeHandle, WakuPeerData,
+    WakuPubSubTopic,
 };
 
-use super::GraphcastAgent;
+use super::{
+    history::{
+        StoreConfig, TimeRange, DEFAULT_HISTORY_PAGE_SIZE, DEFAULT_STORE_QUERY_TIMEOUT,
+        MAX_HISTORY_PAGES,
+    },
+    node::{NodeRole, NodeServices, WakuOptions},
+    transport::{GraphcastTransport, MessageHandler, PeerInfo, SendReport, TransportMessage},
+    validation::ValidationPipeline,
+    GraphcastAgent,
+};
 use crate::{
     app_name, cf_nameserver, discovery_url,
-    graphcast_agent::message_typing::{self, check_message_validity, GraphcastMessage},
+    graphcast_agent::message_typing::{
+        self, BuildMessageError, ReceivedMessage, ValidationContext, ValidationError,
+    },
     graphql::QueryError,
 };
 
+/// Version of the Graphcast protocol in the pubsub topic, independent of the crate version
+/// and of the radio versions in content topics. Agents on different protocol versions do not
+/// exchange messages
 pub const SDK_VERSION: &str = "0";
 
 /// Get pubsub topic based on recommendations from https://rfc.vac.dev/spec/23/
@@ -50,6 +68,22 @@ pub fn build_content_topics(
     .to_vec()
 }
 
+/// Copies of the content topics for each radio version, in the order of `radio_versions`
+pub fn versioned_content_topics(
+    content_topics: &[WakuContentTopic],
+    radio_versions: &[u32],
+) -> Vec<WakuContentTopic> {
+    radio_versions
+        .iter()
+        .flat_map(|&version| {
+            content_topics.iter().map(move |topic| WakuContentTopic {
+                version: version as usize,
+                ..topic.clone()
+            })
+        })
+        .collect()
+}
+
 /// Makes a filter subscription from content topics and optionally pubsub topic
 /// Strictly use the first of pubsub topics as we assume radios only listen to one network (pubsub topic) at a time
 pub fn content_filter_subscription(
@@ -75,7 +109,7 @@ pub fn relay_subscribe(
 }
 
 /// Make filter subscription requests to all peers except for ourselves
-/// Return subscription results for each peer
+/// Return subscription results for each peer, fails if no peer accepted the subscription
 pub fn filter_peer_subscriptions(
     node_handle: &WakuNodeHandle<Running>,
     graphcast_topic: &WakuPubSubTopic,
@@ -87,34 +121,44 @@ pub fn filter_peer_subscriptions(
         peers = tracing::field::debug(&subscription),
         "Subscribe to topics"
     );
-    let filter_subscribe_result: Vec<String> = node_handle
+    let local_id = node_handle
+        .peer_id()
+        .map_err(WakuHandlingError::PeerInfoError)?;
+    let peers: Vec<PeerId> = node_handle
         .peers()
         .map_err(WakuHandlingError::RetrievePeersError)?
         .iter()
-        .filter(|&peer| {
-            // Filter out local peer_id to prevent self dial
-            peer.peer_id().as_str()
-                != node_handle
-                    .peer_id()
-                    .expect("Failed to find local node's peer id")
-                    .as_str()
-        })
-        .map(|peer: &WakuPeerData| {
-            // subscribe to all other peers
-            let filter_res = node_handle.filter_subscribe(
-                &subscription,
-                peer.peer_id().clone(),
-                Duration::new(6000, 0),
-            );
-            match filter_res {
-                Ok(_) => format!(
-                    "Success filter subcription request made to peer {}",
-                    peer.peer_id(),
-                ),
-                Err(e) => format!("Filter subcription request failed for peer {e}"),
+        // Filter out local peer_id to prevent self dial
+        .filter(|&peer| peer.peer_id().as_str() != local_id.as_str())
+        .map(|peer: &WakuPeerData| peer.peer_id().clone())
+        .collect();
+    subscribe_peers(&peers, |peer_id| {
+        node_handle.filter_subscribe(&subscription, peer_id.clone(), Duration::new(6000, 0))
+    })
+}
+
+/// Make a subscription request to each peer, returns the outcome for each peer or an error
+/// if none of them accepted the subscription
+fn subscribe_peers(
+    peers: &[PeerId],
+    mut subscribe: impl FnMut(&PeerId) -> Result<(), String>,
+) -> Result<Vec<String>, WakuHandlingError> {
+    let mut accepted = 0;
+    let filter_subscribe_result: Vec<String> = peers
+        .iter()
+        .map(|peer_id| match subscribe(peer_id) {
+            Ok(()) => {
+                accepted += 1;
+                format!("Success filter subcription request made to peer {peer_id}")
             }
+            Err(e) => format!("Filter subcription request failed for peer {peer_id}: {e}"),
         })
         .collect();
+    if accepted == 0 {
+        return Err(WakuHandlingError::ContentTopicsError(format!(
+            "No peer accepted the filter subscription: {filter_subscribe_result:?}"
+        )));
+    }
     info!(
         peers = tracing::field::debug(&filter_subscribe_result),
         "Subscription connections established",
@@ -148,60 +192,49 @@ pub fn unsubscribe_peer(
 /// Preferrably also provide advertise_addr and Secp256k1 private key in Hex format (0x123...abc).
 ///
 /// For light nodes, config with relay disabled and filter enabled. These node will route all messages but only pull data for messages matching the subscribed content topics.
+#[allow(clippy::too_many_arguments)]
 fn node_config(
     host: Option<&str>,
     port: usize,
     ad_addr: Option<Multiaddr>,
     key: Option<SecretKey>,
-    filter_protocol: Option<bool>,
+    role: NodeRole,
+    services: &NodeServices,
     discv5_nodes: Vec<String>,
     discv5_port: Option<u16>,
+    store: &StoreConfig,
+    options: &WakuOptions,
 ) -> Option<WakuNodeConfig> {
-    let log_level = match env::var("WAKU_LOG_LEVEL") {
-        Ok(level) => match level.to_uppercase().as_str() {
-            "DEBUG" => WakuLogLevel::Debug,
-            "INFO" => WakuLogLevel::Info,
-            "WARN" => WakuLogLevel::Warn,
-            "ERROR" => WakuLogLevel::Error,
-            "FATAL" => WakuLogLevel::Fatal,
-            "PANIC" => WakuLogLevel::Panic,
-            _ => WakuLogLevel::Warn,
-        },
-        Err(_) => WakuLogLevel::Error,
-    };
-
-    let gossipsub_params = GossipSubParams {
-        seen_messages_ttl_seconds: Some(1800),
-        history_length: Some(100_000),
-        ..Default::default()
-    };
-
-    let relay = filter_protocol.map(|b| !b);
+    let relay = role.relay();
+    // Light nodes use filter as clients, relay nodes only enable it to serve light nodes
+    let filter = !relay || services.filter;
     debug!(
-        "protocols: relay {:#?}, filter {:#?}\ndiscv5_nodes: {:#?}",
-        relay, filter_protocol, discv5_nodes
+        "role: {:#?}, protocols: relay {:#?}, filter {:#?}, store {:#?}\ndiscv5_nodes: {:#?}",
+        role, relay, filter, services.store, discv5_nodes
     );
-    let discv5 = Some(discv5_nodes.is_empty());
+    let discv5 = Some(options.discv5.unwrap_or(discv5_nodes.is_empty()));
 
     Some(WakuNodeConfig {
         host: host.and_then(|h| IpAddr::from_str(h).ok()),
         port: Some(port),
         advertise_addr: ad_addr, // Fill this for boot nodes
         node_key: key,
-        keep_alive_interval: None,
-        relay,                         // Default true - will receive all msg on relay
-        min_peers_to_publish: Some(0), // Default 0
-        filter: filter_protocol,       // Default false
-        log_level: Some(log_level),
-        relay_topics: [].to_vec(),
+        keep_alive_interval: options
+            .keep_alive_interval
+            .map(|interval| interval.as_secs() as usize),
+        relay: Some(relay), // Default true - will receive all msg on relay
+        min_peers_to_publish: Some(options.min_peers_to_publish), // Default 0
+        filter: Some(filter), // Default false
+        log_level: Some(options.log_level.into()),
+        relay_topics: options.relay_topics.clone(),
         discv5,
         discv5_bootstrap_nodes: discv5_nodes,
         discv5_udp_port: discv5_port, // Default 9000
-        store: None,
-        database_url: None,
-        store_retention_max_messages: None,
-        store_retention_max_seconds: None,
-        gossipsub_params: Some(gossipsub_params),
+        store: Some(services.store),
+        database_url: store.database_url.clone(),
+        store_retention_max_messages: store.retention_max_messages,
+        store_retention_max_seconds: store.retention_max_seconds,
+        gossipsub_params: Some(options.gossipsub.clone()),
     })
 }
 
@@ -260,12 +293,14 @@ pub fn get_dns_nodes(pubsub_topic: &WakuPubSubTopic) -> Vec<DnsInfo> {
     }
 }
 
-/// Connect to peers from a list of multiaddresses for a specific protocol
+/// Connect to peers from a list of multiaddresses for a specific protocol, returns the
+/// addresses that failed. Peers added but not connected stay known to the node, so the
+/// peer manager retries them
 pub fn connect_multiaddresses(
     nodes: Vec<Multiaddr>,
     node_handle: &WakuNodeHandle<Running>,
     protocol_id: ProtocolId,
-) {
+) -> Vec<Multiaddr> {
     let (connected_peers, unconnected_peers): (Vec<_>, Vec<_>) = nodes
         .clone()
         .into_iter()
@@ -287,11 +322,12 @@ pub fn connect_multiaddresses(
         "Connected to peers"
     );
     if !unconnected_peers.is_empty() {
-        debug!(
-            peers = tracing::field::debug(unconnected_peers),
+        warn!(
+            peers = tracing::field::debug(&unconnected_peers),
             "Peers failed to connect"
         );
     }
+    unconnected_peers
 }
 
 //TODO: Topic discovery DNS and Discv5
@@ -304,9 +340,12 @@ pub fn setup_node_handle(
     port: Option<&str>,
     advertised_addr: Option<Multiaddr>,
     node_key: Option<SecretKey>,
-    filter_protocol: Option<bool>,
+    role: NodeRole,
+    services: &NodeServices,
     discv5_enrs: Vec<String>,
     discv5_port: Option<u16>,
+    store: &StoreConfig,
+    options: &WakuOptions,
 ) -> Result<WakuNodeHandle<Running>, WakuHandlingError> {
     let port = port
         .unwrap_or("60000")
@@ -315,20 +354,22 @@ pub fn setup_node_handle(
 
     let mut discv5_nodes: Vec<String> = get_dns_nodes(pubsub_topic)
         .into_iter()
-        .filter(|d| d.enr.is_some())
-        .map(|d| d.enr.unwrap().to_base64())
+        .filter_map(|d| d.enr.map(|enr| enr.to_base64()))
         .collect::<Vec<String>>();
     discv5_nodes.extend(discv5_enrs.clone());
-    match env::var("WAKU_NODE_BOOT").ok() {
-        Some(x) if x == *"boot" => boot_node_handle(
+    match role {
+        NodeRole::Boot => boot_node_handle(
+            boot_node_addresses,
             pubsub_topic,
             host,
             port,
             advertised_addr,
             node_key,
-            filter_protocol,
+            services,
             discv5_enrs,
             discv5_port,
+            store,
+            options,
         ),
         _ => {
             //TODO: Use DNS nodes as Discv5 Discovery, when get_dns_nodes return enr information as well
@@ -337,9 +378,12 @@ pub fn setup_node_handle(
                 port,
                 advertised_addr,
                 node_key,
-                filter_protocol,
+                role,
+                services,
                 discv5_nodes,
                 discv5_port,
+                store,
+                options,
             );
 
             let node_handle = waku_new(node_config)
@@ -348,7 +392,7 @@ pub fn setup_node_handle(
                 .map_err(WakuHandlingError::CreateNodeError)?;
             let nodes = gather_nodes(boot_node_addresses, pubsub_topic);
             // Connect to peers on the filter protocol or relay protocol
-            if let Some(false) = filter_protocol {
+            if role.relay() {
                 connect_multiaddresses(nodes, &node_handle, ProtocolId::Relay);
             } else {
                 connect_multiaddresses(nodes, &node_handle, ProtocolId::Filter);
@@ -364,25 +408,32 @@ pub fn setup_node_handle(
     }
 }
 
+/// Set up a boot node, relaying the pubsub topic and peering with the other boot nodes
 #[allow(clippy::too_many_arguments)]
 pub fn boot_node_handle(
+    boot_node_addresses: Vec<Multiaddr>,
     pubsub_topic: &WakuPubSubTopic,
     host: Option<&str>,
     port: usize,
     advertised_addr: Option<Multiaddr>,
     node_key: Option<SecretKey>,
-    filter: Option<bool>,
+    services: &NodeServices,
     discv5_enrs: Vec<String>,
     discv5_port: Option<u16>,
+    store: &StoreConfig,
+    options: &WakuOptions,
 ) -> Result<WakuNodeHandle<Running>, WakuHandlingError> {
     let boot_node_config = node_config(
         host,
         port,
         advertised_addr,
         node_key,
-        filter,
+        NodeRole::Boot,
+        services,
         discv5_enrs,
         discv5_port,
+        store,
+        options,
     );
     let boot_node_handle = waku_new(boot_node_config)
         .map_err(WakuHandlingError::CreateNodeError)?
@@ -392,22 +443,21 @@ pub fn boot_node_handle(
     // Relay node subscribe pubsub_topic of graphcast
     boot_node_handle
         .relay_subscribe(Some(pubsub_topic.clone()))
-        .expect("Could not subscribe to the topic");
+        .map_err(WakuHandlingError::ContentTopicsError)?;
+
+    connect_multiaddresses(boot_node_addresses, &boot_node_handle, ProtocolId::Relay);
 
     let boot_node_id = boot_node_handle.peer_id().map_err(|_e| {
         WakuHandlingError::PeerInfoError(
             "Could not get node id from local node instance".to_string(),
         )
     })?;
-    let boot_node_multiaddress = format!(
-        "/ip4/{}/tcp/{}/p2p/{}",
-        host.unwrap_or("0.0.0.0"),
-        port,
-        boot_node_id
-    );
-    debug!(
+    let listen_addresses = boot_node_handle
+        .listen_addresses()
+        .map_err(WakuHandlingError::PeerInfoError)?;
+    info!(
         boot_node_id = tracing::field::debug(&boot_node_id),
-        boot_node_address = tracing::field::debug(&boot_node_multiaddress),
+        listen_addresses = tracing::field::debug(&listen_addresses),
         "Boot node initialized"
     );
     Ok(boot_node_handle)
@@ -421,45 +471,32 @@ pub async fn handle_signal<
         + Clone
         + 'static
         + async_graphql::OutputType,
+    N: GraphcastTransport,
 >(
     signal: Signal,
-    graphcast_agent: &GraphcastAgent,
-) -> Result<GraphcastMessage<T>, WakuHandlingError> {
-    // Do not accept messages that were already received or sent by self
-    let old_message_ids: &Arc<AsyncMutex<HashSet<String>>> = &graphcast_agent.old_message_ids;
-    let mut ids = old_message_ids.lock().await;
+    graphcast_agent: &GraphcastAgent<N>,
+) -> Result<ReceivedMessage<T>, WakuHandlingError> {
     match signal.event() {
         waku::Event::WakuMessage(event) => {
-            match <message_typing::GraphcastMessage<T> as Message>::decode(
-                event.waku_message().payload(),
-            ) {
-                Ok(graphcast_message) => {
-                    trace!(
-                        id = event.message_id(),
-                        message = tracing::field::debug(&graphcast_message),
-                        "Received message"
-                    );
-                    if ids.contains(event.message_id()) {
-                        return Err(WakuHandlingError::InvalidMessage(
-                            "Skip repeated message".to_string(),
-                        ));
-                    };
-                    // Check for content topic and repetitive message id
-                    ids.insert(event.message_id().clone());
-                    check_message_validity(
-                        graphcast_message,
-                        &graphcast_agent.nonces,
-                        graphcast_agent.callbook.clone(),
-                        graphcast_agent.graphcast_identity.graphcast_id.clone(),
-                        graphcast_agent.id_validation.clone(),
-                    )
-                    .await
-                    .map_err(|e| WakuHandlingError::InvalidMessage(e.to_string()))
+            let message = TransportMessage::new(
+                event.message_id().clone(),
+                event.pubsub_topic().clone(),
+                event.waku_message().clone(),
+            );
+            // Do not accept messages that were already received or sent by self
+            let context = graphcast_agent.validation_context();
+            if context.seen_messages.check_and_insert(&message.message_id) {
+                return Err(ValidationError::Duplicate {
+                    message_id: message.message_id,
                 }
-                Err(e) => Err(WakuHandlingError::InvalidMessage(format!(
-                    "Waku message not interpretated as a Graphcast message\nError occurred: {e:?}"
-                ))),
+                .into());
             }
+            handle_message(
+                message,
+                &context,
+                &graphcast_agent.validation_pipeline::<T>(),
+            )
+            .await
         }
 
         waku::Event::Unrecognized(data) => Err(WakuHandlingError::InvalidMessage(format!(
@@ -472,11 +509,55 @@ pub async fn handle_signal<
     }
 }
 
+/// Decode a message received from the transport and validate it through the pipeline.
+/// Duplicates are dropped by the caller beforehand, such as the agent's inbound handler
+/// before the message is dispatched to its subscribers
+pub async fn handle_message<
+    T: Message
+        + ethers::types::transaction::eip712::Eip712
+        + Default
+        + Clone
+        + 'static
+        + async_graphql::OutputType,
+>(
+    message: TransportMessage,
+    context: &ValidationContext,
+    pipeline: &ValidationPipeline<T>,
+) -> Result<ReceivedMessage<T>, WakuHandlingError> {
+    match <message_typing::GraphcastMessage<T> as Message>::decode(message.waku_message.payload()) {
+        Ok(graphcast_message) => {
+            trace!(
+                id = message.message_id,
+                message = tracing::field::debug(&graphcast_message),
+                "Received message"
+            );
+            // Envelope signatures are verified against the radio of the arrival content topic
+            let graphcast_message =
+                ReceivedMessage::new(graphcast_message, message.content_topic().clone());
+            pipeline
+                .validate(&graphcast_message, context)
+                .await
+                .map_err(|e| match e {
+                    BuildMessageError::Validation(e) => WakuHandlingError::Validation(e),
+                    e => WakuHandlingError::InvalidMessage(e.to_string()),
+                })?;
+            trace!(
+                message = tracing::field::debug(&graphcast_message),
+                "Valid message!"
+            );
+            Ok(graphcast_message)
+        }
+        Err(e) => Err(WakuHandlingError::InvalidMessage(format!(
+            "Waku message not interpretated as a Graphcast message\nError occurred: {e:?}"
+        ))),
+    }
+}
+
 /// Check for peer connectivity, try to reconnect if there are disconnected peers
 pub fn network_check(node_handle: &WakuNodeHandle<Running>) -> Result<(), WakuHandlingError> {
     let binding = node_handle
         .peer_id()
-        .expect("Failed to get local node's peer id");
+        .map_err(WakuHandlingError::PeerInfoError)?;
     let local_id = binding.as_str();
 
     let peers = node_handle.peers();
@@ -505,6 +586,396 @@ pub fn network_check(node_handle: &WakuNodeHandle<Running>) -> Result<(), WakuHa
     Ok(())
 }
 
+/// All messages of a store peer matching the query, following the paging cursor up to
+/// `MAX_HISTORY_PAGES` pages
+fn store_history(
+    node_handle: &WakuNodeHandle<Running>,
+    peer_id: &PeerId,
+    pubsub_topic: &WakuPubSubTopic,
+    content_topics: &[WakuContentTopic],
+    time_range: TimeRange,
+) -> Result<Vec<WakuMessage>, String> {
+    let mut query = StoreQuery {
+        pubsub_topic: Some(pubsub_topic.clone()),
+        content_filters: content_topics
+            .iter()
+            .cloned()
+            .map(ContentFilter::new)
+            .collect(),
+        start_time: time_range.start.map(|start| start.max(0) as usize),
+        end_time: time_range.end.map(|end| end.max(0) as usize),
+        paging_options: Some(PagingOptions {
+            page_size: DEFAULT_HISTORY_PAGE_SIZE,
+            cursor: None,
+            forward: true,
+        }),
+    };
+    let mut messages = vec![];
+    for _ in 0..MAX_HISTORY_PAGES {
+        let response =
+            node_handle.store_query(&query, peer_id, Some(DEFAULT_STORE_QUERY_TIMEOUT))?;
+        let page = response.messages();
+        messages.extend_from_slice(page);
+        match response.paging_options() {
+            Some(paging) if paging.cursor.is_some() && !page.is_empty() => {
+                query.paging_options = Some(paging.clone())
+            }
+            _ => break,
+        }
+    }
+    trace!(
+        peer = peer_id,
+        messages = messages.len(),
+        "Queried history from store peer"
+    );
+    Ok(messages)
+}
+
+/// Source of `WakuTransport` ids
+static NEXT_TRANSPORT_ID: AtomicU64 = AtomicU64::new(0);
+/// Transport whose message handler is set as the process-global Waku event callback
+static EVENT_CALLBACK_OWNER: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Graphcast transport backed by a running Waku node.
+///
+/// The Waku library runs a single node per process and delivers its events through a single
+/// process-global callback, so a process runs at most one Waku agent at a time. A transport
+/// only clears the callback it set, a stopped agent dropped after a new one started does not
+/// stop the new agent's inbound messages.
+pub struct WakuTransport {
+    /// Identifies the transport owning the event callback
+    id: u64,
+    /// Running node, taken out once the transport is stopped
+    node_handle: RwLock<Option<WakuNodeHandle<Running>>>,
+    filter_protocol: Option<bool>,
+    /// Peer tried first for lightpush publishing
+    service_peer: Option<String>,
+    /// Peer tried first for history queries
+    store_peer: Option<String>,
+}
+
+impl WakuTransport {
+    pub fn new(node_handle: WakuNodeHandle<Running>, filter_protocol: Option<bool>) -> Self {
+        WakuTransport {
+            id: NEXT_TRANSPORT_ID.fetch_add(1, Ordering::Relaxed),
+            node_handle: RwLock::new(Some(node_handle)),
+            filter_protocol,
+            service_peer: None,
+            store_peer: None,
+        }
+    }
+
+    /// Lightpush messages through `peer_id` while it accepts them
+    pub fn with_service_peer(mut self, peer_id: String) -> Self {
+        self.service_peer = Some(peer_id);
+        self
+    }
+
+    /// Query history from `peer_id` while it answers
+    pub fn with_store_peer(mut self, peer_id: String) -> Self {
+        self.store_peer = Some(peer_id);
+        self
+    }
+
+    /// Run `f` with the underlying Waku node handle, fails once the node is stopped
+    pub fn with_node_handle<R>(
+        &self,
+        f: impl FnOnce(&WakuNodeHandle<Running>) -> Result<R, WakuHandlingError>,
+    ) -> Result<R, WakuHandlingError> {
+        match self.node_handle.read().unwrap().as_ref() {
+            Some(node_handle) => f(node_handle),
+            None => Err(WakuHandlingError::NodeStopped),
+        }
+    }
+
+    /// Relay protocol is used for subscriptions only if filter protocol is explicitly disabled
+    pub fn relay_enabled(&self) -> bool {
+        self.filter_protocol == Some(false)
+    }
+}
+
+impl GraphcastTransport for WakuTransport {
+    fn local_peer_id(&self) -> Result<String, WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            node_handle
+                .peer_id()
+                .map_err(WakuHandlingError::PeerInfoError)
+        })
+    }
+
+    fn peers(&self) -> Result<Vec<PeerInfo>, WakuHandlingError> {
+        let local_id = self.local_peer_id()?;
+        self.with_node_handle(|node_handle| {
+            Ok(node_handle
+                .peers()
+                .map_err(WakuHandlingError::RetrievePeersError)?
+                .iter()
+                .filter(|&peer| peer.peer_id().as_str() != local_id)
+                .map(|peer: &WakuPeerData| PeerInfo {
+                    peer_id: peer.peer_id().to_string(),
+                    addresses: peer.addresses().to_vec(),
+                    connected: peer.connected(),
+                })
+                .collect())
+        })
+    }
+
+    fn peer_count(&self) -> Result<usize, WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            node_handle
+                .peer_count()
+                .map_err(WakuHandlingError::RetrievePeersError)
+        })
+    }
+
+    fn publish(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<String, WakuHandlingError> {
+        self.publish_report(pubsub_topic, message)?.into_result()
+    }
+
+    /// Relay nodes publish to their gossipsub mesh. Light nodes lightpush to a single service
+    /// peer, the configured one first, moving on to the next lightpush peer on failure
+    fn publish_report(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        message: &WakuMessage,
+    ) -> Result<SendReport, WakuHandlingError> {
+        let local_id = self.local_peer_id()?;
+        self.with_node_handle(|node_handle| {
+            let mut report = SendReport::default();
+            if self.relay_enabled() {
+                match node_handle.relay_publish_message(message, Some(pubsub_topic.clone()), None) {
+                    Ok(id) => report.accept(local_id, id),
+                    Err(e) => report.fail(local_id, e),
+                }
+                return Ok(report);
+            }
+
+            let mut service_peers: Vec<String> = node_handle
+                .peers()
+                .map_err(WakuHandlingError::RetrievePeersError)?
+                .iter()
+                .filter(|&peer| {
+                    peer.peer_id().as_str() != local_id
+                        && peer.connected()
+                        && peer
+                            .protocols()
+                            .iter()
+                            .any(|protocol| matches!(protocol, ProtocolId::Lightpush))
+                })
+                .map(|peer| peer.peer_id().to_string())
+                .collect();
+            if let Some(preferred) = &self.service_peer {
+                service_peers.sort_by_key(|peer_id| peer_id != preferred);
+            }
+            for peer_id in service_peers {
+                match node_handle.lightpush_publish(
+                    message,
+                    Some(pubsub_topic.clone()),
+                    peer_id.clone(),
+                    None,
+                ) {
+                    Ok(id) => {
+                        report.accept(peer_id, id);
+                        break;
+                    }
+                    Err(e) => {
+                        debug!(
+                            peer = peer_id,
+                            error = tracing::field::debug(&e),
+                            "Failed to lightpush message to service peer"
+                        );
+                        report.fail(peer_id, e);
+                    }
+                }
+            }
+            Ok(report)
+        })
+    }
+
+    fn subscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            if self.relay_enabled() {
+                debug!("Filter protocol disabled, subscribe to pubsub topic on the relay protocol");
+                relay_subscribe(node_handle, pubsub_topic)
+            } else {
+                debug!("Filter protocol enabled, filter subscriptions with peers");
+                filter_peer_subscriptions(node_handle, pubsub_topic, content_topics).map(|_| ())
+            }
+        })
+    }
+
+    fn unsubscribe(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+    ) -> Result<(), WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            if self.relay_enabled() {
+                // Relay nodes receive every message on the pubsub topic, nothing to unsubscribe per content topic
+                Ok(())
+            } else {
+                unsubscribe_peer(node_handle, pubsub_topic, content_topics)
+            }
+        })
+    }
+
+    /// Pages through the history of a store peer, the configured one first, moving on to
+    /// the next store peer on failure. Messages are given ids from their content hash
+    fn query_history(
+        &self,
+        pubsub_topic: &WakuPubSubTopic,
+        content_topics: &[WakuContentTopic],
+        time_range: TimeRange,
+    ) -> Result<Vec<TransportMessage>, WakuHandlingError> {
+        let local_id = self.local_peer_id()?;
+        self.with_node_handle(|node_handle| {
+            let mut store_peers: Vec<String> = node_handle
+                .peers()
+                .map_err(WakuHandlingError::RetrievePeersError)?
+                .iter()
+                .filter(|&peer| {
+                    peer.peer_id().as_str() != local_id
+                        && peer.connected()
+                        && peer
+                            .protocols()
+                            .iter()
+                            .any(|protocol| matches!(protocol, ProtocolId::Store))
+                })
+                .map(|peer| peer.peer_id().to_string())
+                .collect();
+            if let Some(preferred) = &self.store_peer {
+                store_peers.sort_by_key(|peer_id| peer_id != preferred);
+            }
+
+            let mut failures = vec![];
+            for peer_id in store_peers {
+                match store_history(
+                    node_handle,
+                    &peer_id,
+                    pubsub_topic,
+                    content_topics,
+                    time_range,
+                ) {
+                    Ok(messages) => {
+                        return Ok(messages
+                            .into_iter()
+                            .filter(|message| time_range.contains(message.timestamp() as i64))
+                            .map(|message| TransportMessage::hashed(pubsub_topic.clone(), message))
+                            .collect())
+                    }
+                    Err(e) => {
+                        debug!(
+                            peer = peer_id,
+                            error = tracing::field::debug(&e),
+                            "Failed to query history from store peer"
+                        );
+                        failures.push(format!("{peer_id}: {e}"));
+                    }
+                }
+            }
+            Err(WakuHandlingError::StoreQuery(if failures.is_empty() {
+                "No connected store peers".to_string()
+            } else {
+                failures.join("; ")
+            }))
+        })
+    }
+
+    fn connect_peer(&self, peer_id: &str) -> Result<(), WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            node_handle
+                .connect_peer_with_id(&peer_id.to_string(), None)
+                .map_err(WakuHandlingError::ConnectPeer)
+        })
+    }
+
+    fn disconnect_peer(&self, peer_id: &str) -> Result<(), WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            node_handle
+                .disconnect_peer_with_id(&peer_id.to_string())
+                .map_err(WakuHandlingError::ConnectPeer)
+        })
+    }
+
+    fn listen_addresses(&self) -> Result<Vec<Multiaddr>, WakuHandlingError> {
+        self.with_node_handle(|node_handle| {
+            node_handle
+                .listen_addresses()
+                .map_err(WakuHandlingError::PeerInfoError)
+        })
+    }
+
+    // The Waku bindings do not expose the local node's ENR, `enr` keeps the default
+
+    fn set_message_handler(&self, handler: MessageHandler) {
+        let mut owner = EVENT_CALLBACK_OWNER.lock().unwrap();
+        if owner.map_or(false, |id| id != self.id) {
+            warn!("Replacing the Waku event callback of another agent, a process runs a single Waku agent");
+        }
+        *owner = Some(self.id);
+        waku_set_event_callback(move |signal: Signal| match signal.event() {
+            waku::Event::WakuMessage(event) => handler(TransportMessage::new(
+                event.message_id().clone(),
+                event.pubsub_topic().clone(),
+                event.waku_message().clone(),
+            )),
+            _ => trace!(
+                signal = tracing::field::debug(serde_json::to_string(&signal)),
+                "Ignore non-message signal"
+            ),
+        });
+    }
+
+    fn clear_message_handler(&self) {
+        let mut owner = EVENT_CALLBACK_OWNER.lock().unwrap();
+        if *owner != Some(self.id) {
+            return;
+        }
+        *owner = None;
+        // The event callback is process-global and cannot be removed, replace it with a no-op
+        waku_set_event_callback(|_: Signal| {});
+    }
+
+    fn network_check(&self) -> Result<(), WakuHandlingError> {
+        self.with_node_handle(network_check)
+    }
+
+    fn stop(&self) -> Result<(), WakuHandlingError> {
+        match self.node_handle.write().unwrap().take() {
+            Some(node_handle) => {
+                node_handle
+                    .stop()
+                    .map_err(WakuHandlingError::StopNodeError)?;
+                info!("Stopped Waku node");
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Stops the node if it is still running, such as when the agent failed to start on top of it,
+/// freeing the single Waku node of the process and its ports
+impl Drop for WakuTransport {
+    fn drop(&mut self) {
+        self.clear_message_handler();
+        if let Err(e) = self.stop() {
+            warn!(
+                err = tracing::field::display(&e),
+                "Could not stop the Waku node"
+            );
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum WakuHandlingError {
     #[error(transparent)]
@@ -517,12 +988,22 @@ pub enum WakuHandlingError {
     PublishMessage(String),
     #[error("Unable to validate a message from peer: {}", .0)]
     InvalidMessage(String),
+    #[error("Invalid message from peer: {0}")]
+    Validation(#[from] ValidationError),
     #[error(transparent)]
     ParsePortError(#[from] ParseIntError),
     #[error("Unable to create waku node: {}", .0)]
     CreateNodeError(String),
     #[error("Unable to get peer information: {}", .0)]
     PeerInfoError(String),
+    #[error("Unable to change the connection to peer: {}", .0)]
+    ConnectPeer(String),
+    #[error("Unable to stop waku node: {}", .0)]
+    StopNodeError(String),
+    #[error("Waku node has been stopped")]
+    NodeStopped,
+    #[error("Unable to query message history: {}", .0)]
+    StoreQuery(String),
     #[error(transparent)]
     QueryResponseError(#[from] QueryError),
     #[error("Unknown error: {0}")]
@@ -532,6 +1013,149 @@ pub enum WakuHandlingError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::callbook::CallBook;
+    use crate::graphcast_agent::{
+        message_typing::{
+            GraphcastMessage, IdentityValidation, ENVELOPE_SIGNATURE_VERSION,
+            LEGACY_SIGNATURE_VERSION,
+        },
+        seen_messages::SeenMessages,
+        validation::{NonceValidator, SignatureValidator, TimeValidator},
+        DEFAULT_MAX_CLOCK_SKEW, DEFAULT_REPLAY_WINDOW,
+    };
+    use async_graphql::SimpleObject;
+    use ethers_contract::EthAbiType;
+    use ethers_core::types::transaction::eip712::Eip712;
+    use ethers_derive_eip712::*;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+
+    #[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, SimpleObject)]
+    #[eip712(
+        name = "Graphcast Test Radio",
+        version = "0",
+        chain_id = 1,
+        verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+    )]
+    pub struct TestPayload {
+        #[prost(string, tag = "1")]
+        pub content: String,
+    }
+
+    fn validation_context() -> ValidationContext {
+        ValidationContext {
+            nonces: Arc::default(),
+            callbook: CallBook::new(String::new(), String::new(), String::new()),
+            local_sender_id: String::from("0xlocal"),
+            id_validation: IdentityValidation::NoCheck,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            accept_legacy_signatures: true,
+            seen_messages: Arc::new(SeenMessages::default()),
+            nonce_verdicts: Arc::default(),
+            check_nonces: true,
+        }
+    }
+
+    fn transport_message(id: &str, payload: Vec<u8>) -> TransportMessage {
+        TransportMessage::new(
+            id.to_string(),
+            pubsub_topic(None),
+            WakuMessage::new(
+                payload,
+                build_content_topics("radio", 0, &["Qm".to_string()]).remove(0),
+                2,
+                0,
+                vec![],
+                true,
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_malformed_messages() {
+        let context = validation_context();
+        // Checks that do not reach remote endpoints
+        let pipeline = ValidationPipeline::<TestPayload>::empty()
+            .with(SignatureValidator)
+            .with(TimeValidator)
+            .with(NonceValidator);
+        let inputs = [
+            vec![],
+            vec![0xff; 64],
+            vec![0x0a, 0xff, 0xff],
+            b"not a protobuf message".to_vec(),
+        ];
+        for (i, bytes) in inputs.into_iter().enumerate() {
+            assert!(handle_message::<TestPayload>(
+                transport_message(&i.to_string(), bytes),
+                &context,
+                &pipeline
+            )
+            .await
+            .is_err());
+        }
+
+        let ping = GraphcastMessage::<TestPayload> {
+            identifier: String::from("Qm"),
+            payload: None,
+            nonce: chrono::Utc::now().timestamp(),
+            network: String::from("goerli"),
+            block_number: 0,
+            block_hash: String::from("0x"),
+            graph_account: String::from("0x"),
+            signature: String::from("0x"),
+            signature_version: LEGACY_SIGNATURE_VERSION,
+        };
+        assert!(matches!(
+            handle_message(
+                transport_message("legacy", ping.encode_to_vec()),
+                &context,
+                &pipeline
+            )
+            .await,
+            Err(WakuHandlingError::Validation(
+                ValidationError::MissingPayload { .. }
+            ))
+        ));
+        let ping = GraphcastMessage {
+            signature_version: ENVELOPE_SIGNATURE_VERSION,
+            ..ping
+        };
+        assert!(matches!(
+            handle_message(
+                transport_message("envelope", ping.encode_to_vec()),
+                &context,
+                &pipeline
+            )
+            .await,
+            Err(WakuHandlingError::Validation(
+                ValidationError::InvalidSignature { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_subscribe_peers() {
+        assert!(matches!(
+            subscribe_peers(&[], |_| Ok(())),
+            Err(WakuHandlingError::ContentTopicsError(_))
+        ));
+        let peers = [String::from("peer-a"), String::from("peer-b")];
+        assert!(matches!(
+            subscribe_peers(&peers, |_| Err(String::from("timeout"))),
+            Err(WakuHandlingError::ContentTopicsError(_))
+        ));
+        let results = subscribe_peers(&peers, |peer_id| {
+            if peer_id == "peer-a" {
+                Err(String::from("timeout"))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert_eq!(results.len(), 2);
+    }
 
     #[test]
     fn test_empty_topics() {
@@ -553,6 +1177,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_versioned_content_topics() {
+        let topics = build_content_topics("some-radio", 2, &["Qmyumyum".to_string()]);
+        let res = versioned_content_topics(&topics, &[2, 1]);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0], topics[0]);
+        assert_eq!(res[1].version, 1);
+        assert_eq!(res[1].content_topic_name, "Qmyumyum");
+        assert!(versioned_content_topics(&topics, &[]).is_empty());
+    }
+
     #[test]
     fn test_dns_nodefleet() {
         let pubsub_topic: WakuPubSubTopic = pubsub_topic(Some("testnet"));
@@ -0,0 +1,412 @@
+//! Shared HTTP client for the GraphQL queries.
+//!
+//! `GraphqlClient` wraps a single `reqwest::Client` with a request timeout, exponential
+//! backoff retries for transport errors and 5xx responses, and headers attached to
+//! requests by endpoint, such as gateway API keys or bearer tokens. Headers configured for an
+//! endpoint are only sent to the same scheme, host and port, under the endpoint's path.
+//!
+use graphql_client::{GraphQLQuery, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::Url;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{trace, warn};
+
+use super::QueryError;
+
+/// HTTP settings of the GraphQL client
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Timeout of a single request attempt
+    pub timeout: Duration,
+    /// Number of retries after the first attempt for transport errors and 5xx responses
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after each retry
+    pub initial_backoff: Duration,
+    /// Maximum delay between retries
+    pub max_backoff: Duration,
+    pub user_agent: String,
+    /// Headers attached to requests sent to the endpoint URL of the key, or to URLs under its
+    /// path. Prefer `header` and `bearer_token`, which validate the endpoint and mark the
+    /// values sensitive
+    pub headers: Vec<(String, HeaderMap)>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            timeout: Duration::from_secs(10),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            user_agent: format!("graphcast-sdk/{}", env!("CARGO_PKG_VERSION")),
+            headers: vec![],
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Attach a header to requests sent to `endpoint` or to URLs under its path on the same
+    /// origin. Header values are marked sensitive and are not printed in debug output
+    pub fn header(mut self, endpoint: &str, name: &str, value: &str) -> Result<Self, QueryError> {
+        Url::parse(endpoint).map_err(|e| {
+            QueryError::Other(anyhow::anyhow!("Invalid header endpoint {endpoint}: {e}"))
+        })?;
+        let name = HeaderName::from_str(name)
+            .map_err(|e| QueryError::Other(anyhow::anyhow!("Invalid header name {name}: {e}")))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|e| QueryError::Other(anyhow::anyhow!("Invalid header value: {e}")))?;
+        value.set_sensitive(true);
+        match self
+            .headers
+            .iter_mut()
+            .find(|(e, _)| e.as_str() == endpoint)
+        {
+            Some((_, headers)) => {
+                headers.insert(name, value);
+            }
+            None => {
+                let mut headers = HeaderMap::new();
+                headers.insert(name, value);
+                self.headers.push((endpoint.to_string(), headers));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Attach a bearer token to requests sent to `endpoint` or to URLs under its path
+    pub fn bearer_token(self, endpoint: &str, token: &str) -> Result<Self, QueryError> {
+        self.header(endpoint, AUTHORIZATION.as_str(), &format!("Bearer {token}"))
+    }
+
+    /// Headers for the endpoint, the most specific path wins on conflicts
+    fn headers_for(&self, endpoint: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let endpoint = match Url::parse(endpoint) {
+            Ok(endpoint) => endpoint,
+            Err(_) => return headers,
+        };
+        let mut matched: Vec<(Url, &HeaderMap)> = self
+            .headers
+            .iter()
+            .filter_map(|(prefix, h)| Url::parse(prefix).ok().map(|prefix| (prefix, h)))
+            .filter(|(prefix, _)| endpoint_matches(prefix, &endpoint))
+            .collect();
+        matched.sort_by_key(|(prefix, _)| prefix.path().trim_end_matches('/').len());
+        matched
+            .into_iter()
+            .for_each(|(_, h)| headers.extend(h.clone()));
+        headers
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Whether `endpoint` has the scheme, host and port of `prefix` and is under its path,
+/// comparing whole path segments
+fn endpoint_matches(prefix: &Url, endpoint: &Url) -> bool {
+    if prefix.scheme() != endpoint.scheme()
+        || prefix.host_str() != endpoint.host_str()
+        || prefix.port_or_known_default() != endpoint.port_or_known_default()
+    {
+        return false;
+    }
+    endpoint
+        .path()
+        .strip_prefix(prefix.path().trim_end_matches('/'))
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// GraphQL client shared by every query of a CallBook
+#[derive(Clone, Debug, Default)]
+pub struct GraphqlClient {
+    http: reqwest::Client,
+    config: Arc<HttpConfig>,
+}
+
+impl GraphqlClient {
+    pub fn new(config: HttpConfig) -> GraphqlClient {
+        GraphqlClient {
+            http: reqwest::Client::new(),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    /// Send the query to the endpoint and return the response data, if any.
+    ///
+    /// Timeouts, connection errors and 5xx responses are retried with exponential backoff,
+    /// other HTTP statuses fail right away. GraphQL errors in the response are returned as
+    /// `QueryError::IndexingError` for failed subgraphs and `QueryError::GraphQL` otherwise.
+    pub async fn query<Q: GraphQLQuery>(
+        &self,
+        endpoint: &str,
+        variables: Q::Variables,
+    ) -> Result<Option<Q::ResponseData>, QueryError> {
+        let request_body = Q::build_query(variables);
+        let headers = self.config.headers_for(endpoint);
+        let mut retry = 0;
+        let response = loop {
+            let result = self
+                .http
+                .post(endpoint)
+                .timeout(self.config.timeout)
+                .header(reqwest::header::USER_AGENT, self.config.user_agent.as_str())
+                .headers(headers.clone())
+                .json(&request_body)
+                .send()
+                .await;
+            let error = match result {
+                Ok(response) if response.status().is_server_error() => QueryError::HttpStatus {
+                    endpoint: endpoint.to_string(),
+                    status: response.status(),
+                },
+                Ok(response) if !response.status().is_success() => {
+                    return Err(QueryError::HttpStatus {
+                        endpoint: endpoint.to_string(),
+                        status: response.status(),
+                    })
+                }
+                Ok(response) => break response,
+                Err(e) if e.is_timeout() => QueryError::Timeout(endpoint.to_string()),
+                Err(e) if e.is_connect() || e.is_request() => QueryError::Transport(e),
+                Err(e) => return Err(QueryError::Transport(e)),
+            };
+            if retry >= self.config.max_retries {
+                return Err(error);
+            }
+            let backoff = self.config.backoff(retry);
+            warn!(
+                endpoint,
+                err = tracing::field::display(&error),
+                retry_in = tracing::field::debug(&backoff),
+                "Query failed, retrying"
+            );
+            tokio::time::sleep(backoff).await;
+            retry += 1;
+        };
+
+        let response_body: Response<Q::ResponseData> = response
+            .json()
+            .await
+            .map_err(|e| QueryError::ParseResponseError(e.to_string()))?;
+        if let Some(errors) = response_body.errors.as_deref().filter(|e| !e.is_empty()) {
+            trace!(
+                endpoint,
+                errors = tracing::field::debug(&errors),
+                "Query response errors"
+            );
+            let e = &errors[0];
+            if e.message == "indexing_error" {
+                return Err(QueryError::IndexingError);
+            } else {
+                return Err(QueryError::GraphQL(e.message.clone()));
+            }
+        }
+        Ok(response_body.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphql::client_graph_node::{indexing_statuses, IndexingStatuses};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve the canned (status, body) responses in order, repeating the last one,
+    /// and record the raw requests
+    async fn serve(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, Arc<AtomicUsize>, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/graphql", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let (served, recorded) = (count.clone(), requests.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buf = [0u8; 4096];
+                // Read the headers, then the body announced by content-length
+                let body_len = loop {
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        break 0;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_lowercase();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length: "))
+                            .and_then(|l| l.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        break end + 4 + length;
+                    }
+                };
+                while request.len() < body_len {
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&request).to_lowercase());
+
+                let i = served.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = responses[i.min(responses.len() - 1)];
+                let response = format!(
+                    "HTTP/1.1 {status} STATUS\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (endpoint, count, requests)
+    }
+
+    fn test_client() -> GraphqlClient {
+        GraphqlClient::new(HttpConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_retry_server_errors() {
+        let (endpoint, count, _) = serve(vec![
+            (503, "{}"),
+            (500, "{}"),
+            (200, r#"{"data":{"indexingStatuses":[]}}"#),
+        ])
+        .await;
+        let data = test_client()
+            .query::<IndexingStatuses>(&endpoint, indexing_statuses::Variables {})
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(data.indexing_statuses.is_empty());
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_query_errors() {
+        let (endpoint, count, _) = serve(vec![(401, "{}")]).await;
+        let result = test_client()
+            .query::<IndexingStatuses>(&endpoint, indexing_statuses::Variables {})
+            .await;
+        assert!(matches!(result, Err(QueryError::HttpStatus { status, .. }) if status == 401));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        let (endpoint, _, _) = serve(vec![(200, r#"{"errors":[{"message":"bad query"}]}"#)]).await;
+        let result = test_client()
+            .query::<IndexingStatuses>(&endpoint, indexing_statuses::Variables {})
+            .await;
+        assert!(matches!(result, Err(QueryError::GraphQL(message)) if message == "bad query"));
+
+        let (endpoint, count, _) = serve(vec![(503, "{}")]).await;
+        let result = test_client()
+            .query::<IndexingStatuses>(&endpoint, indexing_statuses::Variables {})
+            .await;
+        assert!(matches!(result, Err(QueryError::HttpStatus { status, .. }) if status == 503));
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+
+        let client = GraphqlClient::new(HttpConfig {
+            max_retries: 0,
+            ..Default::default()
+        });
+        let result = client
+            .query::<IndexingStatuses>(
+                "http://127.0.0.1:1/graphql",
+                indexing_statuses::Variables {},
+            )
+            .await;
+        assert!(matches!(result, Err(QueryError::Transport(_))));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_headers() {
+        let (endpoint, _, requests) = serve(vec![(200, r#"{"data":null}"#)]).await;
+        let origin = endpoint.trim_end_matches("/graphql");
+        let config = HttpConfig::default()
+            .bearer_token(&endpoint, "secret-token")
+            .unwrap()
+            .header(origin, "x-api-key", "gateway-key")
+            .unwrap()
+            .header("http://127.0.0.1", "x-api-key", "default-port-key")
+            .unwrap()
+            .header("https://gateway.thegraph.com", "x-api-key", "other-key")
+            .unwrap();
+        assert!(!format!("{config:?}").contains("secret-token"));
+
+        let data = GraphqlClient::new(config)
+            .query::<IndexingStatuses>(&endpoint, indexing_statuses::Variables {})
+            .await
+            .unwrap();
+        assert!(data.is_none());
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert!(request.contains("authorization: bearer secret-token"));
+        assert!(request.contains("x-api-key: gateway-key"));
+        assert!(!request.contains("other-key"));
+        assert!(!request.contains("default-port-key"));
+    }
+
+    #[test]
+    fn test_header_endpoint_matching() {
+        let config = HttpConfig::default()
+            .header("https://gateway.thegraph.com", "x-api-key", "gateway-key")
+            .unwrap()
+            .header(
+                "https://api.thegraph.com/subgraphs/",
+                "x-api-key",
+                "subgraphs-key",
+            )
+            .unwrap();
+        let key = |endpoint: &str| {
+            config
+                .headers_for(endpoint)
+                .get("x-api-key")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(
+            key("https://gateway.thegraph.com/api/subgraphs/id/Qm").as_deref(),
+            Some("gateway-key")
+        );
+        assert_eq!(
+            key("https://GATEWAY.thegraph.com:443").as_deref(),
+            Some("gateway-key")
+        );
+        assert_eq!(key("https://gateway.thegraph.com.evil.io/api"), None);
+        assert_eq!(key("http://gateway.thegraph.com/api"), None);
+        assert_eq!(key("https://gateway.thegraph.com:8443/api"), None);
+        assert_eq!(
+            key("https://api.thegraph.com/subgraphs/name/graphprotocol/graph-network-goerli")
+                .as_deref(),
+            Some("subgraphs-key")
+        );
+        assert_eq!(
+            key("https://api.thegraph.com/subgraphs").as_deref(),
+            Some("subgraphs-key")
+        );
+        assert_eq!(key("https://api.thegraph.com/subgraphs-evil"), None);
+        assert_eq!(key("not a url"), None);
+        assert!(HttpConfig::default()
+            .header("gateway.thegraph.com", "x-api-key", "gateway-key")
+            .is_err());
+    }
+}
@@ -1,5 +1,8 @@
-use crate::{graphql::QueryError, Account};
-use graphql_client::{GraphQLQuery, Response};
+use crate::{
+    graphql::{client::GraphqlClient, QueryError},
+    Account,
+};
+use graphql_client::GraphQLQuery;
 use tracing::trace;
 
 /// Derived GraphQL Query to Network Subgraph
@@ -15,6 +18,7 @@ pub struct GraphAccount;
 /// Contains indexer address, stake, allocations
 /// and graph network minimum indexer stake requirement
 pub async fn query_graph_account(
+    client: &GraphqlClient,
     url: String,
     operator: String,
     account: String,
@@ -29,26 +33,12 @@ pub async fn query_graph_account(
         },
         account_addr: account.clone(),
     };
-    let request_body = GraphAccount::build_query(variables);
-    let client = reqwest::Client::builder()
-        .user_agent("network-subgraph")
-        .build()?;
-    let request = client.post(url.clone()).json(&request_body);
-    let response = request.send().await?.error_for_status()?;
-    let response_body: Response<graph_account::ResponseData> = response.json().await?;
+    let data = client.query::<GraphAccount>(&url, variables).await?;
     trace!(
-        result = tracing::field::debug(&response_body),
+        result = tracing::field::debug(&data),
         "Query result for graph network account"
     );
-    if let Some(errors) = response_body.errors.as_deref() {
-        let e = &errors[0];
-        if e.message == "indexing_error" {
-            return Err(QueryError::IndexingError);
-        } else {
-            return Err(QueryError::Other(anyhow::anyhow!("{}", e.message)));
-        }
-    }
-    let data = response_body.data.ok_or_else(|| {
+    let data = data.ok_or_else(|| {
         QueryError::ParseResponseError(format!(
             "Missing response data from network subgraph for account {} with agent {}",
             account, operator
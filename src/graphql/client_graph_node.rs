@@ -1,11 +1,11 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::graphql::QueryError;
+use crate::graphql::{client::GraphqlClient, QueryError};
 use crate::NetworkPointer;
 use crate::{networks::NetworkName, BlockPointer};
-use graphql_client::{GraphQLQuery, Response};
+use graphql_client::GraphQLQuery;
 use serde_derive::{Deserialize, Serialize};
-use tracing::{debug, trace, warn};
+use tracing::{debug, trace};
 
 use self::indexing_statuses::IndexingStatusesIndexingStatuses;
 
@@ -26,48 +26,31 @@ pub struct IndexingStatuses;
 )]
 pub struct BlockHashFromNumber;
 
-/// Query graph node for Block hash
-pub async fn perform_block_hash_from_number(
-    graph_node_endpoint: String,
-    variables: block_hash_from_number::Variables,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let request_body = BlockHashFromNumber::build_query(variables);
-    let client = reqwest::Client::new();
-    client
-        .post(graph_node_endpoint)
-        .json(&request_body)
-        .send()
-        .await?
-        .error_for_status()
-}
-
 /// Construct GraphQL variables and parse result for Proof of Indexing.
 /// For other radio use cases, provide a function that returns a string
 pub async fn query_graph_node_network_block_hash(
+    client: &GraphqlClient,
     graph_node_endpoint: String,
     network: String,
     block_number: u64,
 ) -> Result<String, QueryError> {
     let variables: block_hash_from_number::Variables = block_hash_from_number::Variables {
         network: network.clone(),
-        block_number: block_number.try_into().unwrap(),
+        block_number: block_number.try_into().map_err(|_| {
+            QueryError::Other(anyhow::anyhow!(
+                "Block number {block_number} is out of range"
+            ))
+        })?,
     };
-    let queried_result =
-        perform_block_hash_from_number(graph_node_endpoint.clone(), variables).await?;
+    let data = client
+        .query::<BlockHashFromNumber>(&graph_node_endpoint, variables)
+        .await?;
     trace!(
-        result = tracing::field::debug(&queried_result),
+        result = tracing::field::debug(&data),
         "Query result for graph node network block hash"
     );
-    if !queried_result.status().is_success() {
-        warn!(
-            result = tracing::field::debug(&queried_result),
-            "Unsuccessful query"
-        );
-    }
-    let response_body: Response<block_hash_from_number::ResponseData> =
-        queried_result.json().await?;
 
-    if let Some(data) = response_body.data {
+    if let Some(data) = data {
         match data.block_hash_from_number {
             Some(hash) => Ok(hash),
             None => Err(QueryError::ParseResponseError(
@@ -81,36 +64,21 @@ pub async fn query_graph_node_network_block_hash(
     }
 }
 
-/// Query graph node for Indexing Statuses
-pub async fn perform_indexing_statuses(
-    graph_node_endpoint: String,
-    variables: indexing_statuses::Variables,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let request_body = IndexingStatuses::build_query(variables);
-    let client = reqwest::Client::new();
-    client
-        .post(graph_node_endpoint)
-        .json(&request_body)
-        .send()
-        .await?
-        .error_for_status()
-}
-
 /// This function get all indexing statuses from Graph node status endpoint
 pub async fn get_indexing_statuses(
+    client: &GraphqlClient,
     graph_node_endpoint: String,
 ) -> Result<Vec<IndexingStatusesIndexingStatuses>, QueryError> {
     let variables: indexing_statuses::Variables = indexing_statuses::Variables {};
-    let queried_result = perform_indexing_statuses(graph_node_endpoint.clone(), variables).await?;
+    let data = client
+        .query::<IndexingStatuses>(&graph_node_endpoint, variables)
+        .await?;
     trace!(
-        result = tracing::field::debug(&queried_result),
+        result = tracing::field::debug(&data),
         "Query result for indexing statuses"
     );
-    let response_body: Response<indexing_statuses::ResponseData> = queried_result.json().await?;
 
-    response_body
-        .data
-        .map(|data| data.indexing_statuses)
+    data.map(|data| data.indexing_statuses)
         .ok_or(QueryError::IndexingError)
 }
 
@@ -1,8 +1,8 @@
-use graphql_client::{GraphQLQuery, Response};
+use graphql_client::GraphQLQuery;
 use num_traits::Zero;
 use tracing::{error, trace};
 
-use crate::graphql::QueryError;
+use crate::graphql::{client::GraphqlClient, QueryError};
 
 use super::grt_gwei_string_to_f32;
 
@@ -19,6 +19,7 @@ pub struct IndexerStatus;
 /// Contains indexer address, stake, allocations
 /// and graph network minimum indexer stake requirement
 pub async fn query_network_subgraph(
+    client: &GraphqlClient,
     url: String,
     indexer_address: String,
 ) -> Result<Network, QueryError> {
@@ -26,27 +27,12 @@ pub async fn query_network_subgraph(
     let variables: indexer_status::Variables = indexer_status::Variables {
         address: indexer_address.clone(),
     };
-    let request_body = IndexerStatus::build_query(variables);
-    let client = reqwest::Client::builder()
-        .user_agent("network-subgraph")
-        .build()?;
-    let request = client.post(url).json(&request_body);
-    let response = request.send().await?.error_for_status()?;
+    let data = client.query::<IndexerStatus>(&url, variables).await?;
     trace!(
-        result = tracing::field::debug(&response),
+        result = tracing::field::debug(&data),
         "Queried result for Indexer and Network"
     );
-    let response_body: Response<indexer_status::ResponseData> = response.json().await?;
-
-    if let Some(errors) = response_body.errors.as_deref() {
-        let e = &errors[0];
-        if e.message == "indexing_error" {
-            return Err(QueryError::IndexingError);
-        } else {
-            return Err(QueryError::Other(anyhow::anyhow!("{}", e.message)));
-        }
-    }
-    let data = if let Some(data) = response_body.data {
+    let data = if let Some(data) = data {
         data
     } else {
         return Err(QueryError::ParseResponseError(format!(
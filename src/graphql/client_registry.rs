@@ -1,8 +1,8 @@
-use graphql_client::{GraphQLQuery, Response};
+use graphql_client::GraphQLQuery;
 use serde_derive::{Deserialize, Serialize};
-use tracing::{trace, warn};
+use tracing::trace;
 
-use super::QueryError;
+use super::{client::GraphqlClient, QueryError};
 
 /// Derived Indexer
 #[derive(GraphQLQuery, Serialize, Deserialize, Debug)]
@@ -13,43 +13,23 @@ use super::QueryError;
 )]
 pub struct SetGraphcastIds;
 
-/// Query registry subgraph endpoint for resolving Graphcast ID and indexer address
-pub async fn perform_graphcast_id_indexer_query(
-    registry_subgraph_endpoint: String,
-    variables: set_graphcast_ids::Variables,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let request_body = SetGraphcastIds::build_query(variables);
-    let client = reqwest::Client::new();
-    client
-        .post(registry_subgraph_endpoint)
-        .json(&request_body)
-        .send()
-        .await?
-        .error_for_status()
-}
-
 /// Construct GraphQL variables and parse result for indexer address
 pub async fn query_registry(
+    client: &GraphqlClient,
     registry_subgraph_endpoint: String,
     wallet_address: String,
 ) -> Result<String, QueryError> {
     let variables: set_graphcast_ids::Variables = set_graphcast_ids::Variables {
         address: wallet_address.clone(),
     };
-    let queried_result =
-        perform_graphcast_id_indexer_query(registry_subgraph_endpoint.clone(), variables).await?;
+    let data = client
+        .query::<SetGraphcastIds>(&registry_subgraph_endpoint, variables)
+        .await?;
     trace!(
-        result = tracing::field::debug(&queried_result),
+        result = tracing::field::debug(&data),
         "Query result for registry indexer"
     );
-    if !&queried_result.status().is_success() {
-        warn!(
-            result = tracing::field::debug(&queried_result),
-            "Unsuccessful query"
-        );
-    }
-    let response_body: Response<set_graphcast_ids::ResponseData> = queried_result.json().await?;
-    if let Some(data) = response_body.data {
+    if let Some(data) = data {
         data.graphcast_ids
             .first()
             .map(|event| event.indexer.clone())
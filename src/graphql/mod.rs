@@ -1,3 +1,4 @@
+pub mod client;
 pub mod client_graph_account;
 pub mod client_graph_node;
 pub mod client_network;
@@ -7,12 +8,23 @@ pub mod client_registry;
 pub enum QueryError {
     #[error(transparent)]
     Transport(#[from] reqwest::Error),
+    #[error("Request to {0} timed out")]
+    Timeout(String),
+    #[error("Request to {endpoint} failed with HTTP status {status}")]
+    HttpStatus {
+        endpoint: String,
+        status: reqwest::StatusCode,
+    },
+    #[error("GraphQL error in the query response: {0}")]
+    GraphQL(String),
     #[error("The subgraph is in a failed state")]
     IndexingError,
     #[error("Query response is unexpected: {0}")]
     ParseResponseError(String),
     #[error("Query response is empty: {0}")]
     PrometheusError(#[from] prometheus_http_query::Error),
+    #[error("Query failed recently, cached failure: {0}")]
+    CachedError(String),
     #[error("Unknown error: {0}")]
     Other(anyhow::Error),
 }
@@ -20,10 +20,10 @@ use ethers::signers::{
     coins_bip39::English, LocalWallet, MnemonicBuilder, Signer, Wallet, WalletError,
 };
 use ethers_core::k256::ecdsa::SigningKey;
-use graphcast_agent::message_typing::{BuildMessageError, GraphcastMessage};
-use graphql::{
-    client_graph_account::query_graph_account, client_network::query_network_subgraph, QueryError,
+use graphcast_agent::message_typing::{
+    BuildMessageError, GraphcastMessage, IdentityValidation, ValidationError,
 };
+use graphql::QueryError;
 use networks::{NetworkName, NETWORKS};
 
 use once_cell::sync::OnceCell;
@@ -44,7 +44,7 @@ use tracing_subscriber::FmtSubscriber;
 use url::{Host, Url};
 use waku::WakuPubSubTopic;
 
-use crate::{graphcast_agent::ConfigError, graphql::client_registry::query_registry};
+use crate::{callbook::CallBook, graphcast_agent::ConfigError};
 
 pub mod bots;
 pub mod callbook;
@@ -52,7 +52,8 @@ pub mod graphcast_agent;
 pub mod graphql;
 pub mod networks;
 
-type NoncesMap = HashMap<String, HashMap<String, i64>>;
+/// Latest nonce of each sender, keyed by subtopic and then sender address
+pub type NoncesMap = HashMap<String, HashMap<String, i64>>;
 
 /// Each radio persist Nonces from sub-topic messages differentiating sender
 pub static NONCES: OnceCell<Arc<Mutex<NoncesMap>>> = OnceCell::new();
@@ -236,41 +237,51 @@ impl Account {
     }
 
     /// Check for sender's registration at Graphcast (registered at graphcast Registry)
-    pub async fn account_from_registry(
-        &self,
-        registry_subgraph: &str,
-    ) -> Result<Account, QueryError> {
-        let registered_address =
-            query_registry(registry_subgraph.to_string(), self.agent_address()).await?;
+    pub async fn account_from_registry(&self, callbook: &CallBook) -> Result<Account, QueryError> {
+        let registered_address = callbook.registered_indexer(self.agent_address()).await?;
 
         Ok(Account::new(self.agent_address(), registered_address))
     }
 
     /// Check for sender's registration at Graph Network
-    pub async fn account_from_network(
-        &self,
-        network_subgraph: &str,
-    ) -> Result<Account, QueryError> {
-        let matched_account = query_graph_account(
-            network_subgraph.to_string(),
-            self.agent_address(),
-            self.account(),
-        )
-        .await?;
+    pub async fn account_from_network(&self, callbook: &CallBook) -> Result<Account, QueryError> {
+        let matched_account = callbook
+            .graph_account(self.agent_address(), self.account())
+            .await?;
         Ok(matched_account)
     }
 
-    pub async fn valid_indexer(&self, network_subgraph: &str) -> Result<(), BuildMessageError> {
-        if query_network_subgraph(network_subgraph.to_string(), self.account())
+    /// Check that the account verified through `id_validation` is the one claimed by the message
+    pub fn matches(
+        &self,
+        verified: &Account,
+        id_validation: IdentityValidation,
+    ) -> Result<(), ValidationError> {
+        if self.account == verified.account {
+            Ok(())
+        } else {
+            Err(ValidationError::AccountMismatch {
+                sender: self.agent_address(),
+                claimed: self.account(),
+                verified: verified.account(),
+                id_validation,
+            })
+        }
+    }
+
+    pub async fn valid_indexer(&self, callbook: &CallBook) -> Result<(), BuildMessageError> {
+        if callbook
+            .network_subgraph(self.account())
             .await
             .map_err(BuildMessageError::FieldDerivations)?
             .stake_satisfy_requirement()
         {
             Ok(())
         } else {
-            Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
-                "Sender stake is less than the minimum requirement, drop message"
-            )))
+            Err(ValidationError::InsufficientStake {
+                account: self.account(),
+            }
+            .into())
         }
     }
 }
@@ -315,8 +326,27 @@ pub enum NetworkBlockError {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::graphcast_agent::waku_handling::build_content_topics;
 
+    #[test]
+    fn test_account_mismatch() {
+        let claimed = Account::new("0xagent".to_string(), "0xclaimed".to_string());
+        assert!(claimed
+            .matches(&claimed.clone(), IdentityValidation::Indexer)
+            .is_ok());
+        let verified = Account::new("0xagent".to_string(), "0xverified".to_string());
+        assert_eq!(
+            claimed.matches(&verified, IdentityValidation::Indexer),
+            Err(ValidationError::AccountMismatch {
+                sender: "0xagent".to_string(),
+                claimed: "0xclaimed".to_string(),
+                verified: "0xverified".to_string(),
+                id_validation: IdentityValidation::Indexer,
+            })
+        );
+    }
+
     #[test]
     fn test_build_content_topics() {
         let basics = ["Qmyumyum".to_string(), "Ymqumqum".to_string()].to_vec();